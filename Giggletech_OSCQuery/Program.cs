@@ -7,26 +7,40 @@
     remotely.
 
     **Usage:**
-    - The service listens for HTTP commands on the specified port (default: 6969, unless changed in the config).
+    - The service listens for HTTP commands on the specified port (default: 6969, unless changed in the config, with the
+      `GIGGLETECH_HTTP_PORT` environment variable or with the `--http-port <port>` command line argument).
+    - The service name can likewise be set with `GIGGLETECH_SERVICE_NAME` or `--service-name <name>`.
+    - `GIGGLETECH_UDP_PORT`, `--udp-port <port>` or `/start?udpPort=<port>` make the service advertise that UDP port instead
+      of picking a free one, for when the OSC socket has already been bound by the Rust side.
+    - `GIGGLETECH_NO_MDNS=1` or `--no-mdns` leave out the mDNS advertisement, for when the Rust side advertises the service
+      itself (and has possibly renamed it to resolve a name conflict).
     - Commands available via HTTP:
         - Get Info:   http://localhost:6969/info         (Returns the TCP, UDP ports, and service name)
-        - Start:      http://localhost:6969/start        (Starts the OSCQuery service)
+        - Start:      http://localhost:6969/start        (Starts the OSCQuery service; `?serviceName=<name>` renames it first)
         - UDP Port:   http://localhost:6969/port_udp     (Returns the current UDP port)
         - TCP Port:   http://localhost:6969/port_tcp     (Returns the current TCP port)
         - Shut Down:  http://localhost:6969/stop         (Stops the service and shuts down the application)
+        - Endpoints:  POST http://localhost:6969/endpoints  (Publishes exactly the endpoints in the OSCQuery JSON tree in
+                      the body instead of `/avatar`; an empty body goes back to `/avatar`. Kept across `/start`.)
 
     **How It Works:**
     1. **Configuration:**
        - The application reads its configuration from a YAML file (`config_oscq.yml`), specifically the HTTP listener port and service name.
+       - The file is looked for in the same order as the Rust module uses: `--config <path>`, the `GIGGLETECH_CONFIG`
+         environment variable, the working directory, the executable's directory, `%APPDATA%\Giggletech` (`~/.config/Giggletech`
+         on Linux), then `%LOCALAPPDATA%\Giggletech` (`~/.local/share/Giggletech`). The Rust module passes `--config` with the
+         file it chose.
        - Default values are provided in case the configuration file is missing or can't be read (HTTP port 6969, service name "Giggletech").
 
     2. **Starting the HTTP Listener:**
        - A local HTTP server listens for requests on the specified port (default: 6969).
-       - Supported commands (`/start`, `/stop`, `/info`, `/port_udp`, `/port_tcp`) allow remote control and retrieval of service information.
+       - Supported commands (`/start`, `/stop`, `/info`, `/port_udp`, `/port_tcp`, `/endpoints`) allow remote control and retrieval of service information.
 
     3. **Starting the OSCQuery Service:**
        - When the `/start` command is received, the OSCQuery service is initialized with random available TCP and UDP ports.
-       - It also creates an endpoint `/avatar` that clients can interact with (example: sending VRChat avatar data via OSC).
+       - It also creates an endpoint `/avatar` that clients can interact with (example: sending VRChat avatar data via OSC),
+         or the endpoints posted to `/endpoints`. VRChat only sends the addresses that are published, so the Rust side posts
+         just the avatar parameters it subscribed to.
 
     4. **Stopping the OSCQuery Service:**
        - The `/stop` command stops the OSCQuery service and also shuts down the entire application, releasing resources.
@@ -67,6 +81,7 @@ using VRC.OSCQuery;
 using System.Text;
 using System.IO;
 using System.Threading;
+using System.Text.Json;
 using YamlDotNet.Serialization;
 using YamlDotNet.Serialization.NamingConventions;
 
@@ -82,13 +97,33 @@ class Program
     private static int httpPort;
     private static string serviceName;
 
+    // UDP port to advertise, bound by the Rust side; 0 picks a free one on every start
+    private static int fixedUdpPort = 0;
+
+    // Whether to advertise the service over mDNS; off when the Rust side does it
+    private static bool advertiseMdns = true;
+
+    // One endpoint of an OSCQuery JSON tree posted to /endpoints
+    private record EndpointSpec(string Path, string Type, Attributes.AccessValues Access, string Description);
+
+    // Endpoints posted to /endpoints, already validated; null publishes the default /avatar endpoint
+    private static List<EndpointSpec>? requestedEndpoints = null;
+
+    // Paths of the endpoints added to the running service, so they can be removed again
+    private static List<string> publishedPaths = new List<string>();
+
     // Token for stopping the application from the HTTP request
     private static CancellationTokenSource cts = new CancellationTokenSource();
 
     static async Task Main(string[] args)
     {
         // Load the initial configuration from the YAML file (to get the HTTP listener port and service name)
-        LoadConfigFromYaml();
+        LoadConfigFromYaml(args);
+
+        // Environment variables and then command line arguments take precedence over the YAML file.
+        // The Rust supervisor passes both, so the two sides always agree on the HTTP port and service name.
+        ApplyEnvironmentVariables();
+        ApplyCommandLineArgs(args);
 
         // Set up the HTTP listener for remote control using the port from the YAML file
         HttpListener listener = new HttpListener();
@@ -128,11 +163,23 @@ class Program
                 break;
             }
 
-            string command = context.Request.RawUrl.Trim('/').ToLower();
+            string command = context.Request.Url.AbsolutePath.Trim('/').ToLower();
             LogMessage($"Received command: {command}");
 
             if (command == "start")
             {
+                // "/start?serviceName=..." renames the service, e.g. after the Rust side reloaded its config
+                string? newServiceName = context.Request.QueryString["serviceName"];
+                if (!string.IsNullOrWhiteSpace(newServiceName))
+                {
+                    serviceName = newServiceName;
+                    LogMessage($"Service Name {serviceName} set by the start command");
+                }
+                if (int.TryParse(context.Request.QueryString["udpPort"], out int newUdpPort))
+                {
+                    fixedUdpPort = newUdpPort;
+                    LogMessage($"UDP Port {fixedUdpPort} set by the start command");
+                }
                 StartService();
                 byte[] buffer = Encoding.UTF8.GetBytes($"Service started...");
                 context.Response.OutputStream.Write(buffer, 0, buffer.Length);
@@ -165,6 +212,42 @@ class Program
                 byte[] buffer = Encoding.UTF8.GetBytes($"{udpPort}");
                 context.Response.OutputStream.Write(buffer, 0, buffer.Length);
             }
+            else if (command == "endpoints")
+            {
+                // The body is an OSCQuery JSON tree of the endpoints to publish, or empty for the default /avatar
+                string body;
+                using (var reader = new StreamReader(context.Request.InputStream, Encoding.UTF8))
+                {
+                    body = reader.ReadToEnd();
+                }
+
+                // The tree is only kept once it has been read in full (and published, if the service runs), so a bad one
+                // can't break the next /start
+                string reply;
+                try
+                {
+                    List<EndpointSpec>? endpoints = null;
+                    if (!string.IsNullOrWhiteSpace(body))
+                    {
+                        using JsonDocument tree = JsonDocument.Parse(body);
+                        endpoints = ReadEndpoints(tree.RootElement);
+                    }
+                    if (oscQuery != null)
+                    {
+                        PublishEndpoints(endpoints);
+                    }
+                    requestedEndpoints = endpoints;
+                    reply = endpoints == null ? "Publishing /avatar" : $"Publishing {endpoints.Count} endpoints";
+                }
+                catch (Exception ex) when (ex is JsonException || ex is InvalidDataException)
+                {
+                    context.Response.StatusCode = 400;
+                    reply = $"Invalid endpoint tree: {ex.Message}";
+                    LogMessage(reply);
+                }
+                byte[] buffer = Encoding.UTF8.GetBytes(reply);
+                context.Response.OutputStream.Write(buffer, 0, buffer.Length);
+            }
             else if (command == "port_tcp")
             {
                 // Return only the TCP port
@@ -194,23 +277,150 @@ class Program
             LogMessage("A service instance was already running. The previous service has been stopped and a new instance will be started.");
         }
 
-        // Get available TCP and UDP ports for OSCQuery (do NOT read from YAML)
+        // Get available TCP and UDP ports for OSCQuery (do NOT read from YAML),
+        // unless the UDP socket has already been bound for us and we only need to advertise it
         tcpPort = Extensions.GetAvailableTcpPort();
-        udpPort = Extensions.GetAvailableUdpPort();
+        udpPort = fixedUdpPort > 0 ? fixedUdpPort : Extensions.GetAvailableUdpPort();
 
         // Set up the OSCQuery service
-        oscQuery = new OSCQueryServiceBuilder()
+        var builder = new OSCQueryServiceBuilder()
             .WithTcpPort(tcpPort)
             .WithUdpPort(udpPort)
-            .WithServiceName(serviceName) // Use the service name from the YAML file
-            .WithDefaults()
-            .Build();
+            .WithServiceName(serviceName); // Use the service name from the YAML file
+        // The defaults add mDNS discovery and advertising on top of the HTTP server
+        oscQuery = advertiseMdns ? builder.WithDefaults().Build() : builder.StartHttpServer().Build();
         
         LogMessage($"OSCQuery service started at TCP {tcpPort}, UDP {udpPort}, Service Name: {serviceName}");
         LogMessage($"OSC Messages on UDP at: {udpPort}");
+        if (!advertiseMdns)
+        {
+            LogMessage("Not advertising over mDNS, the Rust side does that");
+        }
+
+        // Add the OSC endpoints to the new service
+        publishedPaths.Clear();
+        try
+        {
+            PublishEndpoints(requestedEndpoints);
+        }
+        catch (InvalidDataException ex)
+        {
+            LogMessage($"Publishing /avatar instead of the requested endpoints: {ex.Message}");
+            requestedEndpoints = null;
+            PublishEndpoints(null);
+        }
+    }
+
+    // Replace the endpoints of the running service with `endpoints`, or the default /avatar endpoint for null.
+    // If one of them can't be added, the ones added so far are taken out again, the previous ones are put back and
+    // an InvalidDataException is thrown.
+    static void PublishEndpoints(List<EndpointSpec>? endpoints)
+    {
+        RemovePublishedEndpoints();
+
+        if (endpoints == null)
+        {
+            oscQuery.AddEndpoint("/avatar", "s", Attributes.AccessValues.WriteOnly, new object[] { "This is my avatar endpoint" });
+            publishedPaths.Add("/avatar");
+            LogMessage("Publishing endpoints: /avatar");
+            return;
+        }
+
+        foreach (EndpointSpec endpoint in endpoints)
+        {
+            bool added;
+            try
+            {
+                added = oscQuery.AddEndpoint(endpoint.Path, endpoint.Type, endpoint.Access, null, endpoint.Description);
+            }
+            catch (ArgumentException)
+            {
+                added = false;
+            }
+            if (!added)
+            {
+                RemovePublishedEndpoints();
+                if (endpoints != requestedEndpoints)
+                {
+                    PublishEndpoints(requestedEndpoints);
+                }
+                throw new InvalidDataException($"the endpoint {endpoint.Path} ({endpoint.Type}) can't be added");
+            }
+            publishedPaths.Add(endpoint.Path);
+        }
+        LogMessage($"Publishing endpoints: {string.Join(", ", publishedPaths)}");
+    }
 
-        // Add an OSC endpoint
-        oscQuery.AddEndpoint("/avatar", "s", Attributes.AccessValues.WriteOnly, new object[] { "This is my avatar endpoint" });
+    static void RemovePublishedEndpoints()
+    {
+        foreach (string path in publishedPaths)
+        {
+            oscQuery.RemoveEndpoint(path);
+        }
+        publishedPaths.Clear();
+    }
+
+    // Every node of an OSCQuery JSON tree that has a TYPE, as an endpoint to publish.
+    // Throws an InvalidDataException if the tree isn't made of nodes with the attribute types the spec gives.
+    static List<EndpointSpec> ReadEndpoints(JsonElement tree)
+    {
+        var endpoints = new List<EndpointSpec>();
+        ReadEndpoints(tree, endpoints);
+        return endpoints;
+    }
+
+    static void ReadEndpoints(JsonElement node, List<EndpointSpec> endpoints)
+    {
+        if (node.ValueKind != JsonValueKind.Object)
+        {
+            throw new InvalidDataException("every node has to be a JSON object");
+        }
+
+        string? type = GetString(node, "TYPE");
+        if (type != null)
+        {
+            string path = GetString(node, "FULL_PATH") ?? throw new InvalidDataException($"the {type} endpoint has no FULL_PATH");
+            if (!path.StartsWith("/"))
+            {
+                throw new InvalidDataException($"FULL_PATH {path} doesn't start with /");
+            }
+            var access = Attributes.AccessValues.WriteOnly;
+            if (node.TryGetProperty("ACCESS", out JsonElement accessCode))
+            {
+                if (!accessCode.TryGetInt32(out int code) || code < 0 || code > 3)
+                {
+                    throw new InvalidDataException($"ACCESS of {path} isn't 0, 1, 2 or 3");
+                }
+                access = (Attributes.AccessValues)code;
+            }
+            endpoints.Add(new EndpointSpec(path, type, access, GetString(node, "DESCRIPTION") ?? ""));
+        }
+
+        if (node.TryGetProperty("CONTENTS", out JsonElement contents))
+        {
+            if (contents.ValueKind != JsonValueKind.Object)
+            {
+                throw new InvalidDataException("CONTENTS has to be a JSON object");
+            }
+            foreach (JsonProperty child in contents.EnumerateObject())
+            {
+                ReadEndpoints(child.Value, endpoints);
+            }
+        }
+    }
+
+    // The string attribute `name` of a node, null if it isn't there
+    static string? GetString(JsonElement node, string name)
+    {
+        if (!node.TryGetProperty(name, out JsonElement value))
+        {
+            return null;
+        }
+        if (value.ValueKind != JsonValueKind.String)
+        {
+            throw new InvalidDataException($"{name} has to be a string");
+        }
+        return value.GetString();
     }
 
     // Function to stop the OSCQuery service
@@ -241,8 +451,105 @@ class Program
         File.AppendAllText(logFilePath, $"{DateTime.Now}: {message}{Environment.NewLine}");
     }
 
+    // Override configuration values with command line arguments, e.g. "--http-port 6970 --service-name Giggletech"
+    static void ApplyCommandLineArgs(string[] args)
+    {
+        if (Array.IndexOf(args, "--no-mdns") >= 0)
+        {
+            advertiseMdns = false;
+            LogMessage("mDNS advertising disabled from the command line");
+        }
+
+        for (int i = 0; i < args.Length - 1; i++)
+        {
+            if (args[i] == "--http-port" && int.TryParse(args[i + 1], out int port))
+            {
+                httpPort = port;
+                LogMessage($"HTTP Listener Port {httpPort} set from the command line");
+            }
+            else if (args[i] == "--service-name" && !string.IsNullOrWhiteSpace(args[i + 1]))
+            {
+                serviceName = args[i + 1];
+                LogMessage($"Service Name {serviceName} set from the command line");
+            }
+            else if (args[i] == "--udp-port" && int.TryParse(args[i + 1], out int udp))
+            {
+                fixedUdpPort = udp;
+                LogMessage($"UDP Port {fixedUdpPort} set from the command line");
+            }
+        }
+    }
+
+    // Override configuration values with GIGGLETECH_HTTP_PORT, GIGGLETECH_SERVICE_NAME, GIGGLETECH_UDP_PORT and
+    // GIGGLETECH_NO_MDNS environment variables
+    static void ApplyEnvironmentVariables()
+    {
+        if (int.TryParse(Environment.GetEnvironmentVariable("GIGGLETECH_HTTP_PORT"), out int port))
+        {
+            httpPort = port;
+            LogMessage($"HTTP Listener Port {httpPort} set from GIGGLETECH_HTTP_PORT");
+        }
+
+        string? name = Environment.GetEnvironmentVariable("GIGGLETECH_SERVICE_NAME");
+        if (!string.IsNullOrWhiteSpace(name))
+        {
+            serviceName = name;
+            LogMessage($"Service Name {serviceName} set from GIGGLETECH_SERVICE_NAME");
+        }
+
+        if (int.TryParse(Environment.GetEnvironmentVariable("GIGGLETECH_UDP_PORT"), out int udp))
+        {
+            fixedUdpPort = udp;
+            LogMessage($"UDP Port {fixedUdpPort} set from GIGGLETECH_UDP_PORT");
+        }
+
+        if (Environment.GetEnvironmentVariable("GIGGLETECH_NO_MDNS") == "1")
+        {
+            advertiseMdns = false;
+            LogMessage("mDNS advertising disabled by GIGGLETECH_NO_MDNS");
+        }
+    }
+
+    // Find config_oscq.yml: --config, GIGGLETECH_CONFIG, working directory, executable directory, then the per-user
+    // config and local data directories. Returns null if there is none.
+    static string? FindConfigFile(string[] args)
+    {
+        const string fileName = "config_oscq.yml";
+
+        // A path given explicitly is used even if it doesn't exist, so the error names the file that was asked for
+        string? explicitPath = null;
+        for (int i = 0; i < args.Length - 1; i++)
+        {
+            if (args[i] == "--config")
+            {
+                explicitPath = args[i + 1];
+            }
+        }
+        explicitPath ??= Environment.GetEnvironmentVariable("GIGGLETECH_CONFIG");
+        if (!string.IsNullOrEmpty(explicitPath))
+        {
+            return Directory.Exists(explicitPath) ? Path.Combine(explicitPath, fileName) : explicitPath;
+        }
+
+        string[] candidates =
+        {
+            Path.Combine(Directory.GetCurrentDirectory(), fileName),
+            Path.Combine(AppContext.BaseDirectory, fileName),
+            Path.Combine(Environment.GetFolderPath(Environment.SpecialFolder.ApplicationData), "Giggletech", fileName),
+            Path.Combine(Environment.GetFolderPath(Environment.SpecialFolder.LocalApplicationData), "Giggletech", fileName),
+        };
+        foreach (string candidate in candidates)
+        {
+            if (File.Exists(candidate))
+            {
+                return candidate;
+            }
+        }
+        return null;
+    }
+
     // Load the HTTP port and service name configuration from YAML file with fallback values
-    static void LoadConfigFromYaml()
+    static void LoadConfigFromYaml(string[] args)
     {
         // Set default values for the configuration
         httpPort = 6969;  // Default HTTP listener port
@@ -254,7 +561,9 @@ class Program
                 .WithNamingConvention(UnderscoredNamingConvention.Instance)
                 .Build();
 
-            var yamlContent = File.ReadAllText("config_oscq.yml");
+            string configPath = FindConfigFile(args) ?? throw new FileNotFoundException();
+            LogMessage($"Using configuration file {configPath}");
+            var yamlContent = File.ReadAllText(configPath);
             var config = deserializer.Deserialize<Dictionary<string, object>>(yamlContent);
 
             // Attempt to load the HTTP port and service name from YAML, if present
@@ -0,0 +1 @@
+pub mod oscq_giggletech;
@@ -1,9 +1,125 @@
-mod oscq_giggletech;
+use std::path::PathBuf;
+use rust_test::oscq_giggletech::{
+    config_search_path, default_config_path, init_config, locate_config, migrate_config, Config, Supervisor,
+    SupervisorEvent, SupervisorOptions, CONFIG_VERSION,
+};
 
 fn main() {
-    // Call the synchronous function to initialize and get the UDP port
-    let udp_port = oscq_giggletech::initialize_and_get_udp_port();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let explicit_config = take_flag_value(&mut args, "--config").map(PathBuf::from);
 
-    // Print the UDP port value
-    println!("Final UDP Port as i32: {}", udp_port);
+    // Diagnostic: show which config file would be used, and where else it was looked for
+    if args.iter().any(|arg| arg == "--print-config-path") {
+        print_config_path(explicit_config);
+        return;
+    }
+
+    // `rust-test config init|migrate`
+    if args.first().map(String::as_str) == Some("config") {
+        config_command(&args[1..], explicit_config);
+        return;
+    }
+
+    // Load the config, letting command line flags (e.g. `--http-port 7000`) override it, and check it once they are in
+    let config = Config::read(explicit_config.as_deref()).and_then(|mut config| {
+        let rest = config.apply_args(args)?;
+        if let Some(arg) = rest.first() {
+            eprintln!("Unknown argument: {}", arg);
+            std::process::exit(2);
+        }
+        config.validate()?;
+        Ok(config)
+    });
+
+    // Bring the server up and keep it running until Ctrl-C, reporting the problem instead of panicking if it can't be
+    // done. The supervisor leaves signals alone unless asked, and without its handler Ctrl-C would leave the helper
+    // running on httpPort.
+    let supervisor = config.and_then(|config| {
+        let options = SupervisorOptions { handle_signals: true, ..SupervisorOptions::from(&config) };
+        Supervisor::start_with_config(config, options)
+    });
+    let supervisor = match supervisor {
+        Ok(supervisor) => supervisor,
+        Err(e) => {
+            eprintln!("Could not start the OSCQuery server: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let events = supervisor.events();
+    if let Some(udp_port) = supervisor.udp_port() {
+        println!("Final UDP Port: {}", udp_port);
+    }
+    println!("Press Ctrl-C to stop the OSCQuery server");
+    for event in events {
+        if let SupervisorEvent::PortsChanged { new, .. } = event {
+            println!("UDP Port: {}", new.udp);
+        }
+    }
+}
+
+// Remove `--flag value` or `--flag=value` from `args` and return the value
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == flag || arg.starts_with(&format!("{}=", flag)))?;
+    let arg = args.remove(index);
+    match arg.split_once('=') {
+        Some((_, value)) => Some(value.to_string()),
+        None if index < args.len() => Some(args.remove(index)),
+        None => None,
+    }
+}
+
+// Write a commented default config, or upgrade an existing one to the current schema
+fn config_command(args: &[String], explicit_config: Option<PathBuf>) {
+    let force = args.iter().any(|arg| arg == "--force");
+    let result = match args.first().map(String::as_str) {
+        Some("init") => explicit_config
+            .map(Ok)
+            .unwrap_or_else(default_config_path)
+            .and_then(|path| init_config(&path, force).map(|_| println!("Wrote {}", path.display()))),
+        Some("migrate") => locate_config(explicit_config.as_deref()).and_then(|location| {
+            let Some(location) = location else {
+                println!("No config file found, nothing to migrate (use `config init` to create one)");
+                return Ok(());
+            };
+            let path = location.path;
+            match migrate_config(&path)? {
+                Some(version) => println!(
+                    "Upgraded {} from version {} to {} (the original is in {}.bak)",
+                    path.display(),
+                    version,
+                    CONFIG_VERSION,
+                    path.display()
+                ),
+                None => println!("{} is already at version {}", path.display(), CONFIG_VERSION),
+            }
+            Ok(())
+        }),
+        _ => {
+            eprintln!("Usage: rust-test [--config <path>] config init [--force] | config migrate");
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn print_config_path(explicit_config: Option<PathBuf>) {
+    match locate_config(explicit_config.as_deref()) {
+        Ok(Some(location)) => println!("{} ({})", location.path.display(), location.source),
+        Ok(None) => println!("No config file found, using the defaults"),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    println!("Search path:");
+    for candidate in config_search_path(explicit_config.as_deref()) {
+        let found = if candidate.path.is_file() { "found" } else { "-" };
+        println!("  {:<5} {} ({})", found, candidate.path.display(), candidate.source);
+    }
 }
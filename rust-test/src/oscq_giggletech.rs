@@ -8,26 +8,112 @@
 
     **Main Components:**
     1. **Config Reading:**
-       - The configuration (e.g., the HTTP port) is read from a YAML file located in `AppData\Local\Giggletech\config_oscq.yml`.
+       - The configuration (e.g., the HTTP port) is read from `config_oscq.yml`. `locate_config()` (see `locate.rs`) looks
+         for it in a fixed order shared with the helper: an explicit path, `GIGGLETECH_CONFIG`, the working directory, the
+         executable's directory, `%APPDATA%\Giggletech` (XDG config dir), then `AppData\Local\Giggletech`. The file that
+         was chosen is logged, and `rust-test --print-config-path` shows it.
+       - `Config` (see `config.rs`) covers every setting: the HTTP port and service name, how to launch the helper, the
+         poll interval, startup timeout and retry policy, and the log level. Every key has a default, so the file may be
+         partial or missing. Precedence, lowest first: defaults, the file, `GIGGLETECH_*` environment variables, and
+         command line flags (`Config::apply_args`).
+       - The file is validated before use (see `validate.rs`): syntax errors with their line and column, unknown keys (with a
+         suggestion for near-misses like `http_port`), privileged or out-of-range ports and empty service names are all
+         returned together in `OscqError::ConfigInvalid`.
+       - `init_config()` writes a commented config with the defaults, and `migrate_config()` upgrades an older file to the
+         current schema one `version` at a time (see `migrate.rs`); `rust-test config init|migrate` run them.
+       - Console output goes through the `log_*!` macros in `logging.rs`, filtered by `logLevel`.
     
     2. **Process Management:**
        - The Giggletech OSCQuery server process (`giggletech_oscq.exe`) is started by `run_giggletech()`, and its starting directory 
          is displayed in the console.
+       - `launchMode` in the config selects how: the native executable, `dotnet giggletech_oscq.dll`, or an explicit command;
+         by default whichever of the first two is installed (see `launch.rs`). This also lets it run on Linux.
+       - The helper runs in the directory of our config file (or its own, without one), and is passed the HTTP port and
+         service name as arguments and environment variables, so both sides always use the same configuration.
        - If the process fails to retrieve a valid UDP port or stops running, it is restarted automatically.
+       - If a helper is already answering on the configured port (its `/info` starts with "Giggletech OSCQuery Helper"),
+         it is adopted instead of starting a second process that would only fail to bind the same port.
+       - On Linux the helper runs in its own process group with a parent-death signal, and is recorded in a state file so a
+         helper left behind by a crashed run is cleaned up at the next start (see `lifetime.rs`).
+       - If some other application answers on that port instead, `OscqError::PortOccupiedByForeignService` is returned, or with
+         `httpPortFallback: true` in the config the helper is started on a free port (passed to it with `--http-port`).
+         Something that accepts the connection but doesn't answer `/info` in time isn't taken for another application
+         straight away: it is most likely a helper that is still starting, and is waited for like one. If it is still
+         silent when probed again after the startup grace, it is handled like any other application on the port.
 
     3. **UDP Port Retrieval:**
        - The function `get_udp_port()` retrieves the UDP port from the OSCQuery server via an HTTP request. If the port is invalid (i.e., 0), 
          a start command is sent using `start_server()` to initialize the server properly.
 
-    4. **Main Initialization Loop:**
+    4. **Server Info and Control:**
+       - `get_info()` fetches `/info` and parses its "Key: Value" lines into an `OscqInfo` (TCP, UDP and HTTP ports and the
+         service name; see `info.rs`).
+       - `HelperClient` (see `client.rs`) wraps every HTTP command of the helper (`/start`, `/stop`, `/port_udp`,
+         `/port_tcp`, `/info`) behind one HTTP client with connect and read timeouts, for tools that want to control it directly.
+
+    5. **Main Initialization Loop:**
        - The main function `initialize_and_get_udp_port()` continuously checks the UDP port, restarts the server process when necessary, 
          and returns the valid port once retrieved.
+       - `try_initialize_and_get_udp_port()` does the same but returns an `OscqError` instead of panicking, and gives up after
+         `DEFAULT_STARTUP_TIMEOUT`, so a host application can report the problem and keep running without OSC.
+       - `initialize_and_get_udp_port_async()` is the tokio equivalent of `try_initialize_and_get_udp_port()`, for callers
+         running inside an async runtime.
+       - `try_initialize_with_config(&Config)` (and `initialize_with_config_async`) use a config the caller has already
+         loaded and overridden, e.g. from command line flags.
+       - `try_initialize_with_socket(&Config)` binds the OSC `UdpSocket` first, on a port the OS picks, and has the helper
+         advertise that port (`--udp-port`, `/start?udpPort=`) instead of one it picked and we would bind later, so no
+         other process can grab the port in between. It returns the bound socket together with the port;
+         `initialize_with_socket_async` does the same with a tokio socket.
+       - The `*_with(&RetryPolicy)` variants take a `RetryPolicy` (see `retry.rs`) controlling the startup grace period,
+         exponential backoff with jitter, maximum attempts and overall deadline.
+
+    6. **Native Server and Discovery:**
+       - `OscQueryServer` (see `server.rs`) is a native implementation of the helper's OSCQuery HTTP endpoints
+         (`/?HOST_INFO` and the address-space tree as JSON), for running without .NET. The tree is an `OscQueryNode`
+         (see `node.rs`) the program can change while the server runs, to declare exactly the endpoints it accepts; single
+         attributes are served for queries like `/avatar/change?TYPE`.
+       - `Advertiser` (see `mdns.rs`, on top of the DNS message code in `dns.rs`) advertises the service over mDNS / DNS-SD
+         as `_oscjson._tcp` (TCP port) and `_osc._udp` (UDP port), which is how VRChat finds it. It re-announces when the
+         ports change and withdraws the records with goodbye packets when dropped. Before announcing it probes for the
+         names as RFC 6762 requires, and if another host already uses (or wins a simultaneous probe for) the service
+         name it renames itself to "Giggletech (2)", "Giggletech (3)" and so on; `Advertiser::name()` is the name in use.
+       - `Browser` (see `discovery.rs`) browses for those same service types and reports each `ServiceProfile` (name, host,
+         port, kind) as it appears and disappears; `Browser::vrchat_osc()` finds the port a running VRChat client listens on.
+
+    7. **Watchdog:**
+       - `Supervisor::start()` brings the server up like `try_initialize_and_get_udp_port()`, then keeps supervising it from a
+         background thread (see `supervisor.rs`): it polls `/port_udp`, notices when the process exits or stops answering,
+         restarts it and re-issues `/start`. The current ports are available from the handle, and dropping it stops the watchdog.
+       - Since every `/start` picks new random ports, `Supervisor::events()` delivers a `SupervisorEvent::PortsChanged` whenever
+         they move, so the caller can rebind its OSC socket without polling.
+       - Edits to `config_oscq.yml` are picked up while the supervisor runs: it restarts the helper for a new port, renames
+         the service, or just updates its timeouts, and reports each change as a `SupervisorEvent::ConfigChanged`.
+       - Dropping the `Supervisor` (or calling `shutdown()`) sends `/stop` to the helper, waits for it to exit and only kills
+         it if it doesn't, so `giggletech_oscq.exe` doesn't outlive the program. Ctrl-C / SIGINT / SIGTERM only do the
+         same with `SupervisorOptions::handle_signals`, which is off by default: a host application without a handler of
+         its own has to opt in, as the `rust-test` binary does.
+       - With `SupervisorOptions::advertise` the supervisor starts the helper with `--no-mdns` and advertises the service
+         with its own `Advertiser`, passing any conflict rename on to the helper. `Supervisor::info()` reports the ports
+         and the service name actually in use.
+       - `Supervisor::subscribe()` takes address patterns like "/avatar/parameters/Giggletech_*" and "/avatar/change" and
+         has the helper publish only the matching endpoints of the current avatar (see `subscription.rs`), read from
+         VRChat's own OSCQuery service, instead of the catch-all `/avatar`; VRChat then sends nothing else. The published
+         tree follows avatar changes and new subscriptions while the supervisor runs.
+
+    **Cargo Features:**
+    - `blocking` (default): the synchronous API above, built on `reqwest::blocking`.
+    - `async` (default): the async API in `asynchronous.rs`, built on the async `reqwest::Client` and `tokio::time`.
+    - `native-server`: serve OSCQuery from this process with `OscQueryServer` (see `server.rs`) instead of launching
+      `giggletech_oscq.exe`; the initialization functions then only pick the UDP port and start the server.
+    - With neither `blocking` nor `async` there is no initialization at all, and the helper launching code that goes with
+      it is left out; the config, discovery, mDNS and `OscQueryServer` parts still build.
 
     **How It Works:**
     - First, the configuration is loaded from a YAML file.
     - Then, the OSCQuery process is started.
     - The module continuously tries to retrieve the UDP port from the server.
-    - If the port is not valid (e.g., 0) or if any error occurs, the process is restarted, and the loop continues.
+    - If the port is 0, the start command is sent. If the server doesn't answer, the process is restarted once its startup
+      grace period is over (or right away if it has exited), and the loop continues with an increasing delay.
     - Once a valid UDP port is obtained, it is returned for use in the rest of the program.
 
     This module ensures that the OSCQuery server is always running and that the correct UDP port is available for communication.
@@ -37,98 +123,524 @@
 
 
 
-use std::fs;
+#[cfg(any(feature = "blocking", feature = "async"))]
+use std::io;
+#[cfg(any(feature = "blocking", feature = "async"))]
+use std::net::TcpListener;
+#[cfg(feature = "blocking")]
+use std::net::{Ipv4Addr, UdpSocket};
 use std::path::PathBuf;
-use std::process::{Command, Child};
+#[cfg(feature = "blocking")]
+use std::process::Child;
+#[cfg(feature = "blocking")]
 use std::thread::sleep;
+#[cfg(feature = "blocking")]
+use std::time::Instant;
 use std::time::Duration;
 use dirs::data_local_dir;
-use serde::Deserialize;
-use reqwest::blocking::Client;
-use serde_yaml;
 
-// Struct to deserialize the YAML config
-#[derive(Debug, Deserialize)]
-struct Config {
-    httpPort: u16,
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "blocking")]
+mod client;
+mod config;
+mod discovery;
+mod dns;
+mod error;
+mod info;
+mod launch;
+#[cfg(any(feature = "blocking", feature = "async"))]
+mod lifetime;
+mod locate;
+mod logging;
+mod mdns;
+mod migrate;
+mod node;
+mod retry;
+mod server;
+#[cfg(feature = "blocking")]
+mod subscription;
+#[cfg(feature = "blocking")]
+mod supervisor;
+#[cfg(test)]
+mod testing;
+mod validate;
+
+#[cfg(feature = "async")]
+pub use asynchronous::{
+    get_info_async, initialize_and_get_udp_port_async, initialize_and_get_udp_port_async_with,
+    initialize_with_config_async, initialize_with_socket_async,
+};
+#[cfg(feature = "blocking")]
+pub use client::HelperClient;
+pub use config::{Config, CONFIG_FILE_NAME};
+pub use discovery::{Browser, DiscoveryEvent, ServiceKind, ServiceProfile, VRCHAT_CLIENT_PREFIX};
+pub use error::OscqError;
+pub use info::OscqInfo;
+pub use launch::LaunchMode;
+pub use locate::{
+    config_search_path, default_config_path, locate_config, ConfigLocation, ConfigSource, CONFIG_PATH_ENV,
+};
+pub use logging::LogLevel;
+pub use mdns::{Advertiser, MDNS_ADDR, MDNS_PORT, OSCJSON_SERVICE, OSC_SERVICE};
+pub use migrate::{init_config, migrate_config, render_config, CONFIG_VERSION};
+pub use node::{Access, OscQueryNode, ValueRange};
+pub use retry::RetryPolicy;
+pub use server::OscQueryServer;
+pub use validate::ConfigProblem;
+#[cfg(feature = "blocking")]
+pub use supervisor::{Ports, Supervisor, SupervisorEvent, SupervisorOptions};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use logging::{log_info, log_warn};
+#[cfg(feature = "blocking")]
+use retry::{Startup, StartupStep};
+
+// Overall deadline of the default `RetryPolicy`, after which `OscqError::Timeout` is returned
+pub const DEFAULT_STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// First line of the helper's /info response, used to recognise an already running helper
+#[cfg(any(feature = "blocking", feature = "async"))]
+const HELPER_BANNER: &str = "Giggletech OSCQuery Helper";
+
+// Timeouts of every HTTP request to the helper, so a hung helper can't block the caller forever
+#[cfg(any(feature = "blocking", feature = "async"))]
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+#[cfg(any(feature = "blocking", feature = "async"))]
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait for /info when checking whether a helper is already running
+#[cfg(any(feature = "blocking", feature = "async"))]
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Directory holding the config file and the helper executable (AppData\Local\Giggletech)
+fn giggletech_dir() -> Result<PathBuf, OscqError> {
+    let mut dir = data_local_dir().ok_or(OscqError::DataDirUnavailable)?;
+    dir.push("Giggletech");
+    Ok(dir)
 }
 
-// Function to read and parse the YAML config file
-fn read_config() -> Config {
-    let mut config_path = data_local_dir().expect("Failed to get AppData\\Local directory");
-    config_path.push("Giggletech");
-    config_path.push("config_oscq.yml");
+// The helper we are talking to: either a process we started, or one that was already running when we looked
+#[cfg(any(feature = "blocking", feature = "async"))]
+enum HelperProcess<C> {
+    Spawned { process: C, http_port: u16 },
+    Adopted { http_port: u16 },
+}
 
-    let config_str = fs::read_to_string(config_path).expect("Failed to read config_oscq.yml");
-    serde_yaml::from_str(&config_str).expect("Failed to parse config_oscq.yml")
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl<C> HelperProcess<C> {
+    // Port the helper's HTTP listener is on; differs from the configured one after a fallback
+    fn http_port(&self) -> u16 {
+        match self {
+            HelperProcess::Spawned { http_port, .. } | HelperProcess::Adopted { http_port } => *http_port,
+        }
+    }
+}
+
+// What answered when we fetched /info on the helper's port
+#[cfg(any(feature = "blocking", feature = "async"))]
+enum Responder {
+    Nothing,
+    Helper,
+    // Something accepted the connection but hasn't answered (yet); holds the error
+    Silent(String),
+    // Something else answered over HTTP; holds a short description of what it answered
+    Foreign(String),
+}
+
+// Fingerprint the responder from the result of fetching /info
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn classify_responder(result: Result<String, reqwest::Error>) -> Responder {
+    match result {
+        Ok(body) if is_helper_banner(&body) => Responder::Helper,
+        Ok(body) => Responder::Foreign(body.lines().next().unwrap_or_default().chars().take(80).collect()),
+        Err(e) if e.is_connect() => Responder::Nothing,
+        // A timeout or a dropped connection is no answer at all: most likely a helper that is still starting up
+        Err(e) => Responder::Silent(e.to_string()),
+    }
+}
+
+// True if `body` is a /info response from the Giggletech helper
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn is_helper_banner(body: &str) -> bool {
+    body.trim_start().starts_with(HELPER_BANNER)
+}
+
+// Decide what to do about whatever answered on the configured httpPort:
+// adopt the helper that is already running there (None), or start a new one on the returned port
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn plan_helper_launch(config: &Config, responder: Responder) -> Result<Option<u16>, OscqError> {
+    let port = config.http_port;
+    match responder {
+        Responder::Helper => {
+            log_info!("OSCQ Server is already running on port {}, adopting it", port);
+            Ok(None)
+        }
+        Responder::Nothing => Ok(Some(port)),
+        Responder::Silent(error) => {
+            // Treated as a helper that isn't ready: polling it is retried with backoff, and it is probed again when
+            // the startup grace runs out (see `Startup::plan` for what happens if it is still silent then)
+            log_info!("Something on port {} isn't answering yet ({}), waiting for it", port, error);
+            Ok(None)
+        }
+        Responder::Foreign(response) => {
+            let occupied = OscqError::PortOccupiedByForeignService { port, response };
+            if !config.http_port_fallback {
+                return Err(occupied);
+            }
+            let fallback = free_local_port().map_err(|_| occupied)?;
+            log_warn!(
+                "Port {} is used by another application, starting the OSCQ Server on port {} instead",
+                port, fallback
+            );
+            Ok(Some(fallback))
+        }
+    }
+}
+
+// Ask the OS for a TCP port nobody is listening on
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn free_local_port() -> io::Result<u16> {
+    Ok(TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port())
+}
+
+// Parse the body of a /port_udp or /port_tcp response
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn parse_port(url: String, body: String) -> Result<u16, OscqError> {
+    body.trim()
+        .parse()
+        .map_err(|_| OscqError::InvalidPort { url, body })
 }
 
 // Function to start the giggletech process
-fn run_giggletech() -> Child {
-    let mut executable_path = data_local_dir().expect("Failed to get AppData\\Local directory");
-    executable_path.push("Giggletech");
-    executable_path.push("giggletech_oscq.exe");
+#[cfg(feature = "blocking")]
+fn run_giggletech(config: &Config, http_port: u16, udp_port: Option<u16>, mdns: bool) -> Result<Child, OscqError> {
+    let mut command = launch::helper_command(config, http_port, udp_port, mdns)?;
+    let path = PathBuf::from(command.get_program());
+    let launched = lifetime::command_line(&command);
+    let process =
+        lifetime::spawn_helper(move || command.spawn()).map_err(|source| OscqError::Spawn { path, source })?;
+    lifetime::record_helper(http_port, process.id(), &launched);
+    Ok(process)
+}
 
-    // Display a message indicating the process is being started and the directory it's being started from
-    println!(
-        "Starting OSCQ Server from the directory: {}",
-        executable_path.display()
-    );
+// Kill the helper and reap it so it doesn't linger as a zombie
+#[cfg(feature = "blocking")]
+fn stop_giggletech(process: &mut Child) {
+    let _ = process.kill();
+    let _ = process.wait();
+}
 
-    Command::new(executable_path)
-        .spawn()
-        .expect("Failed to start giggletech process")
+// Adopt the helper if one is already running on the configured port, otherwise start a new one
+#[cfg(feature = "blocking")]
+fn start_or_adopt(
+    config: &Config,
+    startup: &mut Startup<'_>,
+    udp_port: Option<u16>,
+    mdns: bool,
+) -> Result<HelperProcess<Child>, OscqError> {
+    lifetime::reap_stale_helper(config.http_port);
+    match startup.plan(config, HelperClient::new(config.http_port)?.probe())? {
+        None => Ok(HelperProcess::Adopted { http_port: config.http_port }),
+        Some(http_port) => {
+            run_giggletech(config, http_port, udp_port, mdns).map(|process| HelperProcess::Spawned { process, http_port })
+        }
+    }
 }
 
+#[cfg(feature = "blocking")]
+impl HelperProcess<Child> {
+    // True if we started the process and it has exited; an adopted helper can only be checked over HTTP
+    fn has_exited(&mut self) -> bool {
+        match self {
+            HelperProcess::Spawned { process, .. } => matches!(process.try_wait(), Ok(Some(_))),
+            HelperProcess::Adopted { .. } => false,
+        }
+    }
+
+    // Kill the process if we started it; an adopted helper is left alone
+    fn kill(&mut self) {
+        if let HelperProcess::Spawned { process, http_port } = self {
+            stop_giggletech(process);
+            lifetime::forget_helper(*http_port, process.id());
+        }
+    }
 
-// Function to get the UDP port from the server (synchronous)
-fn get_udp_port(port: u16) -> Result<i32, reqwest::Error> {
-    let url = format!("http://localhost:{}/port_udp", port);
-    let response = reqwest::blocking::get(&url)?;
-    let port_value: i32 = response.text()?.trim().parse().unwrap_or(0); // Return 0 if parsing fails
-    Ok(port_value)
+    // Ask the helper to exit with /stop and give it up to `grace` to do so.
+    // A process we started is killed if it is still alive after that.
+    fn shutdown(&mut self, client: &HelperClient, grace: Duration) {
+        if let HelperProcess::Spawned { process, http_port } = self {
+            if let Ok(Some(_)) = process.try_wait() {
+                lifetime::forget_helper(*http_port, process.id());
+                return;
+            }
+        }
+
+        log_info!("Stopping OSCQ Server...");
+        if let Err(e) = client.stop() {
+            log_warn!("Failed to send the stop command: {}", e);
+        }
+
+        if let HelperProcess::Spawned { process, http_port } = self {
+            let deadline = Instant::now() + grace;
+            while !matches!(process.try_wait(), Ok(Some(_))) {
+                if Instant::now() >= deadline {
+                    log_warn!("OSCQ Server did not exit within {}s, killing it", grace.as_secs_f32());
+                    stop_giggletech(process);
+                    break;
+                }
+                sleep(Duration::from_millis(50));
+            }
+            lifetime::forget_helper(*http_port, process.id());
+        }
+    }
 }
 
-// Function to send the start command to the server (synchronous)
-fn start_server(port: u16) -> Result<(), reqwest::Error> {
-    let client = Client::new();
-    let url = format!("http://localhost:{}/start", port);
-    client.get(&url).send()?.error_for_status()?;
-    Ok(())
+
+// Function to get the helper's /info, parsed into an `OscqInfo` (synchronous)
+#[cfg(feature = "blocking")]
+pub fn get_info(port: u16) -> Result<OscqInfo, OscqError> {
+    HelperClient::new(port)?.info()
 }
 
 // Function to initialize, handle the giggletech process, and return the UDP port (synchronous)
+// Panics if the config or the helper executable can't be found, and otherwise keeps trying forever.
+#[cfg(feature = "blocking")]
 pub fn initialize_and_get_udp_port() -> i32 {
-    // Step 1: Read the configuration
-    let config = read_config();
+    match Config::load().and_then(|config| initialize(&config, &RetryPolicy::unbounded())) {
+        Ok(port_value) => i32::from(port_value),
+        Err(e) => panic!("Failed to initialize the OSCQuery server: {}", e),
+    }
+}
+
+// Fallible version of `initialize_and_get_udp_port`, retrying as set up in the config
+#[cfg(feature = "blocking")]
+pub fn try_initialize_and_get_udp_port() -> Result<u16, OscqError> {
+    try_initialize_with_config(&Config::load()?)
+}
+
+// Fallible initialization loop, retrying according to `policy` instead of the config
+#[cfg(feature = "blocking")]
+pub fn try_initialize_and_get_udp_port_with(policy: &RetryPolicy) -> Result<u16, OscqError> {
+    initialize(&Config::load()?, policy)
+}
+
+// Fallible initialization loop for an already loaded (and possibly overridden) config
+#[cfg(feature = "blocking")]
+pub fn try_initialize_with_config(config: &Config) -> Result<u16, OscqError> {
+    initialize(config, &config.retry_policy())
+}
+
+// Bind the OSC socket ourselves and have the helper advertise its port, so nothing can take the port in between.
+// Returns the bound socket together with its port; the caller receives OSC on it as is.
+#[cfg(feature = "blocking")]
+pub fn try_initialize_with_socket(config: &Config) -> Result<(UdpSocket, u16), OscqError> {
+    logging::set_level(config.log_level);
+    let (socket, udp_port) = bind_udp_socket()?;
+    if cfg!(feature = "native-server") {
+        let (udp_port, _) = server::serve_in_process(config, Some(udp_port))?;
+        return Ok((socket, udp_port));
+    }
+    let (_process, _client, port_value) = bring_up(config, &config.retry_policy(), Some(udp_port), true, &mut |delay| {
+        sleep(delay);
+        true
+    })?;
+    Ok((socket, port_value))
+}
+
+// Bind a UDP socket for OSC on a port the OS picks
+#[cfg(feature = "blocking")]
+fn bind_udp_socket() -> Result<(UdpSocket, u16), OscqError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|source| OscqError::UdpBind { source })?;
+    let port = socket.local_addr().map_err(|source| OscqError::UdpBind { source })?.port();
+    log_info!("Bound the OSC socket on UDP port {}", port);
+    Ok((socket, port))
+}
+
+#[cfg(feature = "blocking")]
+fn initialize(config: &Config, policy: &RetryPolicy) -> Result<u16, OscqError> {
+    // Step 1: Apply the configuration
+    logging::set_level(config.log_level);
+    if cfg!(feature = "native-server") {
+        // The caller binds the port itself, so the socket holding it is closed on the way out. Callers that can't
+        // have anyone take the port in between use `try_initialize_with_socket`, which hands the socket over instead.
+        let (udp_port, _socket) = server::serve_in_process(config, None)?;
+        return Ok(udp_port);
+    }
+
+    // Steps 2 and 3: Start (or adopt) the giggletech process and wait for its UDP port.
+    // The process is left running when we return; it keeps serving OSCQuery for the rest of the program.
+    let (_process, _client, port_value) = bring_up(config, policy, None, true, &mut |delay| {
+        sleep(delay);
+        true
+    })?;
+    Ok(port_value)
+}
 
-    // Step 2: Start the giggletech process
-    let mut process = run_giggletech();
+// Start the giggletech process, or adopt one that is already running, and loop until it reports a non-zero UDP port.
+// With `udp_port` (the port of a socket the caller has bound) the helper is told to advertise that port instead of
+// picking one. Without `mdns` a helper we start leaves advertising the service over mDNS to us.
+// Returns the process together with a client for talking to it.
+// `wait` sleeps between attempts and returns false if the caller wants to abort, which yields `OscqError::Cancelled`.
+#[cfg(feature = "blocking")]
+fn bring_up(
+    config: &Config,
+    policy: &RetryPolicy,
+    udp_port: Option<u16>,
+    mdns: bool,
+    wait: &mut dyn FnMut(Duration) -> bool,
+) -> Result<(HelperProcess<Child>, HelperClient, u16), OscqError> {
+    let mut startup = Startup::new(policy, udp_port);
+    let mut process = start_or_adopt(config, &mut startup, udp_port, mdns)?;
+    let mut client = HelperClient::new(process.http_port())?;
 
-    // Step 3: Loop until we get a non-zero UDP port
     loop {
-        match get_udp_port(config.httpPort) {
-            Ok(0) => {
-                // If UDP port is 0, send the start command
-                println!("UDP port is 0, sending start command...");
-                if let Err(e) = start_server(config.httpPort) {
-                    eprintln!("Failed to start server: {}", e);
+        let mut error = None;
+        match startup.step(client.udp_port(), process.has_exited()) {
+            StartupStep::Ready(port_value) => return Ok((process, client, port_value)),
+            StartupStep::Start => {
+                if let Err(e) = client.start_with(None, udp_port) {
+                    log_warn!("Failed to start server: {}", e);
+                    error = Some(e);
                 }
             }
-            Ok(port_value) => {
-                // If we get a valid non-zero port, return it
-                println!("UDP port: {}", port_value);
-                return port_value;
+            StartupStep::Relaunch => {
+                process.kill();
+                process = start_or_adopt(config, &mut startup, udp_port, mdns)?;
+                client = HelperClient::new(process.http_port())?;
+            }
+            StartupStep::Wait => {}
+            StartupStep::Fail(e) => {
+                process.kill();
+                return Err(e);
             }
-            Err(_) => {
-                // If the request fails, restart the process
-                eprintln!("Failed to retrieve UDP port, restarting giggletech process...");
-                let _ = process.kill(); // Kill the current process
-                process = run_giggletech(); // Restart the process
+        }
+
+        // Sleep before the next check, or give up once the policy is exhausted
+        let delay = match startup.next_delay(error) {
+            Ok(delay) => delay,
+            Err(e) => {
+                process.kill();
+                return Err(e);
             }
+        };
+        if !wait(delay) {
+            process.kill();
+            return Err(OscqError::Cancelled);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use super::*;
+    use std::thread;
+    use testing::{helper_config, remove_config_dir, FakeHelper};
+
+    // A policy that runs through its attempts within a second
+    fn quick_policy() -> RetryPolicy {
+        RetryPolicy {
+            startup_grace: Duration::from_millis(500),
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(100),
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+            deadline: None,
         }
+    }
+
+    fn bring_up_now(
+        config: &Config,
+        policy: &RetryPolicy,
+    ) -> Result<(HelperProcess<Child>, HelperClient, u16), OscqError> {
+        bring_up(config, policy, None, true, &mut |delay| {
+            sleep(delay);
+            true
+        })
+    }
+
+    fn adopting(helper: &FakeHelper) -> Config {
+        let mut config = Config::default();
+        config.http_port = helper.http_port();
+        config
+    }
+
+    #[test]
+    fn adopts_a_running_helper_and_starts_its_service() {
+        let helper = FakeHelper::start("Giggletech");
+        let (process, _, udp_port) = bring_up_now(&adopting(&helper), &quick_policy()).unwrap();
+
+        assert!(matches!(process, HelperProcess::Adopted { http_port } if http_port == helper.http_port()));
+        // The adopted helper's service wasn't running, so it has been started and its port is the one we got
+        assert_ne!(udp_port, 0);
+        assert_eq!(udp_port, helper.state().udp_port);
+    }
+
+    #[test]
+    fn gives_up_when_the_policy_runs_out() {
+        let helper = FakeHelper::start("Giggletech");
+        helper.state().broken = true;
+        let config = adopting(&helper);
+
+        let policy = RetryPolicy { max_attempts: Some(3), ..quick_policy() };
+        let result = bring_up_now(&config, &policy);
+        assert!(matches!(result, Err(OscqError::RetriesExhausted { attempts: 3, .. })));
+        assert_eq!(helper.requests().iter().filter(|request| *request == "GET /start").count(), 3);
+
+        let policy = RetryPolicy { deadline: Some(Duration::from_millis(300)), ..quick_policy() };
+        let started = Instant::now();
+        assert!(matches!(bring_up_now(&config, &policy), Err(OscqError::Timeout { .. })));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn starts_a_helper_and_hands_it_the_config() {
+        let config = helper_config("launch", "serviceName: Launch Test\n");
+        // Brought up from a thread that is gone by the time the helper is used, like a supervisor's
+        let (mut process, client, udp_port) = {
+            let config = config.clone();
+            thread::spawn(move || bring_up_now(&config, &quick_policy()).unwrap()).join().unwrap()
+        };
+        assert!(matches!(process, HelperProcess::Spawned { http_port, .. } if http_port == config.http_port));
+
+        let info = client.info().unwrap();
+        assert_eq!((info.udp_port, info.service_name.as_deref()), (Some(udp_port), Some("Launch Test")));
+        // Run in the config file's directory, and pointed at the file
+        let path = config.path().unwrap();
+        let text = reqwest::blocking::get(client.url("info")).unwrap().text().unwrap();
+        assert!(text.contains(&format!("Working Directory: {}", path.parent().unwrap().display())));
+        assert!(text.contains(&format!("Config: {}", path.display())));
+
+        process.shutdown(&client, Duration::from_secs(5));
+        let HelperProcess::Spawned { process: child, .. } = &mut process else {
+            unreachable!();
+        };
+        assert!(child.try_wait().unwrap().is_some_and(|status| status.success()));
+        remove_config_dir(&config);
+    }
+
+    #[test]
+    fn waits_for_a_listener_that_doesnt_answer_yet() {
+        // Accepts connections (the kernel does that for it) but never answers
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let responder = HelperClient::new(port).unwrap().probe();
+        assert!(matches!(responder, Responder::Silent(_)));
+        let mut config = Config::default();
+        config.http_port = port;
+        config.http_port_fallback = true;
+        assert_eq!(plan_helper_launch(&config, responder).unwrap(), None);
+    }
+
+    #[test]
+    fn gives_up_on_a_listener_that_never_answers() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut config = Config::default();
+        config.http_port = listener.local_addr().unwrap().port();
 
-        // Sleep before the next check
-        sleep(Duration::from_secs(1));
+        // Even with a policy that never runs out
+        let result = bring_up_now(&config, &quick_policy());
+        assert!(matches!(result, Err(OscqError::PortOccupiedByForeignService { port, .. }) if port == config.http_port));
     }
 }
@@ -0,0 +1,347 @@
+/*
+    Async (tokio) version of the Giggletech OSCQuery initialization.
+
+    The polling loop takes the same decisions as the blocking one in `oscq_giggletech.rs` (both follow `retry::Startup`),
+    but talks to the helper with the async `reqwest::Client` and waits with `tokio::time`, so it can be awaited from
+    inside a runtime without tying up an executor thread. What has to block (the state files of `lifetime.rs`, handing
+    the spawn to the thread every helper is started from, starting the native server) runs on tokio's blocking pool.
+    Every request has the same connect and read timeouts as `HelperClient`'s, and the whole polling loop runs under
+    `tokio::time::timeout` with the policy's deadline, so an await can't outlast it.
+
+    `initialize_with_socket_async` binds the OSC socket first and has the helper advertise its port, like
+    `try_initialize_with_socket`. There is no async `Supervisor`, so the helper always advertises itself over mDNS.
+*/
+
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use reqwest::Client;
+use tokio::net::UdpSocket;
+use tokio::process::{Child, Command};
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep, timeout, Instant};
+
+use super::lifetime;
+use super::logging::{self, log_error, log_info, log_warn};
+use super::retry::{Startup, StartupStep};
+use super::server;
+use super::{
+    classify_responder, launch, parse_port, Config, HelperProcess, OscqError, OscqInfo, Responder, RetryPolicy,
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT, PROBE_TIMEOUT,
+};
+
+// Client for the helper listening on `port`, with the same timeouts as `HelperClient`
+fn helper_client(port: u16) -> Result<Client, OscqError> {
+    Client::builder()
+        .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
+        .timeout(DEFAULT_READ_TIMEOUT)
+        .build()
+        .map_err(|source| OscqError::Http { url: format!("http://localhost:{}", port), source })
+}
+
+// URL of one of the helper's HTTP commands (e.g. "port_udp", "start")
+fn helper_url(port: u16, command: &str) -> String {
+    format!("http://localhost:{}/{}", port, command)
+}
+
+// Run `work`, which blocks, on tokio's blocking pool instead of an executor thread
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, OscqError> + Send + 'static,
+) -> Result<T, OscqError> {
+    match spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(e) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            // The runtime is shutting down
+            Err(_) => Err(OscqError::Cancelled),
+        },
+    }
+}
+
+// Function to start the giggletech process, advertising `udp_port` if given (async)
+async fn run_giggletech(config: &Config, http_port: u16, udp_port: Option<u16>) -> Result<Child, OscqError> {
+    let config = config.clone();
+    // The helper is spawned on another thread, which has to enter our runtime to hand the child to it
+    let runtime = tokio::runtime::Handle::current();
+    blocking(move || {
+        let std_command = launch::helper_command(&config, http_port, udp_port, true)?;
+        let path = PathBuf::from(std_command.get_program());
+        let launched = lifetime::command_line(&std_command);
+        let process = lifetime::spawn_helper(move || {
+            let _runtime = runtime.enter();
+            Command::from(std_command).spawn()
+        })
+        .map_err(|source| OscqError::Spawn { path, source })?;
+        if let Some(pid) = process.id() {
+            lifetime::record_helper(http_port, pid, &launched);
+        }
+        Ok(process)
+    })
+    .await
+}
+
+// Find out what is listening on `port` (async)
+async fn probe_responder(client: &Client, port: u16) -> Responder {
+    let url = helper_url(port, "info");
+    classify_responder(match client.get(&url).timeout(PROBE_TIMEOUT).send().await {
+        Ok(response) => response.text().await,
+        Err(e) => Err(e),
+    })
+}
+
+// Adopt the helper if one is already running on the configured port, otherwise start a new one (async)
+async fn start_or_adopt(
+    client: &Client,
+    config: &Config,
+    startup: &mut Startup<'_>,
+    udp_port: Option<u16>,
+) -> Result<HelperProcess<Child>, OscqError> {
+    let http_port = config.http_port;
+    blocking(move || {
+        lifetime::reap_stale_helper(http_port);
+        Ok(())
+    })
+    .await?;
+    match startup.plan(config, probe_responder(client, config.http_port).await)? {
+        None => Ok(HelperProcess::Adopted { http_port: config.http_port }),
+        Some(http_port) => {
+            let process = run_giggletech(config, http_port, udp_port).await?;
+            Ok(HelperProcess::Spawned { process, http_port })
+        }
+    }
+}
+
+impl HelperProcess<Child> {
+    // True if we started the process and it has exited; an adopted helper can only be checked over HTTP
+    fn has_exited(&mut self) -> bool {
+        match self {
+            HelperProcess::Spawned { process, .. } => matches!(process.try_wait(), Ok(Some(_))),
+            HelperProcess::Adopted { .. } => false,
+        }
+    }
+
+    // Kill the process and wait for it to exit if we started it; an adopted helper is left alone
+    async fn kill(&mut self) {
+        if let HelperProcess::Spawned { process, http_port } = self {
+            let pid = process.id();
+            let _ = process.kill().await;
+            if let Some(pid) = pid {
+                lifetime::forget_helper(*http_port, pid);
+            }
+        }
+    }
+}
+
+// Function to get the UDP port from the server (async)
+// Returns 0 while the OSCQuery service hasn't been started yet
+async fn get_udp_port(client: &Client, port: u16) -> Result<u16, OscqError> {
+    let (url, body) = get_text(client, port, "port_udp").await?;
+    parse_port(url, body)
+}
+
+// Function to get the helper's /info, parsed into an `OscqInfo` (async)
+pub async fn get_info_async(port: u16) -> Result<OscqInfo, OscqError> {
+    let (_, body) = get_text(&helper_client(port)?, port, "info").await?;
+    Ok(OscqInfo::parse(&body))
+}
+
+// GET one of the helper's commands and return the URL together with the response body (async)
+async fn get_text(client: &Client, port: u16, command: &str) -> Result<(String, String), OscqError> {
+    let url = helper_url(port, command);
+    let body = match client.get(&url).send().await {
+        Ok(response) => response.text().await,
+        Err(e) => Err(e),
+    }
+    .map_err(|source| OscqError::Http { url: url.clone(), source })?;
+    Ok((url, body))
+}
+
+// Function to send the start command to the server, asking it to advertise `udp_port` if given (async)
+async fn start_server(client: &Client, port: u16, udp_port: Option<u16>) -> Result<(), OscqError> {
+    let url = helper_url(port, "start");
+    let query: Vec<(&str, u16)> = udp_port.map(|udp_port| ("udpPort", udp_port)).into_iter().collect();
+    client
+        .get(&url)
+        .query(&query)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|source| OscqError::Http { url, source })?;
+    Ok(())
+}
+
+// Async version of `try_initialize_and_get_udp_port`, retrying as set up in the config
+pub async fn initialize_and_get_udp_port_async() -> Result<u16, OscqError> {
+    initialize_with_config_async(&Config::load()?).await
+}
+
+// Async version of `try_initialize_and_get_udp_port_with`, retrying according to `policy`
+pub async fn initialize_and_get_udp_port_async_with(policy: &RetryPolicy) -> Result<u16, OscqError> {
+    initialize(&Config::load()?, policy, None).await
+}
+
+// Async version of `try_initialize_with_config`
+pub async fn initialize_with_config_async(config: &Config) -> Result<u16, OscqError> {
+    initialize(config, &config.retry_policy(), None).await
+}
+
+// Async version of `try_initialize_with_socket`: bind the OSC socket ourselves and have the helper advertise its port.
+// Returns the bound socket together with its port.
+pub async fn initialize_with_socket_async(config: &Config) -> Result<(UdpSocket, u16), OscqError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.map_err(|source| OscqError::UdpBind { source })?;
+    let udp_port = socket.local_addr().map_err(|source| OscqError::UdpBind { source })?.port();
+    log_info!("Bound the OSC socket on UDP port {}", udp_port);
+    let udp_port = initialize(config, &config.retry_policy(), Some(udp_port)).await?;
+    Ok((socket, udp_port))
+}
+
+// With `udp_port` (the port of a socket the caller has bound) the helper is told to advertise that port
+async fn initialize(config: &Config, policy: &RetryPolicy, udp_port: Option<u16>) -> Result<u16, OscqError> {
+    let started = Instant::now();
+    let client = helper_client(config.http_port)?;
+
+    // Step 1: Apply the configuration
+    logging::set_level(config.log_level);
+    if cfg!(feature = "native-server") {
+        // Without `udp_port` the caller binds the port itself, so the socket holding it goes here
+        let config = config.clone();
+        let (udp_port, _socket) = blocking(move || server::serve_in_process(&config, udp_port)).await?;
+        return Ok(udp_port);
+    }
+
+    // Step 2: Start the giggletech process, or adopt one that is already running
+    let mut startup = Startup::new(policy, udp_port);
+    let mut process = start_or_adopt(&client, config, &mut startup, udp_port).await?;
+
+    // Step 3: Loop until we get a non-zero UDP port, but no longer than the deadline
+    let Some(deadline) = policy.deadline else {
+        return poll_udp_port(&client, config, &mut startup, udp_port, &mut process).await;
+    };
+    let remaining = deadline.saturating_sub(started.elapsed());
+    match timeout(remaining, poll_udp_port(&client, config, &mut startup, udp_port, &mut process)).await {
+        Ok(result) => result,
+        Err(_) => {
+            let e = OscqError::Timeout { after: deadline, last_error: None };
+            log_error!("Giving up on the OSCQ Server: {}", e);
+            process.kill().await;
+            Err(e)
+        }
+    }
+}
+
+async fn poll_udp_port(
+    client: &Client,
+    config: &Config,
+    startup: &mut Startup<'_>,
+    udp_port: Option<u16>,
+    process: &mut HelperProcess<Child>,
+) -> Result<u16, OscqError> {
+    loop {
+        let mut error = None;
+        let polled = get_udp_port(client, process.http_port()).await;
+        match startup.step(polled, process.has_exited()) {
+            StartupStep::Ready(port_value) => return Ok(port_value),
+            StartupStep::Start => {
+                if let Err(e) = start_server(client, process.http_port(), udp_port).await {
+                    log_warn!("Failed to start server: {}", e);
+                    error = Some(e);
+                }
+            }
+            StartupStep::Relaunch => {
+                process.kill().await;
+                *process = start_or_adopt(client, config, startup, udp_port).await?;
+            }
+            StartupStep::Wait => {}
+            StartupStep::Fail(e) => {
+                process.kill().await;
+                return Err(e);
+            }
+        }
+
+        // Sleep before the next check, or give up once the policy is exhausted
+        match startup.next_delay(error) {
+            Ok(delay) => sleep(delay).await,
+            Err(e) => {
+                process.kill().await;
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscq_giggletech::testing::{helper_config, remove_config_dir, FakeHelper};
+
+    fn adopting(helper: &FakeHelper) -> Config {
+        let mut config = Config::default();
+        config.http_port = helper.http_port();
+        config
+    }
+
+    #[tokio::test]
+    async fn starts_the_service_of_an_adopted_helper() {
+        let helper = FakeHelper::start("Giggletech");
+        let config = adopting(&helper);
+        let client = helper_client(config.http_port).unwrap();
+
+        let policy = config.retry_policy();
+        let mut startup = Startup::new(&policy, None);
+        let mut process = start_or_adopt(&client, &config, &mut startup, None).await.unwrap();
+        assert!(matches!(process, HelperProcess::Adopted { .. }));
+        let udp_port = poll_udp_port(&client, &config, &mut startup, None, &mut process).await.unwrap();
+        // The adopted helper's service wasn't running, so it has been started and its port is the one we got
+        assert_ne!(udp_port, 0);
+        assert_eq!(udp_port, helper.state().udp_port);
+    }
+
+    #[tokio::test]
+    async fn reads_the_info_of_a_running_helper() {
+        let helper = FakeHelper::started("Living Room");
+        let info = get_info_async(helper.http_port()).await.unwrap();
+
+        let state = helper.state();
+        assert_eq!((info.udp_port, info.tcp_port), (Some(state.udp_port), Some(state.tcp_port)));
+        assert_eq!(info.service_name.as_deref(), Some("Living Room"));
+    }
+
+    #[tokio::test]
+    async fn starts_a_helper_process() {
+        let config = helper_config("async-launch", "");
+        let client = helper_client(config.http_port).unwrap();
+
+        let policy = config.retry_policy();
+        let mut startup = Startup::new(&policy, None);
+        let mut process = start_or_adopt(&client, &config, &mut startup, None).await.unwrap();
+        assert!(matches!(process, HelperProcess::Spawned { .. }));
+        let udp_port = poll_udp_port(&client, &config, &mut startup, None, &mut process).await.unwrap();
+        assert_eq!(get_info_async(config.http_port).await.unwrap().udp_port, Some(udp_port));
+
+        process.kill().await;
+        assert!(process.has_exited());
+        remove_config_dir(&config);
+    }
+
+    #[cfg(not(feature = "native-server"))]
+    #[tokio::test]
+    async fn gives_up_on_a_hung_helper_at_the_deadline() {
+        let helper = FakeHelper::started("Giggletech");
+        helper.state().hung = true;
+        let policy = RetryPolicy { deadline: Some(std::time::Duration::from_millis(1500)), ..RetryPolicy::default() };
+
+        // Each request would wait DEFAULT_READ_TIMEOUT for an answer; the deadline cuts that short
+        let started = Instant::now();
+        assert!(matches!(initialize(&adopting(&helper), &policy, None).await, Err(OscqError::Timeout { .. })));
+        assert!(started.elapsed() < DEFAULT_READ_TIMEOUT);
+    }
+
+    #[cfg(not(feature = "native-server"))]
+    #[tokio::test]
+    async fn moves_the_service_to_the_port_of_our_socket() {
+        let helper = FakeHelper::started("Giggletech");
+        let (socket, udp_port) = initialize_with_socket_async(&adopting(&helper)).await.unwrap();
+
+        assert_eq!(socket.local_addr().unwrap().port(), udp_port);
+        assert_eq!(helper.state().udp_port, udp_port);
+    }
+}
@@ -0,0 +1,235 @@
+/*
+    Typed client for the helper's HTTP commands.
+
+    `HelperClient` holds a single `reqwest::blocking::Client` (with connect and read timeouts, so a hung helper can't block
+    the caller forever) and wraps every command `giggletech_oscq.exe` understands:
+    - `start()`    -> /start     (starts the OSCQuery service on new random ports)
+    - `start_as()` -> /start?serviceName=...  (same, advertising the service under a new name)
+    - `start_with()` -> /start?serviceName=...&udpPort=...  (either or both; the UDP port of a socket we bound ourselves)
+    - `stop()`     -> /stop      (stops the service and shuts the helper down)
+    - `udp_port()` -> /port_udp  (0 while the service isn't started)
+    - `tcp_port()` -> /port_tcp  (0 while the service isn't started)
+    - `info()`     -> /info      (parsed into an `OscqInfo`)
+    - `set_endpoints()` -> POST /endpoints with an OSCQuery JSON tree  (publish exactly those endpoints)
+    - `reset_endpoints()` -> POST /endpoints with no body  (back to the catch-all `/avatar` endpoint)
+
+    It normally talks to `http://localhost:{httpPort}`, but can be pointed at any base URL.
+*/
+
+use std::time::Duration;
+use reqwest::blocking::Client;
+
+use super::{
+    classify_responder, parse_port, OscQueryNode, OscqError, OscqInfo, Responder, DEFAULT_CONNECT_TIMEOUT,
+    DEFAULT_READ_TIMEOUT, PROBE_TIMEOUT,
+};
+
+#[derive(Debug, Clone)]
+pub struct HelperClient {
+    client: Client,
+    base_url: String,
+}
+
+impl HelperClient {
+    // Client for the helper listening on `http_port` on this machine
+    pub fn new(http_port: u16) -> Result<HelperClient, OscqError> {
+        HelperClient::with_base_url(&format!("http://localhost:{}", http_port))
+    }
+
+    // Client for a helper at `base_url` (e.g. "http://localhost:6969"), with the default timeouts
+    pub fn with_base_url(base_url: &str) -> Result<HelperClient, OscqError> {
+        HelperClient::with_timeouts(base_url, DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT)
+    }
+
+    pub fn with_timeouts(
+        base_url: &str,
+        connect_timeout: Duration,
+        read_timeout: Duration,
+    ) -> Result<HelperClient, OscqError> {
+        let base_url = base_url.trim_end_matches('/').to_string();
+        let client = Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(read_timeout)
+            .build()
+            .map_err(|source| OscqError::Http { url: base_url.clone(), source })?;
+        Ok(HelperClient { client, base_url })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    // URL of one of the helper's commands (e.g. "port_udp", "start")
+    pub fn url(&self, command: &str) -> String {
+        format!("{}/{}", self.base_url, command)
+    }
+
+    pub fn start(&self) -> Result<(), OscqError> {
+        self.send_command("start")
+    }
+
+    // Start (or restart) the service advertised under `service_name`, which the helper keeps using from then on
+    pub fn start_as(&self, service_name: &str) -> Result<(), OscqError> {
+        self.start_with(Some(service_name), None)
+    }
+
+    // Start (or restart) the service, optionally under a new name and advertising `udp_port` instead of a random port
+    pub fn start_with(&self, service_name: Option<&str>, udp_port: Option<u16>) -> Result<(), OscqError> {
+        let udp_port = udp_port.map(|port| port.to_string());
+        let mut query = Vec::new();
+        if let Some(service_name) = service_name {
+            query.push(("serviceName", service_name));
+        }
+        if let Some(udp_port) = &udp_port {
+            query.push(("udpPort", udp_port.as_str()));
+        }
+        self.send_command_with("start", &query)
+    }
+
+    // Note that this also makes the helper exit
+    pub fn stop(&self) -> Result<(), OscqError> {
+        self.send_command("stop")
+    }
+
+    pub fn udp_port(&self) -> Result<u16, OscqError> {
+        self.get_port("port_udp")
+    }
+
+    pub fn tcp_port(&self) -> Result<u16, OscqError> {
+        self.get_port("port_tcp")
+    }
+
+    pub fn info(&self) -> Result<OscqInfo, OscqError> {
+        let body = self.get_text("info")?;
+        Ok(OscqInfo::parse(&body))
+    }
+
+    // Have the OSCQuery service publish the endpoints (nodes with a TYPE) in `tree` instead of `/avatar`.
+    // The helper keeps them across `/start`.
+    pub fn set_endpoints(&self, tree: &OscQueryNode) -> Result<(), OscqError> {
+        self.post("endpoints", tree.to_json().to_string())
+    }
+
+    // Go back to publishing the helper's single `/avatar` endpoint
+    pub fn reset_endpoints(&self) -> Result<(), OscqError> {
+        self.post("endpoints", String::new())
+    }
+
+    // Find out what is listening at the base URL: the helper, something else, or nothing
+    pub(super) fn probe(&self) -> Responder {
+        classify_responder(
+            self.client
+                .get(self.url("info"))
+                .timeout(PROBE_TIMEOUT)
+                .send()
+                .and_then(|response| response.text()),
+        )
+    }
+
+    fn get_port(&self, command: &str) -> Result<u16, OscqError> {
+        let body = self.get_text(command)?;
+        parse_port(self.url(command), body)
+    }
+
+    fn get_text(&self, command: &str) -> Result<String, OscqError> {
+        let url = self.url(command);
+        self.client
+            .get(&url)
+            .send()
+            .and_then(|response| response.text())
+            .map_err(|source| OscqError::Http { url, source })
+    }
+
+    fn send_command(&self, command: &str) -> Result<(), OscqError> {
+        self.send_command_with(command, &[])
+    }
+
+    fn post(&self, command: &str, body: String) -> Result<(), OscqError> {
+        let url = self.url(command);
+        self.client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|source| OscqError::Http { url, source })?;
+        Ok(())
+    }
+
+    fn send_command_with(&self, command: &str, query: &[(&str, &str)]) -> Result<(), OscqError> {
+        let url = self.url(command);
+        self.client
+            .get(&url)
+            .query(query)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|source| OscqError::Http { url, source })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use crate::oscq_giggletech::testing::FakeHelper;
+    use crate::oscq_giggletech::{Access, OscQueryServer};
+
+    #[test]
+    fn sends_every_command_to_the_helper() {
+        let helper = FakeHelper::start("Giggletech");
+        let client = HelperClient::new(helper.http_port()).unwrap();
+        assert_eq!(client.udp_port().unwrap(), 0);
+
+        client.start_with(Some("Living Room"), Some(9100)).unwrap();
+        let tcp_port = client.tcp_port().unwrap();
+        assert_eq!(client.udp_port().unwrap(), 9100);
+        assert_eq!(
+            client.info().unwrap(),
+            OscqInfo {
+                tcp_port: Some(tcp_port),
+                udp_port: Some(9100),
+                http_port: Some(helper.http_port()),
+                service_name: Some("Living Room".to_string()),
+            }
+        );
+
+        let mut tree = OscQueryNode::root();
+        tree.insert("/avatar/change", OscQueryNode::endpoint("s", Access::Write)).unwrap();
+        client.set_endpoints(&tree).unwrap();
+        assert_eq!(helper.state().endpoints, Some(tree.to_json().to_string()));
+        client.reset_endpoints().unwrap();
+        assert_eq!(helper.state().endpoints, None);
+
+        client.stop().unwrap();
+        assert_eq!(client.udp_port().unwrap(), 0);
+        assert_eq!(client.tcp_port().unwrap(), 0);
+    }
+
+    #[test]
+    fn tells_the_helper_from_other_services() {
+        let helper = FakeHelper::start("Giggletech");
+        assert!(matches!(HelperClient::new(helper.http_port()).unwrap().probe(), Responder::Helper));
+
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+        assert!(matches!(HelperClient::new(server.http_port()).unwrap().probe(), Responder::Foreign(_)));
+
+        let port = helper.http_port();
+        drop(helper);
+        assert!(matches!(HelperClient::new(port).unwrap().probe(), Responder::Nothing));
+    }
+
+    #[test]
+    fn gives_up_on_a_helper_that_doesnt_answer() {
+        // Accepts connections (the kernel does that for it) but never answers
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let base_url = format!("http://localhost:{}", listener.local_addr().unwrap().port());
+        let read_timeout = Duration::from_millis(200);
+        let client = HelperClient::with_timeouts(&base_url, Duration::from_secs(1), read_timeout).unwrap();
+
+        let started = Instant::now();
+        assert!(matches!(client.udp_port(), Err(OscqError::Http { .. })));
+        assert!(started.elapsed() < DEFAULT_READ_TIMEOUT);
+    }
+}
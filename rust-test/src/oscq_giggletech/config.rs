@@ -0,0 +1,484 @@
+/*
+    Configuration of the module and of the helper it launches.
+
+    Every setting has a default, so `config_oscq.yml` only needs the keys that differ from it (or doesn't need to exist at
+    all). Each setting is read from these places, later ones overriding earlier ones:
+    1. the built-in default
+    2. `config_oscq.yml`, under its camelCase key (`httpPort`)
+    3. a `GIGGLETECH_*` environment variable (`GIGGLETECH_HTTP_PORT`)
+    4. a command line flag (`--http-port 7000` or `--http-port=7000`)
+
+    Values from the environment and the command line are read as YAML, like the file, so `--helper-args "[-v, --log]"` and
+    `--startup-timeout null` work. The file has to make sense on its own; the settings as a whole are checked once every
+    override is in (`Config::validate`), so a flag can fix a bad environment variable. Durations are given in seconds (`pollInterval: 0.5`); a `null` startup timeout or max
+    attempts means no limit.
+
+        Key                  Default      Meaning
+        version              2            Schema version of the file, used by `rust-test config migrate`
+        httpPort             6969         Port of the helper's HTTP listener
+        serviceName          Giggletech   Name the OSCQuery service is advertised under
+        httpPortFallback     false        Start the helper on a free port if another application owns httpPort
+        launchMode           auto         How to start the helper (see `launch.rs`)
+        helperPath           (none)       Helper executable, dll or program; defaults to the file in AppData\Local\Giggletech
+        helperArgs           []           Extra arguments for the helper
+        pollInterval         2            How often the supervisor checks the helper
+        hangTimeout          10           How long the helper may go without answering before it is restarted
+        shutdownGrace        3            How long the helper gets to exit after /stop before it is killed
+        startupTimeout       60           Give up bringing the helper up after this long (null: never)
+        startupGrace         3            How long a freshly started helper may take to answer
+        initialBackoff       0.25         First delay between polls while starting up
+        maxBackoff           5            Longest delay between polls while starting up
+        backoffMultiplier    2.0          Growth of the delay after every poll (1.0 - 10.0)
+        backoffJitter        0.2          Random spread of the delay (fraction, 0.0 - 1.0)
+        maxAttempts          (none)       Give up bringing the helper up after this many polls
+        logLevel             info         off | error | warn | info | debug
+*/
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use super::logging::{log_debug, log_info, log_warn, LogLevel};
+use super::locate::locate_config;
+use super::validate;
+use super::{LaunchMode, OscqError, RetryPolicy, CONFIG_VERSION, DEFAULT_STARTUP_TIMEOUT};
+
+// Name of the config file, shared with the helper
+pub const CONFIG_FILE_NAME: &str = "config_oscq.yml";
+
+// Prefix of the environment variables overriding config settings
+const ENV_PREFIX: &str = "GIGGLETECH_";
+
+// Every setting that can be overridden, by its key in the config file
+pub(super) const SETTINGS: &[&str] = &[
+    "httpPort",
+    "serviceName",
+    "httpPortFallback",
+    "launchMode",
+    "helperPath",
+    "helperArgs",
+    "pollInterval",
+    "hangTimeout",
+    "shutdownGrace",
+    "startupTimeout",
+    "startupGrace",
+    "initialBackoff",
+    "maxBackoff",
+    "backoffMultiplier",
+    "backoffJitter",
+    "maxAttempts",
+    "logLevel",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Config {
+    // Schema version the file was written for; files without one are version 1 (see `migrate.rs`)
+    pub version: u32,
+    pub http_port: u16,
+    pub service_name: String,
+    pub http_port_fallback: bool,
+    pub launch_mode: LaunchMode,
+    pub helper_path: Option<PathBuf>,
+    pub helper_args: Vec<String>,
+    #[serde(with = "seconds")]
+    pub poll_interval: Duration,
+    #[serde(with = "seconds")]
+    pub hang_timeout: Duration,
+    #[serde(with = "seconds")]
+    pub shutdown_grace: Duration,
+    #[serde(with = "optional_seconds")]
+    pub startup_timeout: Option<Duration>,
+    #[serde(with = "seconds")]
+    pub startup_grace: Duration,
+    #[serde(with = "seconds")]
+    pub initial_backoff: Duration,
+    #[serde(with = "seconds")]
+    pub max_backoff: Duration,
+    pub backoff_multiplier: f64,
+    pub backoff_jitter: f64,
+    pub max_attempts: Option<u32>,
+    pub log_level: LogLevel,
+    // File this config was read from, if any
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    // Whether it came from the search path (`read`), rather than being put together in code
+    #[serde(skip)]
+    searched: bool,
+    // Settings given on the command line, re-applied when the file is reloaded
+    #[serde(skip)]
+    overrides: Vec<(&'static str, String)>,
+}
+
+// A setting that differs between two configs, with both values in their config file form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingChange {
+    pub key: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let retry = RetryPolicy::default();
+        Config {
+            version: CONFIG_VERSION,
+            http_port: 6969,
+            service_name: "Giggletech".to_string(),
+            http_port_fallback: false,
+            launch_mode: LaunchMode::default(),
+            helper_path: None,
+            helper_args: Vec::new(),
+            poll_interval: Duration::from_secs(2),
+            hang_timeout: Duration::from_secs(10),
+            shutdown_grace: Duration::from_secs(3),
+            startup_timeout: Some(DEFAULT_STARTUP_TIMEOUT),
+            startup_grace: retry.startup_grace,
+            initial_backoff: retry.initial_backoff,
+            max_backoff: retry.max_backoff,
+            backoff_multiplier: retry.multiplier,
+            backoff_jitter: retry.jitter,
+            max_attempts: retry.max_attempts,
+            log_level: LogLevel::default(),
+            path: None,
+            searched: false,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    // Defaults, overridden by the first `config_oscq.yml` in the search path (see `locate.rs`), then by the environment
+    pub fn load() -> Result<Config, OscqError> {
+        Config::load_from(None)
+    }
+
+    // Same as `load`, but tries `explicit` before the rest of the search path
+    pub fn load_from(explicit: Option<&Path>) -> Result<Config, OscqError> {
+        let config = Config::read(explicit)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Same as `load_from`, but not validated yet: for callers that apply more overrides (`apply_args`) and then call
+    // `validate` themselves
+    pub fn read(explicit: Option<&Path>) -> Result<Config, OscqError> {
+        let mut config = match locate_config(explicit)? {
+            Some(location) => {
+                let config = Config::from_file(&location.path)?;
+                log_info!("Using config file {} ({})", location.path.display(), location.source);
+                log_debug!("{:?}", config);
+                config
+            }
+            None => {
+                log_info!("No {} found, using the defaults", CONFIG_FILE_NAME);
+                Config::default()
+            }
+        };
+        config.searched = true;
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    // Read `path`, using the defaults for every key it leaves out
+    pub fn from_file(path: &Path) -> Result<Config, OscqError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(OscqError::ConfigMissing { path: path.to_path_buf() });
+            }
+            Err(source) => return Err(OscqError::ConfigRead { path: path.to_path_buf(), source }),
+        };
+        let mut config = validate::parse_config(&text)
+            .map_err(|rejection| rejection.into_error(Some(path.to_path_buf())))?;
+        if config.version < CONFIG_VERSION {
+            log_warn!(
+                "{} uses config version {}, run `rust-test config migrate` to upgrade it to version {}",
+                path.display(),
+                config.version,
+                CONFIG_VERSION
+            );
+        }
+        config.path = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    // File this config was read from; None if it only holds defaults
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // Whether the config was looked for on the search path, so a file created there later would be one for it
+    #[cfg(feature = "blocking")]
+    pub(super) fn searched(&self) -> bool {
+        self.searched
+    }
+
+    // Override settings from `GIGGLETECH_*` environment variables
+    pub fn apply_env(&mut self) -> Result<(), OscqError> {
+        for key in SETTINGS {
+            let name = env_name(key);
+            if let Ok(value) = std::env::var(&name) {
+                self.set(key, &value).map_err(|reason| OscqError::InvalidSetting { setting: name, value, reason })?;
+            }
+        }
+        Ok(())
+    }
+
+    // Override settings from command line flags.
+    // Returns the arguments that aren't settings, in order, for the caller to handle.
+    pub fn apply_args<I>(&mut self, args: I) -> Result<Vec<String>, OscqError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let mut args = args.into_iter().map(Into::into);
+        let mut rest = Vec::new();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            let Some(key) = SETTINGS.iter().find(|key| flag_name(key) == flag) else {
+                rest.push(arg);
+                continue;
+            };
+            let Some(value) = inline_value.or_else(|| args.next()) else {
+                return Err(OscqError::InvalidSetting {
+                    setting: flag,
+                    value: String::new(),
+                    reason: "missing value".to_string(),
+                });
+            };
+            if let Err(reason) = self.set(key, &value) {
+                return Err(OscqError::InvalidSetting { setting: flag, value, reason });
+            }
+            self.overrides.push((key, value));
+        }
+
+        Ok(rest)
+    }
+
+    // Read the file this config came from again, with the same environment and command line overrides on top. Without
+    // a file the search path is looked through again, so a config file created since then is picked up.
+    pub fn reload(&self) -> Result<Config, OscqError> {
+        let mut config = match &self.path {
+            Some(path) => {
+                let mut config = Config::from_file(path)?;
+                config.searched = self.searched;
+                config.apply_env()?;
+                config
+            }
+            None => Config::read(None)?,
+        };
+        for (key, value) in &self.overrides {
+            config.set(key, value).map_err(|reason| OscqError::InvalidSetting {
+                setting: flag_name(key),
+                value: value.clone(),
+                reason,
+            })?;
+        }
+        config.overrides = self.overrides.clone();
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Every setting that has a different value in `other`
+    pub fn changes(&self, other: &Config) -> Vec<SettingChange> {
+        let (old, new) = (self.to_mapping(), other.to_mapping());
+        SETTINGS
+            .iter()
+            .filter(|key| old.get(**key) != new.get(**key))
+            .map(|key| SettingChange {
+                key,
+                old: old.get(*key).map(display_value).unwrap_or_default(),
+                new: new.get(*key).map(display_value).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    // Check values that have the right type but can't be used, e.g. after applying overrides
+    pub fn validate(&self) -> Result<(), OscqError> {
+        let problems = validate::check(self);
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(OscqError::ConfigInvalid { path: None, problems, source: None })
+        }
+    }
+
+    // Retry policy for bringing the helper up
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            startup_grace: self.startup_grace,
+            initial_backoff: self.initial_backoff,
+            max_backoff: self.max_backoff,
+            multiplier: self.backoff_multiplier,
+            jitter: self.backoff_jitter,
+            max_attempts: self.max_attempts,
+            deadline: self.startup_timeout,
+        }
+    }
+
+    // The settings keyed the way they are in the config file
+    fn to_mapping(&self) -> Mapping {
+        match serde_yaml::to_value(self) {
+            Ok(Value::Mapping(fields)) => fields,
+            _ => unreachable!("Config always serializes to a mapping"),
+        }
+    }
+
+    // Set the setting stored under `key` in the config file from its text form
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let mut fields = self.to_mapping();
+        let parsed = serde_yaml::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+
+        fields.insert(Value::from(key), parsed);
+        let updated = serde_yaml::from_value::<Config>(Value::Mapping(fields.clone())).or_else(|e| {
+            // Text that happens to look like a number or a list, for a setting that wants a string
+            fields.insert(Value::from(key), Value::String(value.to_string()));
+            serde_yaml::from_value::<Config>(Value::Mapping(fields)).map_err(|_| e.to_string())
+        })?;
+
+        *self = Config { path: self.path.take(), overrides: std::mem::take(&mut self.overrides), ..updated };
+        Ok(())
+    }
+}
+
+// One line showing a setting's value the way it would be written in the config file
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Sequence(items) => format!("[{}]", items.iter().map(display_value).collect::<Vec<_>>().join(", ")),
+        value => serde_yaml::to_string(value).unwrap_or_default().trim().replace('\n', " "),
+    }
+}
+
+// "httpPort" -> "GIGGLETECH_HTTP_PORT"
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, split_words(key, '_').to_ascii_uppercase())
+}
+
+// "httpPort" -> "--http-port"
+fn flag_name(key: &str) -> String {
+    format!("--{}", split_words(key, '-').to_ascii_lowercase())
+}
+
+// Put `separator` before every upper case letter of a camelCase key
+fn split_words(key: &str, separator: char) -> String {
+    let mut words = String::new();
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            words.push(separator);
+        }
+        words.push(c);
+    }
+    words
+}
+
+// Durations are written as (fractional) seconds
+mod seconds {
+    use std::time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let seconds = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(seconds).map_err(serde::de::Error::custom)
+    }
+}
+
+mod optional_seconds {
+    use std::time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(deserializer)?
+            .map(|seconds| Duration::try_from_secs_f64(seconds).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscq_giggletech::testing::{config_file, TestEnv};
+    use crate::oscq_giggletech::CONFIG_PATH_ENV;
+
+    // Sets `GIGGLETECH_*` variables through a `TestEnv`, so tests reading the environment don't see them
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let mut env = TestEnv::lock();
+        let path = config_file("precedence", "httpPort: 7000\nserviceName: From File\nmaxBackoff: 8\n");
+        env.set("GIGGLETECH_SERVICE_NAME", "From Env");
+        env.set("GIGGLETECH_MAX_BACKOFF", "9");
+
+        let mut config = Config::load_from(Some(&path)).unwrap();
+        assert_eq!(config.http_port, 7000);
+        assert_eq!(config.service_name, "From Env");
+        assert_eq!(config.max_backoff, Duration::from_secs(9));
+
+        let rest = config.apply_args(["--max-backoff", "10", "--service-name=From Flag", "extra"]).unwrap();
+        assert_eq!(rest, ["extra"]);
+        assert_eq!(config.service_name, "From Flag");
+        assert_eq!(config.max_backoff, Duration::from_secs(10));
+        config.validate().unwrap();
+
+        // The flags stay on top when the file changes
+        fs::write(&path, "httpPort: 7001\nserviceName: Changed\nmaxBackoff: 8\n").unwrap();
+        let reloaded = config.reload().unwrap();
+        assert_eq!(reloaded.http_port, 7001);
+        assert_eq!(reloaded.service_name, "From Flag");
+
+        // Only the settings as a whole are validated: a flag can fix what the environment got wrong...
+        env.set("GIGGLETECH_INITIAL_BACKOFF", "9.5");
+        assert!(Config::load_from(Some(&path)).is_err());
+        let mut config = Config::read(Some(&path)).unwrap();
+        config.apply_args(["--initial-backoff", "1"]).unwrap();
+        config.validate().unwrap();
+
+        // ...and a reload that makes them unusable is an error, not a config to run with. The file is fine on its own,
+        // but its maxBackoff is below the initialBackoff of the flag.
+        env.remove("GIGGLETECH_MAX_BACKOFF");
+        fs::write(&path, "maxBackoff: 0.5\n").unwrap();
+        assert!(matches!(config.reload(), Err(OscqError::ConfigInvalid { .. })));
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reloading_without_a_file_looks_for_one_again() {
+        let mut env = TestEnv::lock();
+        let dir = std::env::temp_dir().join(format!("giggletech-search-{}", std::process::id()));
+        env.remove(CONFIG_PATH_ENV);
+        env.set("XDG_CONFIG_HOME", &dir);
+
+        // Loaded when the search found nothing
+        let config = Config { searched: true, ..Config::default() };
+
+        // A file created since, in the user config directory (searched before the data directory)
+        let path = dir.join("Giggletech").join(CONFIG_FILE_NAME);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "httpPort: 7002\n").unwrap();
+        let reloaded = config.reload().unwrap();
+        assert_eq!(reloaded.path(), Some(path.as_path()));
+        assert_eq!(reloaded.http_port, 7002);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
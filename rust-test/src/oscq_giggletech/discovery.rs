@@ -0,0 +1,540 @@
+/*
+    mDNS / DNS-SD discovery of OSC and OSCQuery services.
+
+    `Browser` browses `_oscjson._tcp` and `_osc._udp` on the local network and keeps track of the services it finds, each
+    as a `ServiceProfile` with the instance name, host address, port and kind. Every receiver from `Browser::events()` gets a
+    `DiscoveryEvent::Added` once a service is fully resolved (PTR -> SRV -> A) and `DiscoveryEvent::Removed` when it sends
+    a goodbye or its records expire. A service whose port or address changes is removed and added again.
+
+    The main use is finding VRChat: its client advertises itself as `VRChat-Client-XXXXXX`, and `Browser::vrchat_osc()`
+    returns the `_osc._udp` profile it listens on, instead of assuming port 9000.
+
+    Queries follow RFC 6762 section 5.2: sent right away, then one second later, then with the interval doubling up to an
+    hour, listing the answers we already have so responders don't repeat them (only those with more than half their TTL
+    left, as section 7.1 says). Announcements seen in between are picked up as well, so new services show up without
+    waiting for the next query.
+
+    Every cached record (PTR, SRV and A) is also refreshed before it runs out: a query for it goes out at 80%, 85%, 90% and
+    95% of its TTL, each with up to 2% of random jitter. The SRV and A records live for only two minutes, so without this
+    a service that is still there would be reported as removed when they expire.
+*/
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use super::dns::{same_name, split_name, Message, Question, Record, RecordData, TYPE_A, TYPE_PTR, TYPE_SRV};
+use super::logging::{log_debug, log_info, log_warn};
+use super::mdns::{mdns_socket, send_message, MDNS_ADDR, MDNS_PORT, OSCJSON_SERVICE, OSC_SERVICE};
+use super::OscqError;
+
+// How VRChat clients name their services, followed by a random suffix
+pub const VRCHAT_CLIENT_PREFIX: &str = "VRChat-Client-";
+
+// Query interval bounds (RFC 6762 section 5.2)
+const FIRST_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_QUERY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How often cached records are checked for expiry
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Fractions of a record's TTL at which it is queried for again (RFC 6762 section 5.2), and the most random jitter added
+const REFRESH_AT: [f64; 4] = [0.80, 0.85, 0.90, 0.95];
+const REFRESH_JITTER: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServiceKind {
+    // `_oscjson._tcp`: an OSCQuery HTTP server
+    OscQuery,
+    // `_osc._udp`: an OSC receiver
+    Osc,
+}
+
+impl ServiceKind {
+    // The DNS-SD service type, e.g. "_osc._udp.local"
+    pub fn service_type(self) -> &'static str {
+        match self {
+            ServiceKind::OscQuery => OSCJSON_SERVICE,
+            ServiceKind::Osc => OSC_SERVICE,
+        }
+    }
+}
+
+// A service found on the network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceProfile {
+    // Instance name, e.g. "VRChat-Client-1A2B3C"
+    pub name: String,
+    pub host: Ipv4Addr,
+    pub port: u16,
+    pub kind: ServiceKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryEvent {
+    Added(ServiceProfile),
+    Removed(ServiceProfile),
+}
+
+pub struct Browser {
+    shared: Arc<Shared>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// State shared between the handle and the browsing thread
+struct Shared {
+    profiles: Mutex<Vec<ServiceProfile>>,
+    subscribers: Mutex<Vec<Sender<DiscoveryEvent>>>,
+}
+
+impl Shared {
+    // Send `event` to every live receiver, forgetting the ones that have been dropped
+    fn publish(&self, event: DiscoveryEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+impl Browser {
+    // Browse on the default multicast interface
+    pub fn start() -> Result<Browser, OscqError> {
+        Browser::start_on(Ipv4Addr::UNSPECIFIED)
+    }
+
+    // Browse on the interface with address `interface`
+    pub fn start_on(interface: Ipv4Addr) -> Result<Browser, OscqError> {
+        let socket = mdns_socket(interface).map_err(|source| OscqError::Mdns { source })?;
+        let shared = Arc::new(Shared { profiles: Mutex::new(Vec::new()), subscribers: Mutex::new(Vec::new()) });
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("oscq-discovery".into())
+                .spawn(move || Cache::default().browse(&socket, &shared, &stop))
+                .map_err(|source| OscqError::ThreadSpawn { name: "mDNS discovery", source })?
+        };
+
+        log_info!("Browsing for OSC and OSCQuery services over mDNS");
+        Ok(Browser { shared, stop, thread: Some(thread) })
+    }
+
+    // New receiver for discovery events; every receiver gets every event sent after it was created
+    pub fn events(&self) -> Receiver<DiscoveryEvent> {
+        let (tx, rx) = unbounded();
+        self.shared.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    // Every service currently known
+    pub fn profiles(&self) -> Vec<ServiceProfile> {
+        self.shared.profiles.lock().unwrap().clone()
+    }
+
+    // The OSC receiver of a running VRChat client, if one has been found
+    pub fn vrchat_osc(&self) -> Option<ServiceProfile> {
+        self.profiles()
+            .into_iter()
+            .find(|profile| profile.kind == ServiceKind::Osc && profile.name.starts_with(VRCHAT_CLIENT_PREFIX))
+    }
+}
+
+impl Drop for Browser {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// What has been heard about one service instance
+struct Instance {
+    kind: ServiceKind,
+    // As the PTR record had it, and just the instance label
+    full_name: String,
+    name: String,
+    // Of the PTR record pointing at it
+    lifetime: Lifetime,
+    // SRV: target host, port and lifetime
+    target: Option<(String, u16, Lifetime)>,
+}
+
+// When a cached record was received, how long it is valid, and how many refresh queries have gone out for it
+#[derive(Debug, Clone, Copy)]
+struct Lifetime {
+    received: Instant,
+    ttl: Duration,
+    refreshes: usize,
+    // Added to each fraction in `REFRESH_AT`
+    jitter: f64,
+}
+
+impl Lifetime {
+    fn new(now: Instant, ttl: u32) -> Lifetime {
+        Lifetime {
+            received: now,
+            ttl: Duration::from_secs(u64::from(ttl)),
+            refreshes: 0,
+            jitter: fastrand::f64() * REFRESH_JITTER,
+        }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        now >= self.received + self.ttl
+    }
+
+    fn remaining(&self, now: Instant) -> Duration {
+        (self.received + self.ttl).saturating_duration_since(now)
+    }
+
+    // Whether the record may be listed as a known answer: only with more than half its TTL left (RFC 6762 section 7.1)
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.remaining(now) > self.ttl / 2
+    }
+
+    // Whether the next refresh query is due, counting it as sent if so
+    fn refresh_due(&mut self, now: Instant) -> bool {
+        let Some(fraction) = REFRESH_AT.get(self.refreshes) else {
+            return false;
+        };
+        if now < self.received + self.ttl.mul_f64(fraction + self.jitter) {
+            return false;
+        }
+        self.refreshes += 1;
+        true
+    }
+}
+
+// Records heard so far, owned by the browsing thread
+#[derive(Default)]
+struct Cache {
+    // By full instance name ("name._osc._udp.local"), lowercased
+    instances: HashMap<String, Instance>,
+    // A records, by lowercased host name
+    hosts: HashMap<String, (Ipv4Addr, Lifetime)>,
+    // Profiles reported as added, by full instance name
+    reported: HashMap<String, ServiceProfile>,
+}
+
+impl Cache {
+    fn browse(mut self, socket: &UdpSocket, shared: &Shared, stop: &AtomicBool) {
+        let mut buffer = [0u8; 9000];
+        let mut next_query = Instant::now();
+        let mut query_interval = FIRST_QUERY_INTERVAL;
+        let mut next_expiry_check = Instant::now() + EXPIRY_CHECK_INTERVAL;
+
+        while !stop.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= next_query {
+                self.send(socket, &self.browse_query(now));
+                next_query = now + query_interval;
+                query_interval = (query_interval * 2).min(MAX_QUERY_INTERVAL);
+            }
+            if now >= next_expiry_check {
+                self.expire(now);
+                self.report(shared);
+                next_expiry_check = now + EXPIRY_CHECK_INTERVAL;
+            }
+            let refresh = self.refresh_query(now);
+            if !refresh.questions.is_empty() {
+                self.send(socket, &refresh);
+            }
+
+            // Times out after the mDNS socket's poll interval
+            let Ok((length, _)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            let Some(message) = Message::decode(&buffer[..length]).filter(Message::is_response) else {
+                continue;
+            };
+            let unresolved = self.learn(&message, Instant::now());
+            if !unresolved.questions.is_empty() {
+                self.send(socket, &unresolved);
+            }
+            self.report(shared);
+        }
+    }
+
+    fn send(&self, socket: &UdpSocket, query: &Message) {
+        if let Err(e) = send_message(socket, query, (MDNS_ADDR, MDNS_PORT)) {
+            log_warn!("Failed to send an mDNS query: {}", e);
+        }
+    }
+
+    // PTR questions for both service types, with the instances we know as known answers
+    fn browse_query(&self, now: Instant) -> Message {
+        let mut query = Message::default();
+        for kind in [ServiceKind::OscQuery, ServiceKind::Osc] {
+            query.questions.push(Question::new(kind.service_type(), TYPE_PTR));
+        }
+        query.answers = self.known_answers(now, None);
+        query
+    }
+
+    // The PTR records still fresh enough to list as known answers, for all service types or just `kind`
+    fn known_answers(&self, now: Instant, kind: Option<ServiceKind>) -> Vec<Record> {
+        self.instances
+            .values()
+            .filter(|instance| kind.is_none_or(|kind| instance.kind == kind) && instance.lifetime.is_fresh(now))
+            .map(|instance| {
+                let remaining = instance.lifetime.remaining(now).as_secs() as u32;
+                Record::new(instance.kind.service_type(), false, remaining, RecordData::Ptr(instance.full_name.clone()))
+            })
+            .collect()
+    }
+
+    // Questions for the cached records that are due for a refresh, or none
+    fn refresh_query(&mut self, now: Instant) -> Message {
+        let mut query = Message::default();
+        let mut kinds = Vec::new();
+        for instance in self.instances.values_mut() {
+            if instance.lifetime.refresh_due(now) && !kinds.contains(&instance.kind) {
+                kinds.push(instance.kind);
+            }
+            if let Some((_, _, lifetime)) = &mut instance.target {
+                if lifetime.refresh_due(now) {
+                    query.questions.push(Question::new(&instance.full_name, TYPE_SRV));
+                }
+            }
+        }
+        for (host, (_, lifetime)) in &mut self.hosts {
+            if lifetime.refresh_due(now) {
+                query.questions.push(Question::new(host, TYPE_A));
+            }
+        }
+        for kind in kinds {
+            query.questions.push(Question::new(kind.service_type(), TYPE_PTR));
+            query.answers.extend(self.known_answers(now, Some(kind)));
+        }
+        query
+    }
+
+    // Take in the records of a response. Returns a query for whatever is still needed to resolve the instances it
+    // points at (only those, so unresolvable instances elsewhere don't make every response trigger another query).
+    fn learn(&mut self, message: &Message, now: Instant) -> Message {
+        let mut pointed_at = Vec::new();
+
+        for record in message.records() {
+            match &record.data {
+                RecordData::Ptr(full_name) => {
+                    let Some(kind) = service_kind(&record.name) else {
+                        continue;
+                    };
+                    let key = full_name.to_ascii_lowercase();
+                    if record.ttl == 0 {
+                        self.instances.remove(&key);
+                        continue;
+                    }
+                    let name = split_name(full_name).into_iter().next().unwrap_or_default();
+                    let instance = self.instances.entry(key).or_insert(Instance {
+                        kind,
+                        full_name: full_name.clone(),
+                        name,
+                        lifetime: Lifetime::new(now, record.ttl),
+                        target: None,
+                    });
+                    instance.lifetime = Lifetime::new(now, record.ttl);
+                    pointed_at.push(full_name.to_ascii_lowercase());
+                }
+                RecordData::Srv { port, target, .. } => {
+                    let Some(instance) = self.instances.get_mut(&record.name.to_ascii_lowercase()) else {
+                        continue;
+                    };
+                    instance.target =
+                        (record.ttl > 0).then(|| (target.to_ascii_lowercase(), *port, Lifetime::new(now, record.ttl)));
+                }
+                RecordData::A(address) => {
+                    let host = record.name.to_ascii_lowercase();
+                    if record.ttl == 0 {
+                        self.hosts.remove(&host);
+                    } else {
+                        self.hosts.insert(host, (*address, Lifetime::new(now, record.ttl)));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Ask for the SRV and A records the response didn't include
+        let mut query = Message::default();
+        for instance in pointed_at.iter().filter_map(|key| self.instances.get(key)) {
+            match &instance.target {
+                None => query.questions.push(Question::new(&instance.full_name, TYPE_SRV)),
+                Some((host, _, _)) if !self.hosts.contains_key(host) => {
+                    query.questions.push(Question::new(host, TYPE_A))
+                }
+                _ => {}
+            }
+        }
+        query
+    }
+
+    // Forget records whose TTL has run out
+    fn expire(&mut self, now: Instant) {
+        self.instances.retain(|_, instance| !instance.lifetime.expired(now));
+        for instance in self.instances.values_mut() {
+            if instance.target.as_ref().is_some_and(|(_, _, lifetime)| lifetime.expired(now)) {
+                instance.target = None;
+            }
+        }
+        self.hosts.retain(|_, (_, lifetime)| !lifetime.expired(now));
+    }
+
+    // Fully resolved services, by full instance name
+    fn resolved(&self) -> HashMap<String, ServiceProfile> {
+        self.instances
+            .iter()
+            .filter_map(|(full_name, instance)| {
+                let (host, port, _) = instance.target.as_ref()?;
+                let (address, _) = self.hosts.get(host)?;
+                let profile =
+                    ServiceProfile { name: instance.name.clone(), host: *address, port: *port, kind: instance.kind };
+                Some((full_name.clone(), profile))
+            })
+            .collect()
+    }
+
+    // Publish what has been added or removed since the last call
+    fn report(&mut self, shared: &Shared) {
+        let resolved = self.resolved();
+
+        let mut removed: Vec<String> = Vec::new();
+        for (full_name, profile) in &self.reported {
+            if resolved.get(full_name) != Some(profile) {
+                log_debug!("mDNS service gone: {} ({:?})", profile.name, profile.kind);
+                shared.publish(DiscoveryEvent::Removed(profile.clone()));
+                removed.push(full_name.clone());
+            }
+        }
+        for full_name in removed {
+            self.reported.remove(&full_name);
+        }
+
+        for (full_name, profile) in resolved {
+            if let Entry::Vacant(entry) = self.reported.entry(full_name) {
+                log_debug!("mDNS service found: {} ({:?}) at {}:{}", profile.name, profile.kind, profile.host, profile.port);
+                shared.publish(DiscoveryEvent::Added(profile.clone()));
+                entry.insert(profile);
+            }
+        }
+
+        *shared.profiles.lock().unwrap() = self.reported.values().cloned().collect();
+    }
+}
+
+// The kind of service a PTR record for `service_type` is about, if it is one we browse for
+fn service_kind(service_type: &str) -> Option<ServiceKind> {
+    [ServiceKind::OscQuery, ServiceKind::Osc]
+        .into_iter()
+        .find(|kind| same_name(service_type, kind.service_type()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscq_giggletech::Advertiser;
+
+    // Wait for an event matching `matches`
+    fn wait_for(events: &Receiver<DiscoveryEvent>, matches: impl Fn(&DiscoveryEvent) -> bool) -> Option<DiscoveryEvent> {
+        wait_for_during(events, Duration::from_secs(5), matches)
+    }
+
+    fn wait_for_during(
+        events: &Receiver<DiscoveryEvent>,
+        timeout: Duration,
+        matches: impl Fn(&DiscoveryEvent) -> bool,
+    ) -> Option<DiscoveryEvent> {
+        let deadline = Instant::now() + timeout;
+        while let Ok(event) = events.recv_deadline(deadline) {
+            if matches(&event) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn finds_advertised_services_and_notices_them_leaving() {
+        let browser = Browser::start_on(Ipv4Addr::LOCALHOST).unwrap();
+        let events = browser.events();
+        let advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Browse Test", 9501, 9502).unwrap();
+
+        let osc = ServiceProfile { name: "Browse Test".into(), host: Ipv4Addr::LOCALHOST, port: 9502, kind: ServiceKind::Osc };
+        let added = DiscoveryEvent::Added(osc.clone());
+        assert_eq!(wait_for(&events, |event| *event == added), Some(added));
+        assert!(browser.profiles().contains(&osc));
+
+        drop(advertiser);
+        let removed = DiscoveryEvent::Removed(osc.clone());
+        assert_eq!(wait_for(&events, |event| *event == removed), Some(removed));
+        assert!(!browser.profiles().contains(&osc));
+    }
+
+    #[test]
+    fn follows_port_changes() {
+        let browser = Browser::start_on(Ipv4Addr::LOCALHOST).unwrap();
+        let events = browser.events();
+        let advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Port Change Test", 9601, 9602).unwrap();
+        let is_ours = |event: &DiscoveryEvent, port| {
+            matches!(event, DiscoveryEvent::Added(profile) if profile.name == "Port Change Test" && profile.port == port)
+        };
+
+        assert!(wait_for(&events, |event| is_ours(event, 9601)).is_some());
+        advertiser.set_ports(9603, 9602);
+        assert!(wait_for(&events, |event| is_ours(event, 9603)).is_some());
+    }
+
+    #[test]
+    fn keeps_services_past_their_ttl_by_refreshing_them() {
+        let browser = Browser::start_on(Ipv4Addr::LOCALHOST).unwrap();
+        let events = browser.events();
+        let _advertiser =
+            Advertiser::start_with_ttls(Ipv4Addr::LOCALHOST, "Refresh Test", 9901, 9902, (3, 6)).unwrap();
+        let is_ours = |profile: &ServiceProfile| profile.name == "Refresh Test";
+
+        assert!(wait_for(&events, |event| matches!(event, DiscoveryEvent::Added(profile) if is_ours(profile))).is_some());
+        // Well past the SRV and A records' TTL, and the PTR's
+        let removed = wait_for_during(&events, Duration::from_secs(8), |event| {
+            matches!(event, DiscoveryEvent::Removed(profile) if is_ours(profile))
+        });
+        assert_eq!(removed, None);
+        assert_eq!(browser.profiles().iter().filter(|profile| is_ours(profile)).count(), 2);
+    }
+
+    #[test]
+    fn refreshes_records_late_in_their_ttl() {
+        let start = Instant::now();
+        let mut lifetime = Lifetime::new(start, 100);
+
+        assert!(!lifetime.refresh_due(start + Duration::from_secs(79)));
+        assert!(lifetime.is_fresh(start + Duration::from_secs(49)));
+        assert!(!lifetime.is_fresh(start + Duration::from_secs(51)));
+        for at in [83, 88, 93, 98] {
+            assert!(lifetime.refresh_due(start + Duration::from_secs(at)), "no refresh at {}s", at);
+            assert!(!lifetime.refresh_due(start + Duration::from_secs(at)));
+        }
+        assert!(!lifetime.refresh_due(start + Duration::from_secs(99)));
+    }
+
+    #[test]
+    fn finds_vrchat() {
+        let browser = Browser::start_on(Ipv4Addr::LOCALHOST).unwrap();
+        let events = browser.events();
+        let _vrchat = Advertiser::start_on(Ipv4Addr::LOCALHOST, "VRChat-Client-1A2B3C", 9701, 9001).unwrap();
+
+        wait_for(&events, |event| matches!(event, DiscoveryEvent::Added(profile) if profile.name.starts_with("VRChat")));
+        let vrchat = browser.vrchat_osc().unwrap();
+        assert_eq!(vrchat.port, 9001);
+        assert_eq!(vrchat.kind, ServiceKind::Osc);
+    }
+}
@@ -0,0 +1,408 @@
+/*
+    DNS messages, as far as mDNS / DNS-SD (RFC 6762, RFC 6763) needs them.
+
+    Only the record types a DNS-SD service is made of are understood: A (the host's address), PTR (service type ->
+    instance), SRV (instance -> host and port) and TXT. Anything else is kept as raw bytes so it can be skipped.
+
+    Names are written in presentation format, "Giggletech._oscjson._tcp.local", with `.` and `\` inside a label escaped
+    by a backslash (instance names are free text and may contain dots). They are encoded without compression, which every
+    reader has to accept; compressed names in received messages are followed. A label longer than the 63 bytes the wire
+    format allows makes encoding fail rather than go out cut short, as a name nobody asked for.
+
+    In mDNS the top bit of a record's class is the cache-flush bit ("this is the whole set, forget older ones") and the top
+    bit of a question's class asks for a unicast response; both are separate fields here.
+*/
+
+use std::net::Ipv4Addr;
+
+use super::OscqError;
+
+pub(super) const TYPE_A: u16 = 1;
+pub(super) const TYPE_PTR: u16 = 12;
+pub(super) const TYPE_TXT: u16 = 16;
+pub(super) const TYPE_SRV: u16 = 33;
+pub(super) const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+// Header flags of an authoritative response
+pub(super) const FLAGS_RESPONSE: u16 = 0x8400;
+const FLAG_QR: u16 = 0x8000;
+
+// Longest label the wire format allows
+pub(super) const MAX_LABEL: usize = 63;
+
+// Most compression pointers followed in one name, so a malicious loop can't hang us
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(super) struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Question {
+    pub name: String,
+    pub rtype: u16,
+    // mDNS: the asker would like the answer sent to it directly
+    pub unicast: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Record {
+    pub name: String,
+    // mDNS: this record replaces every cached one with the same name and type
+    pub flush: bool,
+    // Seconds; 0 withdraws the record ("goodbye")
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+    Other { rtype: u16, data: Vec<u8> },
+}
+
+impl Message {
+    // An empty authoritative response
+    pub fn response() -> Message {
+        Message { flags: FLAGS_RESPONSE, ..Message::default() }
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_QR != 0
+    }
+
+    // Every record in the message, whatever section it is in
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.answers.iter().chain(&self.authorities).chain(&self.additionals)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, OscqError> {
+        let mut out = Vec::with_capacity(512);
+        for value in [
+            self.id,
+            self.flags,
+            self.questions.len() as u16,
+            self.answers.len() as u16,
+            self.authorities.len() as u16,
+            self.additionals.len() as u16,
+        ] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+
+        for question in &self.questions {
+            write_name(&mut out, &question.name)?;
+            out.extend_from_slice(&question.rtype.to_be_bytes());
+            let class = if question.unicast { CLASS_IN | CLASS_TOP_BIT } else { CLASS_IN };
+            out.extend_from_slice(&class.to_be_bytes());
+        }
+        for record in self.records() {
+            record.encode(&mut out)?;
+        }
+        Ok(out)
+    }
+
+    // None if `bytes` isn't a well-formed DNS message
+    pub fn decode(bytes: &[u8]) -> Option<Message> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut message = Message { id, flags, ..Message::default() };
+        for _ in 0..counts[0] {
+            let name = reader.name()?;
+            let rtype = reader.u16()?;
+            let class = reader.u16()?;
+            message.questions.push(Question { name, rtype, unicast: class & CLASS_TOP_BIT != 0 });
+        }
+        for (count, section) in [
+            (counts[1], &mut message.answers),
+            (counts[2], &mut message.authorities),
+            (counts[3], &mut message.additionals),
+        ] {
+            for _ in 0..count {
+                section.push(reader.record()?);
+            }
+        }
+        Some(message)
+    }
+}
+
+impl Question {
+    pub fn new(name: &str, rtype: u16) -> Question {
+        Question { name: name.to_string(), rtype, unicast: false }
+    }
+}
+
+impl Record {
+    pub fn new(name: &str, flush: bool, ttl: u32, data: RecordData) -> Record {
+        Record { name: name.to_string(), flush, ttl, data }
+    }
+
+    pub fn rtype(&self) -> u16 {
+        match &self.data {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Txt(_) => TYPE_TXT,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Other { rtype, .. } => *rtype,
+        }
+    }
+
+    // Whether this record answers `question`
+    pub fn answers(&self, question: &Question) -> bool {
+        same_name(&self.name, &question.name) && (question.rtype == TYPE_ANY || question.rtype == self.rtype())
+    }
+
+    // Same name, type and data, whatever the TTL
+    pub fn same_as(&self, other: &Record) -> bool {
+        same_name(&self.name, &other.name) && self.data == other.data
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), OscqError> {
+        write_name(out, &self.name)?;
+        out.extend_from_slice(&self.rtype().to_be_bytes());
+        let class = if self.flush { CLASS_IN | CLASS_TOP_BIT } else { CLASS_IN };
+        out.extend_from_slice(&class.to_be_bytes());
+        out.extend_from_slice(&self.ttl.to_be_bytes());
+
+        let data = self.data_bytes()?;
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&data);
+        Ok(())
+    }
+
+    // The record data as it goes on the wire, which is also what simultaneous probes are compared by
+    pub fn data_bytes(&self) -> Result<Vec<u8>, OscqError> {
+        let mut out = Vec::new();
+        match &self.data {
+            RecordData::A(address) => out.extend_from_slice(&address.octets()),
+            RecordData::Ptr(name) => write_name(&mut out, name)?,
+            RecordData::Txt(entries) if entries.is_empty() => out.push(0),
+            RecordData::Txt(entries) => {
+                for entry in entries {
+                    let entry = &entry.as_bytes()[..entry.len().min(255)];
+                    out.push(entry.len() as u8);
+                    out.extend_from_slice(entry);
+                }
+            }
+            RecordData::Srv { priority, weight, port, target } => {
+                for value in [priority, weight, port] {
+                    out.extend_from_slice(&value.to_be_bytes());
+                }
+                write_name(&mut out, target)?;
+            }
+            RecordData::Other { data, .. } => out.extend_from_slice(data),
+        }
+        Ok(out)
+    }
+}
+
+// DNS names compare case-insensitively
+pub(super) fn same_name(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+// Escape a free-text label (e.g. an instance name) for use in a name
+pub(super) fn escape_label(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('.', "\\.")
+}
+
+// The labels of a name, with escapes removed
+pub(super) fn split_name(name: &str) -> Vec<String> {
+    let mut labels = vec![String::new()];
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => labels.last_mut().unwrap().extend(chars.next()),
+            '.' => labels.push(String::new()),
+            c => labels.last_mut().unwrap().push(c),
+        }
+    }
+    labels.retain(|label| !label.is_empty());
+    labels
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) -> Result<(), OscqError> {
+    for label in split_name(name) {
+        if label.len() > MAX_LABEL {
+            return Err(OscqError::InvalidDnsName { name: name.to_string(), label });
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Option<&[u8]> {
+        let slice = self.bytes.get(self.pos..self.pos + count)?;
+        self.pos += count;
+        Some(slice)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // A possibly compressed name
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut pointers = 0;
+        // Where reading continues once the name is done, set by the first pointer
+        let mut resume = None;
+
+        loop {
+            let length = *self.bytes.get(pos)? as usize;
+            if length == 0 {
+                pos += 1;
+                break;
+            }
+            if length & 0xC0 == 0xC0 {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                resume.get_or_insert(pos + 2);
+                pos = ((length & 0x3F) << 8) | *self.bytes.get(pos + 1)? as usize;
+                continue;
+            }
+            if length > MAX_LABEL {
+                return None;
+            }
+            let label = self.bytes.get(pos + 1..pos + 1 + length)?;
+            labels.push(escape_label(&String::from_utf8_lossy(label)));
+            pos += 1 + length;
+        }
+
+        self.pos = resume.unwrap_or(pos);
+        Some(labels.join("."))
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let length = self.u16()? as usize;
+        let end = self.pos + length;
+        if end > self.bytes.len() {
+            return None;
+        }
+
+        let data = match rtype {
+            TYPE_A if length == 4 => {
+                let octets = self.take(4)?;
+                RecordData::A(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+            }
+            TYPE_PTR => RecordData::Ptr(self.name()?),
+            TYPE_SRV => RecordData::Srv {
+                priority: self.u16()?,
+                weight: self.u16()?,
+                port: self.u16()?,
+                target: self.name()?,
+            },
+            TYPE_TXT => {
+                let mut entries = Vec::new();
+                while self.pos < end {
+                    let entry_length = *self.take(1)?.first()? as usize;
+                    let entry = self.take(entry_length)?;
+                    if !entry.is_empty() {
+                        entries.push(String::from_utf8_lossy(entry).into_owned());
+                    }
+                }
+                RecordData::Txt(entries)
+            }
+            _ => RecordData::Other { rtype, data: self.take(length)?.to_vec() },
+        };
+        self.pos = end;
+        Some(Record { name, flush: class & CLASS_TOP_BIT != 0, ttl, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_a_roundtrip() {
+        let mut message = Message::response();
+        message.questions.push(Question { name: "_osc._udp.local".into(), rtype: TYPE_PTR, unicast: true });
+        message.answers.push(Record::new("_osc._udp.local", false, 4500, RecordData::Ptr("A\\.B._osc._udp.local".into())));
+        message.additionals.push(Record::new(
+            "A\\.B._osc._udp.local",
+            true,
+            120,
+            RecordData::Srv { priority: 0, weight: 0, port: 9001, target: "host.local".into() },
+        ));
+        message.additionals.push(Record::new("A\\.B._osc._udp.local", true, 4500, RecordData::Txt(vec!["txtvers=1".into()])));
+        message.additionals.push(Record::new("host.local", true, 120, RecordData::A(Ipv4Addr::LOCALHOST)));
+
+        assert_eq!(Message::decode(&message.encode().unwrap()), Some(message));
+    }
+
+    #[test]
+    fn follows_compressed_names() {
+        // "a.local" at offset 12, then a PTR question for "b" + pointer to "local"
+        let mut bytes = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+        bytes.extend_from_slice(b"\x01a\x05local\x00\x00\x01\x00\x01");
+        bytes.extend_from_slice(b"\x01b\xC0\x0E\x00\x0C\x00\x01");
+        let message = Message::decode(&bytes).unwrap();
+
+        assert_eq!(message.questions[0].name, "a.local");
+        assert_eq!(message.questions[1].name, "b.local");
+        assert_eq!(message.questions[1].rtype, TYPE_PTR);
+    }
+
+    #[test]
+    fn rejects_pointer_loops_and_truncated_messages() {
+        let mut bytes = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        bytes.extend_from_slice(b"\xC0\x0C\x00\x01\x00\x01");
+
+        assert_eq!(Message::decode(&bytes), None);
+        assert_eq!(Message::decode(&[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn refuses_labels_longer_than_the_wire_format_allows() {
+        let mut message = Message::default();
+        message.questions.push(Question::new(&format!("{}._osc._udp.local", "ä".repeat(32)), TYPE_PTR));
+        let Err(OscqError::InvalidDnsName { label, .. }) = message.encode() else {
+            panic!("a 64 byte label must not be encoded");
+        };
+        assert_eq!(label, "ä".repeat(32));
+
+        message.questions[0].name = format!("{}._osc._udp.local", "x".repeat(MAX_LABEL));
+        let encoded = message.encode().unwrap();
+        assert_eq!(Message::decode(&encoded), Some(message));
+    }
+
+    #[test]
+    fn splits_escaped_names() {
+        assert_eq!(split_name("My\\.PC._osc._udp.local"), vec!["My.PC", "_osc", "_udp", "local"]);
+        assert_eq!(escape_label("a.b\\c"), "a\\.b\\\\c");
+    }
+}
@@ -0,0 +1,148 @@
+/*
+    Error type for the Giggletech OSCQuery module.
+
+    Every fallible step of bringing up `giggletech_oscq.exe` maps to one variant here, and each variant carries
+    the context needed to show a useful message to the user (the file that was looked at, the URL that was
+    requested, the text the helper answered with, ...). Host applications can match on the variant to decide
+    whether to retry, tell the user to fix their config, or simply carry on without OSC.
+*/
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+use super::ConfigProblem;
+
+#[derive(Debug, Error)]
+pub enum OscqError {
+    // The platform has no AppData\Local (or XDG data) directory to look in
+    #[error("could not determine the local data directory")]
+    DataDirUnavailable,
+
+    #[error("config file not found at {}", path.display())]
+    ConfigMissing { path: PathBuf },
+
+    #[error("failed to read config file {}: {source}", path.display())]
+    ConfigRead {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    // Everything wrong with the config, found in one go; `path` is None for settings from the environment or command line.
+    // `source` is the YAML parser's error for the first setting it couldn't read, if that is what was wrong.
+    #[error("invalid config{}:{}", path.as_ref().map(|path| format!(" file {}", path.display())).unwrap_or_default(), list(problems))]
+    ConfigInvalid {
+        path: Option<PathBuf>,
+        problems: Vec<ConfigProblem>,
+        #[source]
+        source: Option<serde_yaml::Error>,
+    },
+
+    // A config override from the environment or the command line has a value the setting can't take
+    #[error("invalid value {value:?} for {setting}: {reason}")]
+    InvalidSetting { setting: String, value: String, reason: String },
+
+    #[error("config file {} already exists", path.display())]
+    ConfigExists { path: PathBuf },
+
+    #[error("failed to write config file {}: {source}", path.display())]
+    ConfigWrite {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("helper executable not found at {}", path.display())]
+    ExecutableMissing { path: PathBuf },
+
+    #[error("failed to start helper {}: {source}", path.display())]
+    Spawn {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("HTTP request to {url} failed: {source}")]
+    Http {
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    // Another application is listening on the helper's HTTP port
+    #[error("port {port} is used by another application, not the Giggletech OSCQuery Helper (it answered {response:?})")]
+    PortOccupiedByForeignService { port: u16, response: String },
+
+    // The helper answered, but not with something we can use as a port number
+    #[error("{url} returned an invalid port: {body:?}")]
+    InvalidPort { url: String, body: String },
+
+    // An OSCQuery service answered with JSON that isn't an OSCQuery node
+    #[error("{url} did not answer with an OSCQuery node")]
+    InvalidNode { url: String },
+
+    // We bound the OSC socket and asked the helper to advertise its port, but it kept using a port of its own
+    #[error("the helper advertises UDP port {reported} instead of {bound}, the port of our socket (it may be too old to support --udp-port)")]
+    UdpPortIgnored { bound: u16, reported: u16 },
+
+    #[error("failed to bind the OSC UDP socket: {source}")]
+    UdpBind {
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to start the OSCQuery server on {address}: {source}")]
+    ServerBind {
+        address: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+
+    // A path given for an OSCQuery node isn't a valid OSC address
+    #[error("invalid OSC address {path:?}: {reason}")]
+    InvalidOscAddress { path: String, reason: &'static str },
+
+    // A name to be sent over mDNS has a label (the part between two dots) the DNS wire format can't hold
+    #[error("invalid DNS name {name:?}: {label:?} is longer than {} bytes", super::dns::MAX_LABEL)]
+    InvalidDnsName { name: String, label: String },
+
+    #[error("failed to open the mDNS socket: {source}")]
+    Mdns {
+        #[source]
+        source: io::Error,
+    },
+
+    // One of our background threads (the supervisor's watchdog, a server, an mDNS responder, ...) couldn't be started
+    #[error("failed to start the {name} thread: {source}")]
+    ThreadSpawn {
+        name: &'static str,
+        #[source]
+        source: io::Error,
+    },
+
+    // The operation was interrupted because the supervisor is shutting down
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("no UDP port was reported after {attempts} attempts")]
+    RetriesExhausted {
+        attempts: u32,
+        #[source]
+        last_error: Option<Box<OscqError>>,
+    },
+
+    #[error("no UDP port was reported within {}s", after.as_secs_f32())]
+    Timeout {
+        after: Duration,
+        #[source]
+        last_error: Option<Box<OscqError>>,
+    },
+}
+
+// One problem per line, for the `ConfigInvalid` message
+fn list(problems: &[ConfigProblem]) -> String {
+    problems.iter().map(|problem| format!("\n  - {}", problem)).collect()
+}
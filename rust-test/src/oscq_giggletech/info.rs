@@ -0,0 +1,109 @@
+/*
+    Typed view of the helper's /info response.
+
+    The C# helper answers /info with plain text lines:
+
+        Giggletech OSCQuery Helper
+        TCP Port: 51234
+        UDP Port: 51235
+        HTTP Listener Port: 6969
+        Service Name: Giggletech
+
+    `OscqInfo::parse` reads the "Key: Value" lines it knows and ignores everything else, so older or newer helpers that
+    leave out or add lines still parse. A field that is missing or doesn't hold a valid value is `None`.
+*/
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OscqInfo {
+    pub tcp_port: Option<u16>,
+    pub udp_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub service_name: Option<String>,
+}
+
+impl OscqInfo {
+    pub fn parse(text: &str) -> OscqInfo {
+        let mut info = OscqInfo::default();
+
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match key.trim().to_ascii_lowercase().as_str() {
+                "tcp port" => info.tcp_port = parse_port(value),
+                "udp port" => info.udp_port = parse_port(value),
+                "http listener port" => info.http_port = parse_port(value),
+                "service name" if !value.is_empty() => info.service_name = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        info
+    }
+}
+
+// The helper reports 0 while the OSCQuery service isn't started, which is as good as no port
+fn parse_port(value: &str) -> Option<u16> {
+    value.parse().ok().filter(|&port| port != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_helper_response() {
+        let text = "Giggletech OSCQuery Helper\nTCP Port: 51234\nUDP Port: 51235\nHTTP Listener Port: 6969\nService Name: Giggletech";
+
+        assert_eq!(
+            OscqInfo::parse(text),
+            OscqInfo {
+                tcp_port: Some(51234),
+                udp_port: Some(51235),
+                http_port: Some(6969),
+                service_name: Some("Giggletech".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn service_not_started_has_no_ports() {
+        let text = "Giggletech OSCQuery Helper\nTCP Port: 0\nUDP Port: 0\nHTTP Listener Port: 6969\nService Name: Giggletech";
+        let info = OscqInfo::parse(text);
+
+        assert_eq!(info.tcp_port, None);
+        assert_eq!(info.udp_port, None);
+        assert_eq!(info.http_port, Some(6969));
+    }
+
+    #[test]
+    fn tolerates_missing_fields_and_crlf() {
+        let info = OscqInfo::parse("Giggletech OSCQuery Helper\r\nUDP Port: 9001\r\n");
+
+        assert_eq!(info.udp_port, Some(9001));
+        assert_eq!(info.tcp_port, None);
+        assert_eq!(info.http_port, None);
+        assert_eq!(info.service_name, None);
+    }
+
+    #[test]
+    fn ignores_malformed_lines() {
+        let text = "TCP Port: lots\nUDP Port 9001\nHTTP Listener Port: 70000\nService Name:   \nsome: other: line";
+
+        assert_eq!(OscqInfo::parse(text), OscqInfo::default());
+    }
+
+    #[test]
+    fn keeps_colons_in_service_name() {
+        let info = OscqInfo::parse("service name:  Giggletech: Living Room ");
+
+        assert_eq!(info.service_name.as_deref(), Some("Giggletech: Living Room"));
+    }
+
+    #[test]
+    fn empty_response() {
+        assert_eq!(OscqInfo::parse(""), OscqInfo::default());
+    }
+}
@@ -0,0 +1,191 @@
+/*
+    How the helper process is launched.
+
+    The helper can be run in three ways, picked with `launchMode` in the config:
+    - `native`:  the self-contained executable (`giggletech_oscq.exe` on Windows, `giggletech_oscq` elsewhere).
+    - `dotnet`:  the framework-dependent build, run as `dotnet giggletech_oscq.dll` (the `exported/` builds ship the
+                 `deps.json` and `runtimeconfig.json` this needs). Works on Linux dev boxes and CI.
+    - `command`: an explicit program, e.g. a wrapper script, with `helperArgs` as its arguments.
+    - `auto` (default): `native` if the executable exists, otherwise `dotnet` if the dll exists.
+
+    `helperPath` points at the executable, dll or program; it defaults to the file in `AppData\Local\Giggletech`.
+
+    The helper is pointed at the config file we read with `--config` / `GIGGLETECH_CONFIG`, and runs in its directory (where
+    it writes `service_log_oscq.txt`). Without a config file it runs in its own directory, never in whatever our working
+    directory happens to be. On top of that the settings both sides have to agree on are handed over explicitly,
+    as `--http-port` / `--service-name` arguments and as `GIGGLETECH_HTTP_PORT` / `GIGGLETECH_SERVICE_NAME` environment
+    variables, so the helper can't fall back to its defaults behind our back. When we have bound the OSC socket ourselves,
+    its port is passed the same way (`--udp-port` / `GIGGLETECH_UDP_PORT`) for the helper to advertise. When the service
+    is advertised over mDNS from Rust instead (see `mdns.rs`), the helper is told to leave that out (`--no-mdns` /
+    `GIGGLETECH_NO_MDNS`).
+*/
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+use std::path::{Path, PathBuf};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use std::process::Command;
+use serde::{Deserialize, Serialize};
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+use super::logging::log_info;
+#[cfg(any(feature = "blocking", feature = "async"))]
+use super::{giggletech_dir, lifetime, Config, OscqError, CONFIG_PATH_ENV};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LaunchMode {
+    #[default]
+    Auto,
+    Native,
+    Dotnet,
+    Command,
+}
+
+// File name of the self-contained helper executable on this platform
+#[cfg(any(feature = "blocking", feature = "async"))]
+const NATIVE_NAME: &str = if cfg!(windows) { "giggletech_oscq.exe" } else { "giggletech_oscq" };
+// File name of the framework-dependent helper, run with `dotnet`
+#[cfg(any(feature = "blocking", feature = "async"))]
+const DLL_NAME: &str = "giggletech_oscq.dll";
+
+// Build the command that starts the helper listening on `http_port`,
+// advertising `udp_port` (a socket we have bound already) instead of picking its own.
+// Without `mdns` the helper doesn't advertise itself over mDNS, because we do that from Rust.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(super) fn helper_command(
+    config: &Config,
+    http_port: u16,
+    udp_port: Option<u16>,
+    mdns: bool,
+) -> Result<Command, OscqError> {
+    // The helper runs in another directory than ours, so relative paths are made absolute first. Only a bare program
+    // name for `command` mode is left alone, to be looked up on PATH.
+    let (mode, file) = resolve_mode(config)?;
+    let is_bare = file.parent().is_some_and(|dir| dir.as_os_str().is_empty());
+    let file = if mode == LaunchMode::Command && is_bare { file } else { absolute(&file) };
+    let config_path = config.path().map(absolute);
+
+    let mut command = match mode {
+        LaunchMode::Dotnet => {
+            require_file(&file)?;
+            let mut command = Command::new("dotnet");
+            command.arg(&file);
+            command
+        }
+        LaunchMode::Command => Command::new(&file),
+        _ => {
+            require_file(&file)?;
+            Command::new(&file)
+        }
+    };
+    command.args(&config.helper_args);
+    command.arg("--http-port").arg(http_port.to_string());
+    command.env("GIGGLETECH_HTTP_PORT", http_port.to_string());
+    command.arg("--service-name").arg(&config.service_name);
+    command.env("GIGGLETECH_SERVICE_NAME", &config.service_name);
+    if let Some(udp_port) = udp_port {
+        command.arg("--udp-port").arg(udp_port.to_string());
+        command.env("GIGGLETECH_UDP_PORT", udp_port.to_string());
+    }
+    if !mdns {
+        command.arg("--no-mdns");
+        command.env("GIGGLETECH_NO_MDNS", "1");
+    }
+    if let Some(path) = &config_path {
+        command.arg("--config").arg(path);
+        command.env(CONFIG_PATH_ENV, path);
+    }
+    // The config file's directory, or else the helper's own (AppData\Local\Giggletech for a program on PATH)
+    let helper_dir = file.parent().filter(|dir| !dir.as_os_str().is_empty());
+    let working_dir = match config_path.as_deref().and_then(Path::parent).or(helper_dir) {
+        Some(dir) => Ok(dir.to_path_buf()),
+        None => giggletech_dir(),
+    };
+    if let Ok(dir) = working_dir {
+        command.current_dir(dir);
+    }
+    lifetime::configure_command(&mut command);
+
+    // Display a message indicating the process is being started and the command used to start it
+    log_info!("Starting OSCQ Server: {:?}", command);
+
+    Ok(command)
+}
+
+// Turn `auto` into a concrete mode and work out which file it runs
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn resolve_mode(config: &Config) -> Result<(LaunchMode, PathBuf), OscqError> {
+    let default_path = |name: &str| giggletech_dir().map(|dir| dir.join(name));
+
+    match (config.launch_mode, &config.helper_path) {
+        (LaunchMode::Auto, Some(path)) if has_extension(path, "dll") => Ok((LaunchMode::Dotnet, path.clone())),
+        (LaunchMode::Auto, Some(path)) => Ok((LaunchMode::Native, path.clone())),
+        (LaunchMode::Auto, None) => {
+            let native = default_path(NATIVE_NAME)?;
+            let dll = default_path(DLL_NAME)?;
+            if !native.is_file() && dll.is_file() {
+                Ok((LaunchMode::Dotnet, dll))
+            } else {
+                Ok((LaunchMode::Native, native))
+            }
+        }
+        (mode, Some(path)) => Ok((mode, path.clone())),
+        (LaunchMode::Dotnet, None) => Ok((LaunchMode::Dotnet, default_path(DLL_NAME)?)),
+        (mode, None) => Ok((mode, default_path(NATIVE_NAME)?)),
+    }
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn require_file(path: &Path) -> Result<(), OscqError> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(OscqError::ExecutableMissing { path: path.to_path_buf() })
+    }
+}
+
+#[cfg(all(test, any(feature = "blocking", feature = "async")))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn command_config(program: &str) -> Config {
+        let mut config = Config::default();
+        config.launch_mode = LaunchMode::Command;
+        config.helper_path = Some(PathBuf::from(program));
+        config
+    }
+
+    #[test]
+    fn runs_the_helper_in_a_directory_of_its_own() {
+        // Without a config file: the helper's directory, with the helper given by absolute path
+        let command = helper_command(&command_config("tools/helper.sh"), 6969, None, true).unwrap();
+        let tools = std::env::current_dir().unwrap().join("tools");
+        assert_eq!(command.get_current_dir(), Some(tools.as_path()));
+        assert_eq!(Path::new(command.get_program()), tools.join("helper.sh"));
+
+        // A program on PATH stays one, and runs in the data directory
+        let command = helper_command(&command_config("helper.sh"), 6969, None, true).unwrap();
+        assert_eq!(command.get_program(), "helper.sh");
+        assert_eq!(command.get_current_dir(), giggletech_dir().ok().as_deref());
+
+        // With one: the config file's directory
+        let dir = std::env::temp_dir().join(format!("giggletech-launch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("config_oscq.yml"), "launchMode: command\nhelperPath: helper.sh\n").unwrap();
+        let config = Config::from_file(&dir.join("config_oscq.yml")).unwrap();
+        let command = helper_command(&config, 6969, None, true).unwrap();
+        assert_eq!(command.get_current_dir(), Some(dir.as_path()));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
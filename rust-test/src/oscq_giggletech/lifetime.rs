@@ -0,0 +1,303 @@
+/*
+    Tie the helper's lifetime to ours (Linux).
+
+    If the Rust process is SIGKILLed or crashes, nothing gets to send `/stop`, and the helper would be left behind holding
+    httpPort. On Linux this is prevented in two ways:
+    - `configure_command` starts the helper in its own process group (so a Ctrl-C in the terminal reaches us, and we shut
+      it down in order, instead of hitting both at once) and sets a parent-death signal, so the kernel kills the helper
+      when we die. The kernel ties this signal to the *thread* that spawned the helper, not to the process, so helpers
+      are only spawned through `spawn_helper`, on one thread that lives as long as we do. Spawned from a short-lived
+      thread (a supervisor's, or a tokio worker's) the helper would be killed as soon as that thread exits.
+    - Every helper we start is recorded in a state file (`giggletech_oscq-<httpPort>.pid` in the Giggletech data
+      directory, `AppData\Local\Giggletech` or `~/.local/share/Giggletech`) together with our own PID and the command
+      line it was started with. At startup `reap_stale_helper` kills a helper whose recorded owner is no longer running,
+      or one of ours that was lost track of (say by a restart within this process) and is still alive. The helper is only
+      killed while its command line still matches the recorded one (the kernel may put a script's interpreter in front),
+      so a PID since reused by another program is left alone, whichever way the helper was launched.
+
+    On other platforms these functions do nothing.
+*/
+
+use std::io;
+use std::process::Command;
+
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::os::unix::process::CommandExt;
+#[cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use std::sync::mpsc::{self, Sender};
+#[cfg(target_os = "linux")]
+use std::sync::{Mutex, PoisonError};
+#[cfg(target_os = "linux")]
+use std::thread::{self, sleep};
+#[cfg(target_os = "linux")]
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use super::giggletech_dir;
+#[cfg(target_os = "linux")]
+use super::logging::{log_info, log_warn};
+
+// Start the helper in its own process group, and have the kernel kill it if we die
+#[cfg(target_os = "linux")]
+pub(super) fn configure_command(command: &mut Command) {
+    let parent = std::process::id() as libc::pid_t;
+    command.process_group(0);
+
+    // SAFETY: only async-signal-safe libc calls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL as libc::c_ulong, 0, 0, 0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // We may have died before the prctl took effect
+            if libc::getppid() != parent {
+                libc::_exit(1);
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn configure_command(_command: &mut Command) {}
+
+// Run `spawn`, which starts a helper, on the thread every helper is started from, and return its result.
+// Also fails if that thread can't be started, or is gone.
+#[cfg(target_os = "linux")]
+pub(super) fn spawn_helper<T: Send + 'static>(
+    spawn: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    type Job = Box<dyn FnOnce() + Send>;
+    static SPAWNER: Mutex<Option<Sender<Job>>> = Mutex::new(None);
+
+    let (sender, result) = mpsc::sync_channel(1);
+    let job: Job = Box::new(move || {
+        let _ = sender.send(spawn());
+    });
+    let gone = || io::Error::other("the helper spawner thread is gone");
+    {
+        let mut spawner = SPAWNER.lock().unwrap_or_else(PoisonError::into_inner);
+        let jobs = match spawner.take() {
+            Some(jobs) => jobs,
+            None => {
+                let (jobs, queue) = mpsc::channel::<Job>();
+                thread::Builder::new()
+                    .name("oscq-spawner".into())
+                    .spawn(move || queue.into_iter().for_each(|job| job()))?;
+                jobs
+            }
+        };
+        // A spawner thread that is gone is replaced by the next call
+        jobs.send(job).map_err(|_| gone())?;
+        *spawner = Some(jobs);
+    }
+    result.recv().map_err(|_| gone())?
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn spawn_helper<T>(spawn: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    spawn()
+}
+
+// The program and arguments of `command`, as they show up in /proc/<pid>/cmdline
+pub(super) fn command_line(command: &Command) -> Vec<String> {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+// State file recording the helper started for `http_port`
+#[cfg(target_os = "linux")]
+fn state_file(http_port: u16) -> Option<PathBuf> {
+    giggletech_dir()
+        .ok()
+        .map(|dir| dir.join(format!("giggletech_oscq-{}.pid", http_port)))
+}
+
+// Remember that we started helper `pid` on `http_port`, with the command line `launched`
+#[cfg(target_os = "linux")]
+pub(super) fn record_helper(http_port: u16, pid: u32, launched: &[String]) {
+    let Some(path) = state_file(http_port) else {
+        return;
+    };
+    let written = match path.parent() {
+        Some(dir) => fs::create_dir_all(dir),
+        None => Ok(()),
+    };
+    let launched = serde_json::to_string(launched).unwrap_or_default();
+    let state = format!("{} {}\n{}\n", pid, std::process::id(), launched);
+    if let Err(e) = written.and_then(|_| fs::write(&path, state)) {
+        log_warn!("Failed to write {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn record_helper(_http_port: u16, _pid: u32, _launched: &[String]) {}
+
+// Forget helper `pid` once it has been stopped
+#[cfg(target_os = "linux")]
+pub(super) fn forget_helper(http_port: u16, pid: u32) {
+    let Some(path) = state_file(http_port) else {
+        return;
+    };
+    if read_state(&path).is_some_and(|state| state.helper == pid) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn forget_helper(_http_port: u16, _pid: u32) {}
+
+// Kill a helper left behind on `http_port` by a previous run that didn't get to stop it
+#[cfg(target_os = "linux")]
+pub(super) fn reap_stale_helper(http_port: u16) {
+    let Some(path) = state_file(http_port) else {
+        return;
+    };
+    let Some(state) = read_state(&path) else {
+        return;
+    };
+    let ours = state.owner == std::process::id();
+
+    // Still owned by another running process (possibly another instance of our app); leave it alone
+    if !ours && process_alive(state.owner) {
+        return;
+    }
+
+    if process_alive(state.helper) && is_helper_process(state.helper, &state.launched) {
+        if ours {
+            log_info!("Stopping OSCQ Server this program lost track of (PID {})", state.helper);
+        } else {
+            log_info!("Stopping OSCQ Server left behind by a previous run (PID {})", state.helper);
+        }
+        // SAFETY: plain kill(2) on a PID we just verified belongs to the helper
+        unsafe {
+            libc::kill(state.helper as libc::pid_t, libc::SIGKILL);
+        }
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while process_alive(state.helper) && Instant::now() < deadline {
+            if ours {
+                // Our own child stays a zombie until it is waited for
+                // SAFETY: waitpid(2) without blocking, on a child of ours
+                unsafe {
+                    libc::waitpid(state.helper as libc::pid_t, std::ptr::null_mut(), libc::WNOHANG);
+                }
+            }
+            sleep(Duration::from_millis(20));
+        }
+    }
+    let _ = fs::remove_file(path);
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn reap_stale_helper(_http_port: u16) {}
+
+// What a state file records
+#[cfg(target_os = "linux")]
+struct HelperState {
+    helper: u32,
+    owner: u32,
+    // Command line the helper was started with; empty in files written before it was recorded
+    launched: Vec<String>,
+}
+
+// Read "<helper pid> <owner pid>" and the command line (as a JSON list) from a state file
+#[cfg(target_os = "linux")]
+fn read_state(path: &Path) -> Option<HelperState> {
+    let text = fs::read_to_string(path).ok()?;
+    let (pids, launched) = text.split_once('\n').unwrap_or((&text, ""));
+    let mut pids = pids.split_whitespace().map(|pid| pid.parse().ok());
+    Some(HelperState {
+        helper: pids.next()??,
+        owner: pids.next()??,
+        launched: serde_json::from_str(launched.trim()).unwrap_or_default(),
+    })
+}
+
+// Running, and not just a zombie waiting to be reaped
+#[cfg(target_os = "linux")]
+fn process_alive(pid: u32) -> bool {
+    let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+        return false;
+    };
+    // The state follows the command name, which is in parentheses and may contain anything
+    let state = stat.rsplit_once(')').and_then(|(_, rest)| rest.split_whitespace().next());
+    state != Some("Z")
+}
+
+// Guard against the PID having been reused by an unrelated process: its command line has to end with the arguments
+// the helper was started with, and contain the program (after the interpreter, for a script)
+#[cfg(target_os = "linux")]
+fn is_helper_process(pid: u32, launched: &[String]) -> bool {
+    let Ok(cmdline) = fs::read(format!("/proc/{}/cmdline", pid)) else {
+        return false;
+    };
+    // Every argument ends with a NUL
+    let argv: Vec<String> = cmdline
+        .strip_suffix(&[0])
+        .unwrap_or(&cmdline)
+        .split(|byte| *byte == 0)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    match launched.split_first() {
+        Some((program, args)) => argv.ends_with(args) && argv.contains(program),
+        // Recorded by an older version, which only ever launched the helper under its own name
+        None => argv.iter().any(|arg| arg.contains("giggletech_oscq")),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helpers_outlive_the_thread_that_started_them() {
+        let mut helper = thread::spawn(|| {
+            let mut command = Command::new("sleep");
+            command.arg("10");
+            configure_command(&mut command);
+            spawn_helper(move || command.spawn()).unwrap()
+        })
+        .join()
+        .unwrap();
+
+        sleep(Duration::from_millis(300));
+        assert!(helper.try_wait().unwrap().is_none());
+        let _ = helper.kill();
+        let _ = helper.wait();
+    }
+
+    #[test]
+    fn reaps_only_the_helper_it_recorded() {
+        // No helper listens on a privileged port, so nothing else uses this state file
+        let http_port = 1;
+        let start = |seconds: &str| {
+            let mut command = Command::new("sleep");
+            command.arg(seconds);
+            let launched = command_line(&command);
+            (spawn_helper(move || command.spawn()).unwrap(), launched)
+        };
+
+        // A PID that now belongs to another command line is left alone
+        let (mut other, _) = start("30");
+        record_helper(http_port, other.id(), &["sleep".to_string(), "31".to_string()]);
+        reap_stale_helper(http_port);
+        assert!(other.try_wait().unwrap().is_none());
+        let _ = other.kill();
+        let _ = other.wait();
+
+        // One of ours we lost track of is stopped, even though we are still running
+        let (lost, launched) = start("30");
+        let pid = lost.id();
+        record_helper(http_port, pid, &launched);
+        drop(lost);
+        reap_stale_helper(http_port);
+        assert!(!process_alive(pid));
+        assert!(state_file(http_port).is_some_and(|path| !path.exists()));
+    }
+}
@@ -0,0 +1,145 @@
+/*
+    Where `config_oscq.yml` is looked for.
+
+    The Rust module and the helper search the same places, in this order, and use the first file that exists:
+    1. an explicit path (`--config <path>` on the command line, or `Config::load_from`)
+    2. the `GIGGLETECH_CONFIG` environment variable
+    3. the current working directory
+    4. the directory of the running executable
+    5. the per-user config directory: `%APPDATA%\Giggletech` on Windows, `$XDG_CONFIG_HOME/Giggletech` (`~/.config`) on Linux
+    6. the per-user local data directory: `%LOCALAPPDATA%\Giggletech` on Windows, `~/.local/share/Giggletech` on Linux
+
+    A path given explicitly (1 or 2) must exist; it may also name a directory holding `config_oscq.yml`. If none of the
+    other places has the file, the defaults are used. The helper is started with `--config` pointing at the file we chose,
+    so both sides always read the same one.
+*/
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use dirs::config_dir;
+
+use super::{giggletech_dir, OscqError, CONFIG_FILE_NAME};
+
+// Environment variable naming the config file (or the directory holding it)
+pub const CONFIG_PATH_ENV: &str = "GIGGLETECH_CONFIG";
+
+// Which step of the search found the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Explicit,
+    Environment,
+    WorkingDirectory,
+    ExecutableDirectory,
+    ConfigDirectory,
+    DataDirectory,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigSource::Explicit => "given explicitly",
+            ConfigSource::Environment => CONFIG_PATH_ENV,
+            ConfigSource::WorkingDirectory => "working directory",
+            ConfigSource::ExecutableDirectory => "executable directory",
+            ConfigSource::ConfigDirectory => "user config directory",
+            ConfigSource::DataDirectory => "user local data directory",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigLocation {
+    pub path: PathBuf,
+    pub source: ConfigSource,
+}
+
+// Every place the config file is looked for, in search order
+pub fn config_search_path(explicit: Option<&Path>) -> Vec<ConfigLocation> {
+    let mut candidates = Vec::new();
+    let mut push = |path: PathBuf, source| candidates.push(ConfigLocation { path, source });
+    // A path given explicitly may name the directory holding the file
+    let file_or_dir = |path: PathBuf| if path.is_dir() { path.join(CONFIG_FILE_NAME) } else { path };
+
+    if let Some(path) = explicit {
+        push(file_or_dir(path.to_path_buf()), ConfigSource::Explicit);
+    }
+    if let Some(path) = std::env::var_os(CONFIG_PATH_ENV).filter(|path| !path.is_empty()) {
+        push(file_or_dir(PathBuf::from(path)), ConfigSource::Environment);
+    }
+    if let Ok(dir) = std::env::current_dir() {
+        push(dir.join(CONFIG_FILE_NAME), ConfigSource::WorkingDirectory);
+    }
+    if let Some(dir) = std::env::current_exe().ok().as_deref().and_then(Path::parent) {
+        push(dir.join(CONFIG_FILE_NAME), ConfigSource::ExecutableDirectory);
+    }
+    if let Some(dir) = config_dir() {
+        push(dir.join("Giggletech").join(CONFIG_FILE_NAME), ConfigSource::ConfigDirectory);
+    }
+    if let Ok(dir) = giggletech_dir() {
+        push(dir.join(CONFIG_FILE_NAME), ConfigSource::DataDirectory);
+    }
+
+    candidates
+}
+
+// Where a new config file is written when none was given explicitly: next to the helper in AppData\Local\Giggletech
+pub fn default_config_path() -> Result<PathBuf, OscqError> {
+    Ok(giggletech_dir()?.join(CONFIG_FILE_NAME))
+}
+
+// The config file to use: the first one in the search path that exists, or None to use the defaults.
+// A file given explicitly or through `GIGGLETECH_CONFIG` that doesn't exist is an error rather than being skipped.
+pub fn locate_config(explicit: Option<&Path>) -> Result<Option<ConfigLocation>, OscqError> {
+    for candidate in config_search_path(explicit) {
+        if candidate.path.is_file() {
+            return Ok(Some(candidate));
+        }
+        if matches!(candidate.source, ConfigSource::Explicit | ConfigSource::Environment) {
+            return Err(OscqError::ConfigMissing { path: candidate.path });
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::oscq_giggletech::testing::TestEnv;
+
+    #[test]
+    fn searches_in_a_fixed_order() {
+        let mut env = TestEnv::lock();
+        env.set(CONFIG_PATH_ENV, "environment.yml");
+        let search_path = config_search_path(Some(Path::new("explicit.yml")));
+        assert_eq!(search_path[1].path, PathBuf::from("environment.yml"));
+        let sources: Vec<ConfigSource> = search_path.iter().map(|candidate| candidate.source).collect();
+        assert_eq!(
+            sources,
+            [
+                ConfigSource::Explicit,
+                ConfigSource::Environment,
+                ConfigSource::WorkingDirectory,
+                ConfigSource::ExecutableDirectory,
+                ConfigSource::ConfigDirectory,
+                ConfigSource::DataDirectory,
+            ]
+        );
+    }
+
+    #[test]
+    fn an_explicit_path_must_exist() {
+        let dir = std::env::temp_dir().join(format!("giggletech-locate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(CONFIG_FILE_NAME), "httpPort: 7000\n").unwrap();
+
+        // A directory stands for the file in it
+        let found = locate_config(Some(&dir)).unwrap();
+        assert_eq!(found, Some(ConfigLocation { path: dir.join(CONFIG_FILE_NAME), source: ConfigSource::Explicit }));
+
+        // Not skipped for the next place in the search path
+        let missing = dir.join("missing.yml");
+        assert!(matches!(locate_config(Some(&missing)), Err(OscqError::ConfigMissing { path }) if path == missing));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
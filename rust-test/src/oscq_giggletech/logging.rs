@@ -0,0 +1,69 @@
+/*
+    Console output of the module, filtered by the `logLevel` config setting.
+
+    The module has always reported what it is doing on stdout/stderr; these macros keep doing that, but skip messages below
+    the configured level. `info` and `debug` go to stdout, `warn` and `error` to stderr. The level is process-wide and is
+    set from the `Config` whenever one is used to start the helper.
+*/
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(super) fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub(super) fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        if $crate::oscq_giggletech::logging::enabled($crate::oscq_giggletech::LogLevel::Error) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        if $crate::oscq_giggletech::logging::enabled($crate::oscq_giggletech::LogLevel::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::oscq_giggletech::logging::enabled($crate::oscq_giggletech::LogLevel::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if $crate::oscq_giggletech::logging::enabled($crate::oscq_giggletech::LogLevel::Debug) {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(super) use log_error;
+pub(super) use {log_debug, log_info, log_warn};
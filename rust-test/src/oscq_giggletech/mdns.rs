@@ -0,0 +1,703 @@
+/*
+    mDNS / DNS-SD advertisement of the OSCQuery service.
+
+    VRChat finds OSCQuery services only through DNS-SD over multicast DNS. In the helper, `OSCQueryServiceBuilder
+    .WithDefaults()` takes care of that; `Advertiser` is the Rust equivalent, for use with the native server:
+    - `<name>._oscjson._tcp.local` points at the OSCQuery HTTP server (the TCP port)
+    - `<name>._osc._udp.local` points at the OSC receiver (the UDP port)
+    - both name `<name>-giggletech.local` as their target, whose A record is the advertised address (localhost by
+      default, since the native server only listens there). The host name comes from the instance name rather than the
+      machine's, so several advertisers on one machine (or a second one next to Bonjour's) never share an A record.
+
+    Before announcing anything, the instance and host names are claimed as RFC 6762 section 8 requires: three probes,
+    250 ms apart, asking for the names. If another host answers with records of its own for them (say a second PC in the
+    same household also called "Giggletech"), or is probing for them at the same time and wins the tiebreak, the next
+    name is tried: "Giggletech (2)", "Giggletech (3)" and so on, shortening the name where the number would make it too
+    long for a DNS label. A name that is too long to begin with is an error. A conflict that turns up later, in another
+    host's announcement, sends the probes for the names in use again (RFC 6762 section 9); only if those find the names
+    taken is the next one tried. `Advertiser::name()` is the name actually in use.
+
+    The records are announced twice, a second apart, once the name is claimed and again whenever the ports change (with the
+    cache-flush bit, so the old ports are forgotten). Queries for them, including DNS-SD service enumeration, are answered
+    from a background thread for as long as the `Advertiser` lives. Dropping it sends goodbye packets (the records with a
+    TTL of 0), so browsers drop the service right away instead of when their cache expires. The goodbye leaves out the
+    `_services._dns-sd._udp` listing of the service types: every other instance of `_oscjson._tcp` and `_osc._udp` on the
+    network (VRChat's own, for one) is listed under the same records, so they aren't ours to withdraw.
+
+    The mDNS socket is shared with other responders on the machine (Bonjour, the helper, VRChat itself): it is bound to
+    port 5353 with address (and on Unix, port) reuse, and joins the multicast group on the chosen interface.
+*/
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::dns::{escape_label, same_name, Message, Question, Record, RecordData, MAX_LABEL, TYPE_ANY};
+use super::logging::{log_debug, log_info, log_warn};
+use super::OscqError;
+
+pub const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+
+// DNS-SD service types of an OSCQuery service
+pub const OSCJSON_SERVICE: &str = "_oscjson._tcp.local";
+pub const OSC_SERVICE: &str = "_osc._udp.local";
+
+// Lists every service type on the network (RFC 6763 section 9)
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+// TTLs recommended by RFC 6762 section 10: short for records naming hosts, long for the rest
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+
+// Put after the instance name to make the host name
+const HOST_SUFFIX: &str = "-giggletech";
+
+// TTL cap for answers to legacy unicast queries (RFC 6762 section 6.7)
+const LEGACY_TTL: u32 = 10;
+
+// Announcements are sent this many times, this far apart (RFC 6762 section 8.3)
+const ANNOUNCEMENTS: u32 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Probes sent to claim a name, and how far apart (RFC 6762 section 8.1)
+const PROBES: u32 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+// How often the responder thread checks for announcements due and for being stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Advertiser {
+    state: Arc<Mutex<Advertised>>,
+    socket: UdpSocket,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// What is advertised, shared with the responder thread
+struct Advertised {
+    // Name asked for, and the number put after it to make it unique (1: none)
+    base_name: String,
+    number: u32,
+    // Instance name in use, as a label (escaped), and the host name that goes with it
+    instance: String,
+    host: String,
+    address: Ipv4Addr,
+    tcp_port: u16,
+    udp_port: u16,
+    // TTLs of the records naming the host (SRV and A) and of the rest
+    host_ttl: u32,
+    service_ttl: u32,
+    // When the next announcements are due, earliest first
+    announce_at: Vec<Instant>,
+}
+
+impl Advertiser {
+    // Advertise `name` on the default multicast interface, pointing at localhost
+    pub fn start(name: &str, tcp_port: u16, udp_port: u16) -> Result<Advertiser, OscqError> {
+        Advertiser::start_on(Ipv4Addr::UNSPECIFIED, name, tcp_port, udp_port)
+    }
+
+    // Advertise `name` on the interface with address `interface`, pointing at that address
+    // (or at localhost for `Ipv4Addr::UNSPECIFIED`, which uses the default interface).
+    // Returns once a name has been claimed, which takes a little under a second.
+    pub fn start_on(interface: Ipv4Addr, name: &str, tcp_port: u16, udp_port: u16) -> Result<Advertiser, OscqError> {
+        Advertiser::start_with_ttls(interface, name, tcp_port, udp_port, (HOST_TTL, SERVICE_TTL))
+    }
+
+    // Same as `start_on`, with other (host, service) TTLs than the recommended ones
+    pub(super) fn start_with_ttls(
+        interface: Ipv4Addr,
+        name: &str,
+        tcp_port: u16,
+        udp_port: u16,
+        (host_ttl, service_ttl): (u32, u32),
+    ) -> Result<Advertiser, OscqError> {
+        let address = if interface.is_unspecified() { Ipv4Addr::LOCALHOST } else { interface };
+        let advertised = Advertised {
+            base_name: name.to_string(),
+            number: 1,
+            instance: escape_label(name),
+            host: host_name(name),
+            address,
+            tcp_port,
+            udp_port,
+            host_ttl,
+            service_ttl,
+            announce_at: Vec::new(),
+        };
+        // Fails on a name that doesn't fit in a DNS label
+        advertised.probe_query().encode()?;
+
+        let socket = mdns_socket(interface).map_err(|source| OscqError::Mdns { source })?;
+        let responder_socket = socket.try_clone().map_err(|source| OscqError::Mdns { source })?;
+        let state = Arc::new(Mutex::new(advertised));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        probe(&socket, &state, &stop);
+        state.lock().unwrap().announce_at = announcement_times();
+        let name = state.lock().unwrap().name();
+
+        let thread = {
+            let state = Arc::clone(&state);
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("oscq-mdns".into())
+                .spawn(move || respond(responder_socket, state, stop))
+                .map_err(|source| OscqError::ThreadSpawn { name: "mDNS responder", source })?
+        };
+
+        log_info!("Advertising \"{}\" over mDNS (OSCQuery on TCP {}, OSC on UDP {})", name, tcp_port, udp_port);
+        Ok(Advertiser { state, socket, stop, thread: Some(thread) })
+    }
+
+    // Instance name the service is advertised under; differs from the one asked for after a conflict
+    pub fn name(&self) -> String {
+        self.state.lock().unwrap().name()
+    }
+
+    // The advertised (TCP, UDP) ports
+    pub fn ports(&self) -> (u16, u16) {
+        let state = self.state.lock().unwrap();
+        (state.tcp_port, state.udp_port)
+    }
+
+    // Advertise new ports, announcing them right away
+    pub fn set_ports(&self, tcp_port: u16, udp_port: u16) {
+        let mut state = self.state.lock().unwrap();
+        if (state.tcp_port, state.udp_port) == (tcp_port, udp_port) {
+            return;
+        }
+        log_info!("Re-announcing over mDNS: OSCQuery on TCP {}, OSC on UDP {}", tcp_port, udp_port);
+        state.tcp_port = tcp_port;
+        state.udp_port = udp_port;
+        state.announce_at = announcement_times();
+    }
+}
+
+impl Drop for Advertiser {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        // Goodbye: the records of this instance with a TTL of 0
+        let mut goodbye = self.state.lock().unwrap().announcement();
+        goodbye.answers.retain(|record| !same_name(&record.name, SERVICE_TYPES));
+        for record in &mut goodbye.answers {
+            record.ttl = 0;
+        }
+        if let Err(e) = send_message(&self.socket, &goodbye, (MDNS_ADDR, MDNS_PORT)) {
+            log_warn!("Failed to withdraw the mDNS records: {}", e);
+        }
+    }
+}
+
+impl Advertised {
+    fn name(&self) -> String {
+        let number = match self.number {
+            1 => return self.base_name.clone(),
+            number => format!(" ({})", number),
+        };
+        // Make room for the number in the label
+        let mut end = self.base_name.len().min(MAX_LABEL - number.len());
+        while !self.base_name.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}{}", self.base_name[..end].trim_end(), number)
+    }
+
+    // Move on to the next name after a conflict
+    fn rename(&mut self) {
+        let taken = self.name();
+        self.number += 1;
+        self.instance = escape_label(&self.name());
+        self.host = host_name(&self.name());
+        log_warn!("The mDNS name {:?} is already in use, trying {:?}", taken, self.name());
+    }
+
+    // The names only we may have records for: our instance names and host name
+    fn unique_names(&self) -> [String; 3] {
+        [self.service_name(OSCJSON_SERVICE), self.service_name(OSC_SERVICE), self.host.clone()]
+    }
+
+    fn is_ours(&self, name: &str) -> bool {
+        self.unique_names().iter().any(|ours| same_name(name, ours))
+    }
+
+    // Our records for our unique names (SRV, TXT and A), the ones that make up a claim on them
+    fn claim(&self) -> Vec<Record> {
+        self.records().into_iter().filter(|record| self.is_ours(&record.name)).collect()
+    }
+
+    // Query claiming our unique names: a question for each, with the records we intend to use in the authority
+    // section so other hosts probing at the same time can break the tie
+    fn probe_query(&self) -> Message {
+        let mut query = Message::default();
+        for name in self.unique_names() {
+            query.questions.push(Question { name, rtype: TYPE_ANY, unicast: true });
+        }
+        query.authorities = self.claim();
+        query
+    }
+
+    // Whether `message` shows someone else using one of our instance names: an answer with other records for it, or
+    // (with `probing`) a simultaneous probe for it whose records win the tiebreak of RFC 6762 section 8.2. Our own
+    // messages, which the loopback brings back to us, never conflict.
+    fn conflicts(&self, message: &Message, probing: bool) -> bool {
+        let ours = self.claim();
+        let is_foreign = |record: &&Record| {
+            self.is_ours(&record.name) && record.ttl > 0 && !ours.iter().any(|own| own.same_as(record))
+        };
+
+        if message.is_response() {
+            return message.records().any(|record| is_foreign(&record));
+        }
+        if !probing {
+            return false;
+        }
+        // For each name, the records compare as sorted lists of (type, data); the later one wins
+        let sorted = |records: &[Record], name: &str| {
+            let mut keys: Vec<(u16, Vec<u8>)> = records
+                .iter()
+                .filter(|record| same_name(&record.name, name))
+                // Received records were decoded, and ours checked when we started, so they all encode
+                .map(|record| (record.rtype(), record.data_bytes().unwrap_or_default()))
+                .collect();
+            keys.sort();
+            keys
+        };
+        message
+            .authorities
+            .iter()
+            .filter(is_foreign)
+            .any(|record| sorted(&message.authorities, &record.name) > sorted(&ours, &record.name))
+    }
+
+    fn service_name(&self, service: &str) -> String {
+        format!("{}.{}", self.instance, service)
+    }
+
+    // Every record we are authoritative for
+    fn records(&self) -> Vec<Record> {
+        let mut records = Vec::new();
+        for (service, port) in [(OSCJSON_SERVICE, self.tcp_port), (OSC_SERVICE, self.udp_port)] {
+            let instance = self.service_name(service);
+            records.push(Record::new(SERVICE_TYPES, false, self.service_ttl, RecordData::Ptr(service.to_string())));
+            records.push(Record::new(service, false, self.service_ttl, RecordData::Ptr(instance.clone())));
+            records.push(Record::new(
+                &instance,
+                true,
+                self.host_ttl,
+                RecordData::Srv { priority: 0, weight: 0, port, target: self.host.clone() },
+            ));
+            let txt = RecordData::Txt(vec!["txtvers=1".to_string()]);
+            records.push(Record::new(&instance, true, self.service_ttl, txt));
+        }
+        records.push(Record::new(&self.host, true, self.host_ttl, RecordData::A(self.address)));
+        records
+    }
+
+    // Unsolicited response carrying all our records
+    fn announcement(&self) -> Message {
+        let mut message = Message::response();
+        message.answers = self.records();
+        message
+    }
+
+    // Response to `query`, or None if nothing in it is about us
+    fn answer(&self, query: &Message) -> Option<Message> {
+        let records = self.records();
+        let mut response = Message::response();
+
+        for question in &query.questions {
+            for record in records.iter().filter(|record| record.answers(question)) {
+                // Known-answer suppression (RFC 6762 section 7.1): skip what the asker already has
+                let known = query.answers.iter().any(|known| known.same_as(record) && known.ttl >= record.ttl / 2);
+                if !known && !response.answers.contains(record) {
+                    response.answers.push(record.clone());
+                }
+            }
+        }
+        if response.answers.is_empty() {
+            return None;
+        }
+
+        // Everything needed to resolve the answers (RFC 6763 section 12), minus the service type listing
+        response.additionals = records
+            .into_iter()
+            .filter(|record| !response.answers.contains(record) && !same_name(&record.name, SERVICE_TYPES))
+            .collect();
+        Some(response)
+    }
+}
+
+// Claim an instance name: probe for it, and move on to the next one whenever someone else turns out to have it
+fn probe(socket: &UdpSocket, state: &Mutex<Advertised>, stop: &AtomicBool) {
+    let mut buffer = [0u8; 9000];
+    let mut sent = 0;
+    // After the last probe, other hosts still get one interval to answer
+    let mut next_probe = Instant::now();
+
+    while sent < PROBES || Instant::now() < next_probe {
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+        if sent < PROBES && Instant::now() >= next_probe {
+            let query = state.lock().unwrap().probe_query();
+            if let Err(e) = send_message(socket, &query, (MDNS_ADDR, MDNS_PORT)) {
+                log_warn!("Failed to send an mDNS probe: {}", e);
+            }
+            sent += 1;
+            next_probe = Instant::now() + PROBE_INTERVAL;
+        }
+
+        // Times out after `POLL_INTERVAL`
+        let Ok((length, _)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        let Some(message) = Message::decode(&buffer[..length]) else {
+            continue;
+        };
+        let mut state = state.lock().unwrap();
+        if state.conflicts(&message, true) {
+            state.rename();
+            sent = 0;
+            next_probe = Instant::now();
+        }
+    }
+}
+
+// Answer queries and send due announcements until `stop` is set
+fn respond(socket: UdpSocket, state: Arc<Mutex<Advertised>>, stop: Arc<AtomicBool>) {
+    let mut buffer = [0u8; 9000];
+    while !stop.load(Ordering::SeqCst) {
+        let announcement = {
+            let mut state = state.lock().unwrap();
+            match state.announce_at.first() {
+                Some(due) if *due <= Instant::now() => {
+                    state.announce_at.remove(0);
+                    Some(state.announcement())
+                }
+                _ => None,
+            }
+        };
+        if let Some(announcement) = announcement {
+            if let Err(e) = send_message(&socket, &announcement, (MDNS_ADDR, MDNS_PORT)) {
+                log_warn!("Failed to send the mDNS announcement: {}", e);
+            }
+        }
+
+        // Times out after `POLL_INTERVAL`
+        let Ok((length, source)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        let Some(message) = Message::decode(&buffer[..length]) else {
+            continue;
+        };
+        if message.is_response() {
+            // Someone else announced one of our names: probe for them again, which moves on to the next name if they
+            // turn out to be taken. Announcements sent while their host lost the name (say it was asleep) don't
+            // make us give it up.
+            if state.lock().unwrap().conflicts(&message, false) {
+                let name = state.lock().unwrap().name();
+                log_info!("Another host announced records for {:?}, probing for the name again", name);
+                probe(&socket, &state, &stop);
+                state.lock().unwrap().announce_at = announcement_times();
+            }
+            continue;
+        }
+        let query = message;
+        let Some(mut response) = state.lock().unwrap().answer(&query) else {
+            continue;
+        };
+
+        let destination = if source.port() != MDNS_PORT {
+            // A legacy unicast query from a plain DNS resolver: it only accepts an ordinary DNS response
+            response.id = query.id;
+            response.questions = query.questions.clone();
+            for record in response.answers.iter_mut().chain(&mut response.additionals) {
+                record.ttl = record.ttl.min(LEGACY_TTL);
+                record.flush = false;
+            }
+            source
+        } else {
+            // Even when unicast is asked for: several responders share port 5353 and only one of them would get it
+            SocketAddr::from((MDNS_ADDR, MDNS_PORT))
+        };
+        log_debug!("Answering an mDNS query from {}", source);
+        if let Err(e) = send_message(&socket, &response, destination) {
+            log_warn!("Failed to answer an mDNS query from {}: {}", source, e);
+        }
+    }
+}
+
+// A socket on the mDNS port, shared with the other responders on this machine, that receives the mDNS multicast group
+// on `interface` and sends to it there
+pub(super) fn mdns_socket(interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(255)?;
+
+    let socket = UdpSocket::from(socket);
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(socket)
+}
+
+// Send `message` to `destination`; one that can't be encoded fails like a send that didn't go through
+pub(super) fn send_message(socket: &UdpSocket, message: &Message, destination: impl ToSocketAddrs) -> io::Result<()> {
+    let bytes = message.encode().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    socket.send_to(&bytes, destination).map(|_| ())
+}
+
+fn announcement_times() -> Vec<Instant> {
+    let now = Instant::now();
+    (0..ANNOUNCEMENTS).map(|n| now + ANNOUNCE_INTERVAL * n).collect()
+}
+
+// The host name that goes with the instance name `name`: "Giggletech (2)" -> "Giggletech-2-giggletech.local"
+fn host_name(name: &str) -> String {
+    let mut label = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            label.push(c);
+        } else if !label.is_empty() && !label.ends_with('-') {
+            label.push('-');
+        }
+    }
+    label.truncate(MAX_LABEL - HOST_SUFFIX.len());
+    match label.trim_end_matches('-') {
+        "" => format!("{}.local", &HOST_SUFFIX[1..]),
+        label => format!("{}{}.local", label, HOST_SUFFIX),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscq_giggletech::dns::TYPE_PTR;
+
+    // Wait for a record on the loopback mDNS group matching `matches`
+    fn wait_for(listener: &UdpSocket, matches: impl Fn(&Record) -> bool) -> Option<Record> {
+        let message = wait_for_message(listener, |message| message.records().any(&matches))?;
+        let found = message.records().find(|record| matches(record)).cloned();
+        found
+    }
+
+    fn wait_for_message(listener: &UdpSocket, matches: impl Fn(&Message) -> bool) -> Option<Message> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buffer = [0u8; 9000];
+        while Instant::now() < deadline {
+            let Ok((length, _)) = listener.recv_from(&mut buffer) else {
+                continue;
+            };
+            match Message::decode(&buffer[..length]) {
+                Some(message) if matches(&message) => return Some(message),
+                _ => continue,
+            }
+        }
+        None
+    }
+
+    fn srv_port(record: &Record) -> Option<u16> {
+        match record.data {
+            RecordData::Srv { port, .. } => Some(port),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn announces_both_services() {
+        let listener = mdns_socket(Ipv4Addr::LOCALHOST).unwrap();
+        let _advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Announce Test", 9101, 9102).unwrap();
+
+        let oscjson = wait_for(&listener, |record| {
+            record.name == "Announce Test._oscjson._tcp.local" && srv_port(record).is_some()
+        });
+        let osc = wait_for(&listener, |record| record.name == "Announce Test._osc._udp.local" && srv_port(record).is_some());
+        assert_eq!(oscjson.and_then(|record| srv_port(&record)), Some(9101));
+        assert_eq!(osc.and_then(|record| srv_port(&record)), Some(9102));
+    }
+
+    #[test]
+    fn reannounces_when_ports_change() {
+        let listener = mdns_socket(Ipv4Addr::LOCALHOST).unwrap();
+        let advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Reannounce Test", 9201, 9202).unwrap();
+        advertiser.set_ports(9201, 9203);
+
+        let record = wait_for(&listener, |record| {
+            record.name == "Reannounce Test._osc._udp.local" && srv_port(record) == Some(9203)
+        });
+        assert!(record.is_some_and(|record| record.flush && record.ttl > 0));
+        assert_eq!(advertiser.ports(), (9201, 9203));
+    }
+
+    #[test]
+    fn says_goodbye_when_dropped() {
+        let listener = mdns_socket(Ipv4Addr::LOCALHOST).unwrap();
+        let advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Goodbye Test", 9301, 9302).unwrap();
+        drop(advertiser);
+
+        let goodbye = wait_for_message(&listener, |message| {
+            message.records().any(|record| record.name == "Goodbye Test._oscjson._tcp.local" && record.ttl == 0)
+        })
+        .unwrap();
+        // The service types are still offered by everyone else
+        assert!(goodbye.records().all(|record| !same_name(&record.name, SERVICE_TYPES)));
+        assert!(goodbye.records().any(|record| same_name(&record.name, OSC_SERVICE) && record.ttl == 0));
+    }
+
+    #[test]
+    fn answers_queries() {
+        let _advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Query Test", 9401, 9402).unwrap();
+
+        // A one-shot query from an ephemeral port gets a unicast answer
+        let asker = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        asker.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+        let mut query = Message { id: 42, ..Message::default() };
+        query.questions.push(Question::new(OSCJSON_SERVICE, TYPE_PTR));
+        send_message(&asker, &query, (MDNS_ADDR, MDNS_PORT)).unwrap();
+
+        let pointer = RecordData::Ptr("Query Test._oscjson._tcp.local".to_string());
+        let answer = wait_for(&asker, |record| record.data == pointer);
+        assert!(answer.is_some_and(|record| record.ttl <= LEGACY_TTL));
+    }
+
+    #[test]
+    fn renames_itself_when_the_name_is_taken() {
+        let first = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Conflict Test", 9801, 9802).unwrap();
+        let second = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Conflict Test", 9803, 9804).unwrap();
+        let third = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Conflict Test", 9805, 9806).unwrap();
+
+        assert_eq!(first.name(), "Conflict Test");
+        assert_eq!(second.name(), "Conflict Test (2)");
+        assert_eq!(third.name(), "Conflict Test (3)");
+    }
+
+    #[test]
+    fn probes_again_before_giving_up_a_name() {
+        let listener = mdns_socket(Ipv4Addr::LOCALHOST).unwrap();
+        let advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "Reprobe Test", 9501, 9502).unwrap();
+
+        // A stale announcement from a host that doesn't answer for the name any more
+        let stale = advertised("Reprobe Test", 9501, 7777).announcement();
+        send_message(&listener, &stale, (MDNS_ADDR, MDNS_PORT)).unwrap();
+
+        let probe = wait_for_message(&listener, |message| {
+            !message.is_response() && message.authorities.iter().any(|record| srv_port(record) == Some(9502))
+        });
+        let host = advertiser.state.lock().unwrap().host.clone();
+        assert!(probe.is_some_and(|probe| probe.questions.iter().any(|question| question.name == host)));
+        let announced = wait_for_message(&listener, |message| {
+            message.is_response() && message.answers.iter().any(|record| srv_port(record) == Some(9502))
+        });
+        assert!(announced.is_some());
+        assert_eq!(advertiser.name(), "Reprobe Test");
+    }
+
+    #[test]
+    fn names_must_fit_in_a_dns_label() {
+        let long = "x".repeat(MAX_LABEL + 1);
+        let result = Advertiser::start_on(Ipv4Addr::LOCALHOST, &long, 9901, 9902);
+        assert!(matches!(result, Err(OscqError::InvalidDnsName { label, .. }) if label == long));
+
+        // The number after a taken name shortens it instead
+        let mut state = advertised(&"x".repeat(MAX_LABEL), 9901, 9902);
+        state.rename();
+        assert_eq!(state.name(), format!("{} (2)", "x".repeat(MAX_LABEL - 4)));
+        assert!(state.probe_query().encode().is_ok());
+    }
+
+    #[test]
+    fn host_names_follow_the_instance_name() {
+        assert_eq!(host_name("Giggletech"), "Giggletech-giggletech.local");
+        assert_eq!(host_name("Conflict Test (2)"), "Conflict-Test-2-giggletech.local");
+        assert_eq!(host_name("ギグル"), "giggletech.local");
+        let long = host_name(&"x".repeat(100));
+        assert_eq!(long.split('.').next().unwrap().len(), MAX_LABEL);
+    }
+
+    #[test]
+    fn simultaneous_probes_are_won_by_the_later_records() {
+        let state = advertised("Tie", 5000, 5001);
+        let mut probe = advertised("Tie", 5000, 5001).probe_query();
+        // Our own probe, looped back
+        assert!(!state.conflicts(&probe, true));
+
+        probe.authorities = advertised("Tie", 6000, 5001).claim();
+        assert!(state.conflicts(&probe, true));
+        // Only while probing; afterwards we defend the name by answering
+        assert!(!state.conflicts(&probe, false));
+
+        probe.authorities = advertised("Tie", 4000, 5001).claim();
+        assert!(!state.conflicts(&probe, true));
+    }
+
+    #[test]
+    fn other_answers_for_our_name_conflict() {
+        let state = advertised("Taken", 5000, 5001);
+        let mut response = advertised("Taken", 5000, 5001).announcement();
+        assert!(!state.conflicts(&response, false));
+
+        response.answers = advertised("Taken", 5000, 7001).claim();
+        assert!(state.conflicts(&response, false));
+
+        // A goodbye isn't a claim
+        for record in &mut response.answers {
+            record.ttl = 0;
+        }
+        assert!(!state.conflicts(&response, false));
+    }
+
+    fn advertised(name: &str, tcp_port: u16, udp_port: u16) -> Advertised {
+        Advertised {
+            base_name: name.to_string(),
+            number: 1,
+            instance: escape_label(name),
+            host: host_name(name),
+            address: Ipv4Addr::LOCALHOST,
+            tcp_port,
+            udp_port,
+            host_ttl: HOST_TTL,
+            service_ttl: SERVICE_TTL,
+            announce_at: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn suppresses_known_answers() {
+        let state = Advertised {
+            base_name: "Known".to_string(),
+            number: 1,
+            instance: "Known".to_string(),
+            host: "host.local".to_string(),
+            address: Ipv4Addr::LOCALHOST,
+            tcp_port: 1,
+            udp_port: 2,
+            host_ttl: HOST_TTL,
+            service_ttl: SERVICE_TTL,
+            announce_at: Vec::new(),
+        };
+        let mut query = Message::default();
+        query.questions.push(Question::new(OSC_SERVICE, TYPE_PTR));
+        assert!(state.answer(&query).is_some());
+
+        query.answers.push(Record::new(OSC_SERVICE, false, SERVICE_TTL, RecordData::Ptr("Known._osc._udp.local".into())));
+        assert!(state.answer(&query).is_none());
+
+        // The instance's own records are still answered
+        query.questions.push(Question::new("Known._osc._udp.local", TYPE_ANY));
+        assert_eq!(state.answer(&query).unwrap().answers.len(), 2);
+    }
+}
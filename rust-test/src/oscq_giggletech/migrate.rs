@@ -0,0 +1,253 @@
+/*
+    Writing and upgrading `config_oscq.yml`.
+
+    `init_config` writes a config with every setting at its default, each with a comment explaining it, so new installs
+    don't start out with "YAML configuration file not found" in the helper's log.
+
+    `migrate_config` upgrades a file written for an older schema. The `version` key says which schema a file follows
+    (files without one are version 1, from before the Rust side had a schema), and `STEPS` holds one function per version
+    that rewrites the keys of that version into the next one. After the last step the result is validated and written back
+    in the same commented layout as `init_config`, keeping every value that was set. The original is kept as `.bak`.
+
+    Versions:
+    1. Only `httpPort` and `serviceName`, possibly spelled `http_port` / `service_name` (the helper's YAML library uses an
+       underscored naming convention).
+    2. The full schema in `config.rs`.
+*/
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_yaml::{Mapping, Value};
+
+use super::config::SETTINGS;
+use super::validate::{find_key, parse_config, suggestion};
+use super::{Config, OscqError};
+
+// Schema version written by this program
+pub const CONFIG_VERSION: u32 = 2;
+
+// `STEPS[n]` upgrades a version `n + 1` file to version `n + 2`
+const STEPS: &[fn(&mut Mapping)] = &[version_1_to_2];
+
+// Write a commented config with the defaults to `path`; an existing file is only replaced with `force`
+pub fn init_config(path: &Path, force: bool) -> Result<(), OscqError> {
+    if path.exists() && !force {
+        return Err(OscqError::ConfigExists { path: path.to_path_buf() });
+    }
+    write_config(path, &render_config(&Config::default()))
+}
+
+// Upgrade the config at `path` to `CONFIG_VERSION`.
+// Returns the version it was at, or None if it was already current and has been left alone.
+pub fn migrate_config(path: &Path) -> Result<Option<u32>, OscqError> {
+    let text = fs::read_to_string(path).map_err(|source| match source.kind() {
+        io::ErrorKind::NotFound => OscqError::ConfigMissing { path: path.to_path_buf() },
+        _ => OscqError::ConfigRead { path: path.to_path_buf(), source },
+    })?;
+    // Let validation explain what is wrong with the file
+    let rejected = || parse_config(&text).err().unwrap_or_default().into_error(Some(path.to_path_buf()));
+
+    // Syntax errors are reported the same way as when loading
+    let mut fields = match serde_yaml::from_str::<Value>(&text) {
+        Ok(Value::Mapping(fields)) => fields,
+        Ok(Value::Null) => Mapping::new(),
+        _ => return Err(rejected()),
+    };
+
+    let version = match fields.get("version") {
+        None => 1,
+        Some(value) => match value.as_u64().and_then(|version| u32::try_from(version).ok()) {
+            Some(version) if (1..=CONFIG_VERSION).contains(&version) => version,
+            _ => return Err(rejected()),
+        },
+    };
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    let written: Vec<String> = fields.keys().filter_map(Value::as_str).map(str::to_string).collect();
+    for step in &STEPS[version as usize - 1..] {
+        step(&mut fields);
+    }
+    fields.insert(Value::from("version"), Value::from(CONFIG_VERSION));
+
+    let upgraded = serde_yaml::to_string(&fields).unwrap_or_default();
+    let config = parse_config(&upgraded).map_err(|mut rejection| {
+        // Point at the lines of the file as the user wrote it, where a key may still have its old spelling
+        let find = |key: &str| {
+            find_key(&text, key).or_else(|| {
+                let old = written.iter().find(|old| suggestion(old) == Some(key))?;
+                find_key(&text, old)
+            })
+        };
+        for problem in &mut rejection.problems {
+            let position = problem.key.as_deref().and_then(find);
+            problem.line = position.map(|(line, _)| line);
+            problem.column = position.map(|(_, column)| column);
+        }
+        rejection.into_error(Some(path.to_path_buf()))
+    })?;
+
+    let backup = backup_path(path);
+    fs::copy(path, &backup).map_err(|source| OscqError::ConfigWrite { path: backup, source })?;
+    write_config(path, &render_config(&config))?;
+    Ok(Some(version))
+}
+
+// The config file text for `config`: every setting, with a comment explaining it
+pub fn render_config(config: &Config) -> String {
+    let Ok(Value::Mapping(fields)) = serde_yaml::to_value(config) else {
+        unreachable!("Config always serializes to a mapping");
+    };
+
+    let mut text = String::from(
+        "# Giggletech OSCQuery configuration, read by rust-test and giggletech_oscq.\n\
+         # Every key is optional; a removed key gets its default. Durations are in seconds.\n\
+         # Keys can be overridden with GIGGLETECH_* environment variables and --kebab-case command line flags.\n\n",
+    );
+    text.push_str(&format!("# {}\nversion: {}\n", describe("version"), config.version));
+    for key in SETTINGS {
+        let Some(value) = fields.get(*key) else {
+            continue;
+        };
+        let entry = Mapping::from_iter([(Value::from(*key), value.clone())]);
+        text.push_str(&format!("\n# {}\n{}", describe(key), serde_yaml::to_string(&entry).unwrap_or_default()));
+    }
+    text
+}
+
+// Version 1 files only knew the HTTP port and service name, and may have used the helper's underscored spelling
+fn version_1_to_2(fields: &mut Mapping) {
+    let renames: Vec<(String, &str)> = fields
+        .keys()
+        .filter_map(Value::as_str)
+        .filter(|key| !SETTINGS.contains(key))
+        .filter_map(|key| suggestion(key).map(|setting| (key.to_string(), setting)))
+        .collect();
+
+    for (old, new) in renames {
+        if let Some(value) = fields.remove(old.as_str()) {
+            if !fields.contains_key(new) {
+                fields.insert(Value::from(new), value);
+            }
+        }
+    }
+}
+
+// Comment written above each key
+fn describe(key: &str) -> &'static str {
+    match key {
+        "version" => "Schema version of this file, used by `rust-test config migrate`",
+        "httpPort" => "Port of the helper's HTTP listener (1024 - 65535)",
+        "serviceName" => "Name the OSCQuery service is advertised under",
+        "httpPortFallback" => "Start the helper on a free port if another application owns httpPort",
+        "launchMode" => "How to start the helper: auto | native | dotnet | command",
+        "helperPath" => "Helper executable, dll or program; null for the file in AppData\\Local\\Giggletech",
+        "helperArgs" => "Extra arguments for the helper",
+        "pollInterval" => "How often the supervisor checks the helper",
+        "hangTimeout" => "How long the helper may go without answering before it is restarted",
+        "shutdownGrace" => "How long the helper gets to exit after /stop before it is killed",
+        "startupTimeout" => "Give up bringing the helper up after this long; null to never give up",
+        "startupGrace" => "How long a freshly started helper may take to answer",
+        "initialBackoff" => "First delay between polls while starting up",
+        "maxBackoff" => "Longest delay between polls while starting up",
+        "backoffMultiplier" => "Growth of the delay after every poll (1.0 - 10.0)",
+        "backoffJitter" => "Random spread of the delay (fraction, 0.0 - 1.0)",
+        "maxAttempts" => "Give up bringing the helper up after this many polls; null for no limit",
+        "logLevel" => "off | error | warn | info | debug",
+        _ => "",
+    }
+}
+
+fn write_config(path: &Path, text: &str) -> Result<(), OscqError> {
+    let write = || {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, text)
+    };
+    write().map_err(|source| OscqError::ConfigWrite { path: path.to_path_buf(), source })
+}
+
+// "config_oscq.yml" -> "config_oscq.yml.bak"
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory of its own for each test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("giggletech-migrate-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn renames_near_miss_keys_from_version_1() {
+        let mut fields: Mapping = serde_yaml::from_str("http_port: 7000\nService-Name: Home\nserviceName: Kept\n").unwrap();
+        version_1_to_2(&mut fields);
+
+        assert_eq!(fields.get("httpPort"), Some(&Value::from(7000)));
+        // The correct spelling wins over a near miss
+        assert_eq!(fields.get("serviceName"), Some(&Value::from("Kept")));
+        assert_eq!(fields.len(), 2);
+    }
+
+    #[test]
+    fn migrates_a_version_1_file_and_keeps_the_original() {
+        let dir = scratch_dir("round-trip");
+        let path = dir.join("config_oscq.yml");
+        let original = "http_port: 7000\nservice_name: Home PC\n";
+        fs::write(&path, original).unwrap();
+
+        assert_eq!(migrate_config(&path).unwrap(), Some(1));
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), original);
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!((config.version, config.http_port, config.service_name.as_str()), (2, 7000, "Home PC"));
+        assert!(fs::read_to_string(&path).unwrap().contains("# Port of the helper's HTTP listener"));
+
+        // Already current: left alone
+        let migrated = fs::read_to_string(&path).unwrap();
+        assert_eq!(migrate_config(&path).unwrap(), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), migrated);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn leaves_files_it_cant_migrate_untouched() {
+        let dir = scratch_dir("invalid");
+        let path = dir.join("config_oscq.yml");
+        fs::write(&path, "serviceName: Home\nhttp_port: 80\n").unwrap();
+
+        let Err(OscqError::ConfigInvalid { problems, .. }) = migrate_config(&path) else {
+            panic!("a privileged port must be rejected");
+        };
+        // Reported at the line of the key as it was spelled in the file
+        assert_eq!(problems[0].line, Some(2));
+        assert!(!backup_path(&path).exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn init_writes_the_defaults_once() {
+        let dir = scratch_dir("init");
+        let path = dir.join("nested").join("config_oscq.yml");
+
+        init_config(&path, false).unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(config.changes(&Config::default()), Vec::new());
+
+        fs::write(&path, "httpPort: 7000\n").unwrap();
+        assert!(matches!(init_config(&path, false), Err(OscqError::ConfigExists { .. })));
+        init_config(&path, true).unwrap();
+        assert_eq!(Config::from_file(&path).unwrap().http_port, Config::default().http_port);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
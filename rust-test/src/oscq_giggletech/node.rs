@@ -0,0 +1,555 @@
+/*
+    OSCQuery address-space tree.
+
+    `OscQueryNode` models one node of the tree an OSCQuery server publishes, with the attributes from the spec:
+    FULL_PATH, CONTENTS, TYPE, ACCESS, RANGE, VALUE and DESCRIPTION. The root is made with `OscQueryNode::root()` and
+    endpoints are added below it by path:
+
+        let mut tree = OscQueryNode::root();
+        tree.insert("/avatar/change", OscQueryNode::endpoint("s", Access::Write))?;
+        tree.insert("/avatar/parameters/Giggletech_Intensity", OscQueryNode::endpoint("f", Access::Write))?;
+
+    Containers along the way are created as needed and removed again by `remove()` once they are empty, so the tree only
+    ever holds the endpoints that were inserted. FULL_PATH is kept in step with where a node sits in the tree.
+
+    `to_json()` gives the node as the spec's JSON (attributes that aren't set are left out), and `attribute()` a single
+    attribute for queries like `/avatar/change?TYPE`. `OscQueryServer` (see `server.rs`) serves both. `from_json()` reads
+    a tree another server published, such as VRChat's list of avatar parameters.
+
+    `select()` picks the endpoints matching a set of address patterns out of a tree, e.g. "/avatar/parameters/Giggletech_*"
+    out of the current avatar's parameters. As in OSC address patterns, `*` matches any run of characters and `?` any
+    single character within one part of the address.
+*/
+
+use std::collections::BTreeMap;
+use serde_json::{json, Map, Value};
+
+use super::OscqError;
+
+// The attributes a node can be queried for, as in `/some/path?VALUE`
+pub(super) const ATTRIBUTES: [&str; 7] = ["FULL_PATH", "CONTENTS", "TYPE", "ACCESS", "RANGE", "VALUE", "DESCRIPTION"];
+
+// Characters OSC doesn't allow in an address
+const RESERVED: &[char] = &[' ', '#', '*', ',', '/', '?', '[', ']', '{', '}'];
+
+// Who may use an endpoint: reading its value through OSCQuery, sending to it over OSC, or both
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Access {
+    #[default]
+    None,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    // The number ACCESS is encoded as
+    pub fn code(self) -> u8 {
+        match self {
+            Access::None => 0,
+            Access::Read => 1,
+            Access::Write => 2,
+            Access::ReadWrite => 3,
+        }
+    }
+}
+
+// The values one argument of an endpoint can take: between `min` and `max`, and/or one of `vals`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValueRange {
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    pub vals: Vec<Value>,
+}
+
+impl ValueRange {
+    pub fn between(min: impl Into<Value>, max: impl Into<Value>) -> ValueRange {
+        ValueRange { min: Some(min.into()), max: Some(max.into()), vals: Vec::new() }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut range = Map::new();
+        if let Some(min) = &self.min {
+            range.insert("MIN".into(), min.clone());
+        }
+        if let Some(max) = &self.max {
+            range.insert("MAX".into(), max.clone());
+        }
+        if !self.vals.is_empty() {
+            range.insert("VALS".into(), Value::Array(self.vals.clone()));
+        }
+        Value::Object(range)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OscQueryNode {
+    full_path: String,
+    // OSC type tags of the arguments, e.g. "f" or "ii"
+    pub osc_type: Option<String>,
+    pub access: Access,
+    // One range per argument
+    pub range: Vec<ValueRange>,
+    // One current value per argument
+    pub value: Vec<Value>,
+    pub description: Option<String>,
+    contents: BTreeMap<String, OscQueryNode>,
+}
+
+impl OscQueryNode {
+    // An empty tree
+    pub fn root() -> OscQueryNode {
+        OscQueryNode { full_path: "/".into(), ..OscQueryNode::default() }
+    }
+
+    // An endpoint taking arguments of `osc_type`, to be placed in a tree with `insert()`
+    pub fn endpoint(osc_type: &str, access: Access) -> OscQueryNode {
+        OscQueryNode { osc_type: Some(osc_type.into()), access, ..OscQueryNode::default() }
+    }
+
+    pub fn with_description(mut self, description: &str) -> OscQueryNode {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn with_range(mut self, range: Vec<ValueRange>) -> OscQueryNode {
+        self.range = range;
+        self
+    }
+
+    pub fn with_value(mut self, value: Vec<Value>) -> OscQueryNode {
+        self.value = value;
+        self
+    }
+
+    // Read a node (and everything below it) from OSCQuery JSON; None if it isn't an object with a valid FULL_PATH.
+    // Attributes that can't be read are left unset, and so are children that aren't filed under their own name: the
+    // key has to be one part of an address, and the child's FULL_PATH has to be this node's with that part added.
+    pub fn from_json(json: &Value) -> Option<OscQueryNode> {
+        let full_path = json.get("FULL_PATH")?.as_str()?;
+        split_path(full_path).ok()?;
+        let mut node = OscQueryNode::read_attributes(json);
+        node.full_path = full_path.to_string();
+        if let Some(contents) = json.get("CONTENTS").and_then(Value::as_object) {
+            for (name, child) in contents {
+                let Some(child) = OscQueryNode::from_json(child) else {
+                    continue;
+                };
+                if !name.contains('/') && child.full_path == child_path(full_path, name) {
+                    node.child_mut(name).merge(child);
+                }
+            }
+        }
+        Some(node)
+    }
+
+    pub fn full_path(&self) -> &str {
+        &self.full_path
+    }
+
+    // The nodes directly below this one, by name
+    pub fn contents(&self) -> impl Iterator<Item = (&str, &OscQueryNode)> {
+        self.contents.iter().map(|(name, node)| (name.as_str(), node))
+    }
+
+    // The node at `path` ("/a/b", or "/" for this one) below this one
+    pub fn get(&self, path: &str) -> Option<&OscQueryNode> {
+        split_path(path).ok()?.into_iter().try_fold(self, |node, segment| node.contents.get(segment))
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut OscQueryNode> {
+        split_path(path).ok()?.into_iter().try_fold(self, |node, segment| node.contents.get_mut(segment))
+    }
+
+    // Put `node` at `path`, creating the containers above it. A node already there takes over the attributes of `node`
+    // but keeps its contents, with those of `node` added to them.
+    pub fn insert(&mut self, path: &str, node: OscQueryNode) -> Result<(), OscqError> {
+        let segments = split_path(path)?;
+        let target = segments.into_iter().fold(self, |node, segment| node.child_mut(segment));
+        target.merge(node);
+        Ok(())
+    }
+
+    // A new tree with just the endpoints (nodes with a TYPE) below this one whose address matches one of `patterns`
+    pub fn select<S: AsRef<str>>(&self, patterns: &[S]) -> OscQueryNode {
+        let mut selected = OscQueryNode::root();
+        for endpoint in self.endpoints() {
+            if patterns.iter().any(|pattern| matches_pattern(pattern.as_ref(), &endpoint.full_path)) {
+                let node = OscQueryNode { contents: BTreeMap::new(), ..endpoint.clone() };
+                // The path came from a tree, so it is valid
+                let _ = selected.insert(&endpoint.full_path, node);
+            }
+        }
+        selected
+    }
+
+    // This node and every node below it that has a TYPE
+    pub fn endpoints(&self) -> Vec<&OscQueryNode> {
+        let mut endpoints = Vec::new();
+        if self.osc_type.is_some() {
+            endpoints.push(self);
+        }
+        for child in self.contents.values() {
+            endpoints.extend(child.endpoints());
+        }
+        endpoints
+    }
+
+    // Take the node at `path` out of the tree, along with the containers above it it leaves empty
+    pub fn remove(&mut self, path: &str) -> Option<OscQueryNode> {
+        let segments = split_path(path).ok()?;
+        if segments.is_empty() {
+            return None;
+        }
+        self.remove_below(&segments)
+    }
+
+    // The node as OSCQuery JSON, including everything below it
+    pub fn to_json(&self) -> Value {
+        let mut node = Map::new();
+        for name in ATTRIBUTES {
+            if let Some(value) = self.attribute(name) {
+                node.insert(name.into(), value);
+            }
+        }
+        Value::Object(node)
+    }
+
+    // The value of one attribute, None if it isn't set (or isn't one of `ATTRIBUTES`)
+    pub fn attribute(&self, name: &str) -> Option<Value> {
+        match name {
+            "FULL_PATH" => Some(json!(self.full_path)),
+            "CONTENTS" if !self.contents.is_empty() => Some(Value::Object(
+                self.contents.iter().map(|(name, node)| (name.clone(), node.to_json())).collect(),
+            )),
+            "TYPE" => self.osc_type.as_ref().map(|osc_type| json!(osc_type)),
+            "ACCESS" => Some(json!(self.access.code())),
+            "RANGE" if !self.range.is_empty() => Some(self.range.iter().map(ValueRange::to_json).collect()),
+            "VALUE" if !self.value.is_empty() => Some(Value::Array(self.value.clone())),
+            "DESCRIPTION" => self.description.as_ref().map(|description| json!(description)),
+            _ => None,
+        }
+    }
+
+    // The attributes of a node in OSCQuery JSON, without FULL_PATH and CONTENTS
+    fn read_attributes(json: &Value) -> OscQueryNode {
+        let access = match json.get("ACCESS").and_then(Value::as_u64) {
+            Some(1) => Access::Read,
+            Some(2) => Access::Write,
+            Some(3) => Access::ReadWrite,
+            _ => Access::None,
+        };
+        let range = json
+            .get("RANGE")
+            .and_then(Value::as_array)
+            .map(|ranges| {
+                ranges
+                    .iter()
+                    .map(|range| ValueRange {
+                        min: range.get("MIN").cloned(),
+                        max: range.get("MAX").cloned(),
+                        vals: range.get("VALS").and_then(Value::as_array).cloned().unwrap_or_default(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        OscQueryNode {
+            osc_type: json.get("TYPE").and_then(Value::as_str).map(String::from),
+            access,
+            range,
+            value: json.get("VALUE").and_then(Value::as_array).cloned().unwrap_or_default(),
+            description: json.get("DESCRIPTION").and_then(Value::as_str).map(String::from),
+            ..OscQueryNode::default()
+        }
+    }
+
+    // Whether this node is only there to hold others
+    fn is_container(&self) -> bool {
+        self.osc_type.is_none()
+            && self.access == Access::None
+            && self.range.is_empty()
+            && self.value.is_empty()
+            && self.description.is_none()
+    }
+
+    fn merge(&mut self, node: OscQueryNode) {
+        self.osc_type = node.osc_type;
+        self.access = node.access;
+        self.range = node.range;
+        self.value = node.value;
+        self.description = node.description;
+        for (name, child) in node.contents {
+            self.child_mut(&name).merge(child);
+        }
+    }
+
+    // The node called `name` directly below this one, created as an empty container if there is none
+    fn child_mut(&mut self, name: &str) -> &mut OscQueryNode {
+        let full_path = child_path(&self.full_path, name);
+        self.contents
+            .entry(name.to_string())
+            .or_insert_with(|| OscQueryNode { full_path, ..OscQueryNode::default() })
+    }
+
+    fn remove_below(&mut self, segments: &[&str]) -> Option<OscQueryNode> {
+        let (first, rest) = segments.split_first()?;
+        if rest.is_empty() {
+            return self.contents.remove(*first);
+        }
+        let child = self.contents.get_mut(*first)?;
+        let removed = child.remove_below(rest);
+        if child.contents.is_empty() && child.is_container() {
+            self.contents.remove(*first);
+        }
+        removed
+    }
+}
+
+// Check that `pattern` can match addresses: absolute, without empty parts, and with no reserved characters other than
+// the `*` and `?` wildcards
+#[cfg(feature = "blocking")]
+pub(super) fn check_pattern(pattern: &str) -> Result<(), OscqError> {
+    let literal = pattern.replace(['*', '?'], "_");
+    split_path(&literal)
+        .map(|_| ())
+        .map_err(|e| match e {
+            OscqError::InvalidOscAddress { reason, .. } => OscqError::InvalidOscAddress { path: pattern.into(), reason },
+            e => e,
+        })
+}
+
+// The segments of an absolute OSC address, none for "/"
+fn split_path(path: &str) -> Result<Vec<&str>, OscqError> {
+    let invalid = |reason| OscqError::InvalidOscAddress { path: path.to_string(), reason };
+    let Some(rest) = path.strip_prefix('/') else {
+        return Err(invalid("it doesn't start with '/'"));
+    };
+    if rest.is_empty() {
+        return Ok(Vec::new());
+    }
+    let segments: Vec<&str> = rest.split('/').collect();
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return Err(invalid("it has an empty part"));
+    }
+    if segments.iter().any(|segment| segment.contains(RESERVED)) {
+        return Err(invalid("it contains one of the characters OSC reserves ( #*,?[]{})"));
+    }
+    Ok(segments)
+}
+
+fn child_path(parent: &str, name: &str) -> String {
+    format!("{}/{}", parent.trim_end_matches('/'), name)
+}
+
+// Whether the OSC address `path` matches `pattern`, part by part
+fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    pattern.len() == path.len()
+        && pattern.iter().zip(&path).all(|(pattern, part)| matches_part(pattern.as_bytes(), part.as_bytes()))
+}
+
+// `*` matches any run of characters and `?` any single one.
+// Matched left to right; on a mismatch only the last `*` is made to take one more character, since whatever an earlier
+// `*` could take instead the last one can take too. That keeps it linear in the length of `part` for each `*`.
+fn matches_part(pattern: &[u8], part: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where the pattern goes on after the last `*`, and how much of `part` that `*` has taken up to
+    let mut star: Option<(usize, usize)> = None;
+    while s < part.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, s));
+            }
+            Some(&c) if c == b'?' || c == part[s] => {
+                p += 1;
+                s += 1;
+            }
+            _ => match star {
+                Some((after, taken)) => {
+                    p = after;
+                    s = taken + 1;
+                    star = Some((after, s));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn giggletech_tree() -> OscQueryNode {
+        let mut tree = OscQueryNode::root();
+        tree.insert("/avatar/change", OscQueryNode::endpoint("s", Access::Write)).unwrap();
+        tree.insert(
+            "/avatar/parameters/Giggletech_Intensity",
+            OscQueryNode::endpoint("f", Access::ReadWrite)
+                .with_range(vec![ValueRange::between(0.0, 1.0)])
+                .with_value(vec![json!(0.5)])
+                .with_description("Haptic strength"),
+        )
+        .unwrap();
+        tree
+    }
+
+    #[test]
+    fn insert_creates_containers_with_full_paths() {
+        let tree = giggletech_tree();
+
+        let parameters = tree.get("/avatar/parameters").unwrap();
+        assert_eq!(parameters.full_path(), "/avatar/parameters");
+        assert!(parameters.osc_type.is_none());
+        assert_eq!(tree.get("/avatar/parameters/Giggletech_Intensity").unwrap().full_path(), "/avatar/parameters/Giggletech_Intensity");
+        assert_eq!(tree.get("/").unwrap().full_path(), "/");
+        assert!(tree.get("/avatar/nothing").is_none());
+    }
+
+    #[test]
+    fn serialises_to_oscquery_json() {
+        let tree = giggletech_tree();
+
+        assert_eq!(
+            tree.to_json(),
+            json!({
+                "FULL_PATH": "/",
+                "ACCESS": 0,
+                "CONTENTS": {
+                    "avatar": {
+                        "FULL_PATH": "/avatar",
+                        "ACCESS": 0,
+                        "CONTENTS": {
+                            "change": { "FULL_PATH": "/avatar/change", "TYPE": "s", "ACCESS": 2 },
+                            "parameters": {
+                                "FULL_PATH": "/avatar/parameters",
+                                "ACCESS": 0,
+                                "CONTENTS": {
+                                    "Giggletech_Intensity": {
+                                        "FULL_PATH": "/avatar/parameters/Giggletech_Intensity",
+                                        "TYPE": "f",
+                                        "ACCESS": 3,
+                                        "RANGE": [{ "MIN": 0.0, "MAX": 1.0 }],
+                                        "VALUE": [0.5],
+                                        "DESCRIPTION": "Haptic strength",
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn answers_attribute_queries() {
+        let tree = giggletech_tree();
+        let intensity = tree.get("/avatar/parameters/Giggletech_Intensity").unwrap();
+
+        assert_eq!(intensity.attribute("TYPE"), Some(json!("f")));
+        assert_eq!(intensity.attribute("VALUE"), Some(json!([0.5])));
+        assert_eq!(tree.get("/avatar/change").unwrap().attribute("VALUE"), None);
+        assert_eq!(intensity.attribute("BOGUS"), None);
+    }
+
+    #[test]
+    fn inserting_over_a_container_keeps_its_contents() {
+        let mut tree = giggletech_tree();
+        tree.insert("/avatar", OscQueryNode::endpoint("s", Access::Write)).unwrap();
+
+        assert_eq!(tree.get("/avatar").unwrap().osc_type.as_deref(), Some("s"));
+        assert!(tree.get("/avatar/change").is_some());
+    }
+
+    #[test]
+    fn remove_prunes_empty_containers() {
+        let mut tree = giggletech_tree();
+
+        let removed = tree.remove("/avatar/parameters/Giggletech_Intensity").unwrap();
+        assert_eq!(removed.osc_type.as_deref(), Some("f"));
+        assert!(tree.get("/avatar/parameters").is_none());
+        assert!(tree.get("/avatar/change").is_some());
+
+        tree.remove("/avatar/change");
+        assert!(tree.contents().next().is_none());
+        assert!(tree.remove("/").is_none());
+    }
+
+    #[test]
+    fn reads_its_own_json() {
+        let tree = giggletech_tree();
+
+        assert_eq!(OscQueryNode::from_json(&tree.to_json()), Some(tree));
+        assert_eq!(OscQueryNode::from_json(&json!({ "TYPE": "f" })), None);
+    }
+
+    #[test]
+    fn leaves_out_children_filed_under_another_name() {
+        let json = json!({
+            "FULL_PATH": "/avatar",
+            "CONTENTS": {
+                "change": { "FULL_PATH": "/avatar/change", "TYPE": "s" },
+                "parameters/VelocityX": { "FULL_PATH": "/avatar/parameters/VelocityX", "TYPE": "f" },
+                "Giggletech_Intensity": { "FULL_PATH": "/avatar/parameters/Giggletech_Intensity", "TYPE": "f" },
+            },
+        });
+
+        let node = OscQueryNode::from_json(&json).unwrap();
+        let paths: Vec<&str> = node.endpoints().iter().map(|node| node.full_path()).collect();
+        assert_eq!(paths, ["/avatar/change"]);
+    }
+
+    #[test]
+    fn matches_wildcards_within_a_part() {
+        for (pattern, part, expected) in [
+            ("Giggletech_*", "Giggletech_Intensity", true),
+            ("*_Intensity", "Giggletech_Intensity", true),
+            ("G*t*h_*y", "Giggletech_Intensity", true),
+            ("Velocity?", "VelocityX", true),
+            ("Velocity?", "Velocity", false),
+            ("*", "", true),
+            ("**", "a", true),
+            ("a*b", "acb_", false),
+            ("*X", "VelocityXY", false),
+        ] {
+            assert_eq!(matches_part(pattern.as_bytes(), part.as_bytes()), expected, "{} against {}", pattern, part);
+        }
+
+        // Backtracking into every `*` would take far too long here
+        let pattern = "*a".repeat(30) + "b";
+        assert!(!matches_part(pattern.as_bytes(), "a".repeat(100).as_bytes()));
+    }
+
+    #[test]
+    fn selects_endpoints_by_pattern() {
+        let mut avatar = giggletech_tree();
+        avatar.insert("/avatar/parameters/Giggletech_Enabled", OscQueryNode::endpoint("T", Access::ReadWrite)).unwrap();
+        avatar.insert("/avatar/parameters/VelocityX", OscQueryNode::endpoint("f", Access::Read)).unwrap();
+
+        let selected = avatar.select(&["/avatar/parameters/Giggletech_*", "/avatar/change"]);
+        let paths: Vec<&str> = selected.endpoints().iter().map(|node| node.full_path()).collect();
+        assert_eq!(
+            paths,
+            ["/avatar/change", "/avatar/parameters/Giggletech_Enabled", "/avatar/parameters/Giggletech_Intensity"]
+        );
+        assert_eq!(selected.get("/avatar/parameters/Giggletech_Intensity").unwrap().description.as_deref(), Some("Haptic strength"));
+
+        assert!(avatar.select(&["/avatar/*"]).get("/avatar/change").is_some());
+        assert!(avatar.select(&["/avatar/*"]).get("/avatar/parameters/VelocityX").is_none());
+        assert!(avatar.select(&["/avatar/parameters/Velocity?"]).get("/avatar/parameters/VelocityX").is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        let mut tree = OscQueryNode::root();
+
+        for path in ["avatar", "/avatar//change", "/avatar/*", "/a b"] {
+            let result = tree.insert(path, OscQueryNode::endpoint("f", Access::Write));
+            assert!(matches!(result, Err(OscqError::InvalidOscAddress { .. })), "{} was accepted", path);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+/*
+    Retry policy for the initialization loop.
+
+    `RetryPolicy` describes how patiently we wait for `giggletech_oscq.exe` to report a UDP port:
+    - `startup_grace`: a freshly spawned helper that doesn't answer yet is left alone for this long, because it is most
+      likely still binding its HTTP listener. It is only restarted once the grace period is over (or if it has exited).
+    - `initial_backoff`, `max_backoff`, `multiplier`, `jitter`: the delay between polls starts at `initial_backoff`, grows by
+      `multiplier` after every attempt up to `max_backoff`, and is randomly spread by +/- `jitter` (a fraction, 0.0 - 1.0).
+    - `max_attempts` and `deadline`: when either is reached the loop gives up and returns an error instead of spinning forever.
+      `None` means unlimited.
+
+    `Startup` is the running state of one initialization loop, shared by the blocking and async versions: given what the
+    helper answered to `/port_udp`, `Startup::step` decides what to do next (send `/start`, restart the helper, keep
+    waiting, or give up), and `Startup::next_delay` how long to wait before asking again, using `Backoff`. The loops
+    themselves only carry out those steps with their own HTTP client and process handle.
+
+    `Startup::plan` decides whether to adopt or launch a helper. Something on the helper's port that accepts connections
+    but doesn't answer is waited for like a helper starting up, but only for `startup_grace`: after that it is treated
+    like any other application on the port, so even an unbounded policy doesn't wait for it forever.
+*/
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+use std::time::Instant;
+use std::time::Duration;
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+use super::logging::{log_error, log_info, log_warn};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use super::{plan_helper_launch, Config, OscqError, Responder};
+use super::DEFAULT_STARTUP_TIMEOUT;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub startup_grace: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+    pub max_attempts: Option<u32>,
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            startup_grace: Duration::from_secs(3),
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+            deadline: Some(DEFAULT_STARTUP_TIMEOUT),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Same backoff as the default policy, but never gives up
+    pub fn unbounded() -> Self {
+        RetryPolicy {
+            max_attempts: None,
+            deadline: None,
+            ..RetryPolicy::default()
+        }
+    }
+}
+
+// What an initialization loop does next
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) enum StartupStep {
+    // The service runs on this UDP port
+    Ready(u16),
+    // Send `/start`, asking for the UDP port the loop was given if any
+    Start,
+    // Kill the helper if we started it, and look again for one to adopt or start
+    Relaunch,
+    // Leave the helper alone, it is still starting up
+    Wait,
+    // Give up with this error
+    Fail(OscqError),
+}
+
+// Running state of one initialization loop
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) struct Startup<'a> {
+    backoff: Backoff<'a>,
+    // UDP port of a socket the caller has bound, which the helper has to advertise
+    udp_port: Option<u16>,
+    // When the current helper was started or adopted
+    spawned_at: Instant,
+    // Whether the helper has been asked to move the service to `udp_port`
+    moved_to_udp_port: bool,
+    // Since when the helper's port has accepted connections without answering, if it does
+    silent_since: Option<Instant>,
+    // Error of the last poll, passed on to the policy
+    last_error: Option<OscqError>,
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl<'a> Startup<'a> {
+    pub(crate) fn new(policy: &'a RetryPolicy, udp_port: Option<u16>) -> Self {
+        Startup {
+            backoff: Backoff::new(policy),
+            udp_port,
+            spawned_at: Instant::now(),
+            moved_to_udp_port: false,
+            silent_since: None,
+            last_error: None,
+        }
+    }
+
+    // Decide what to do about whatever answered on the configured httpPort (see `plan_helper_launch`), giving up on
+    // something that has kept quiet for the whole startup grace
+    pub(crate) fn plan(&mut self, config: &Config, responder: Responder) -> Result<Option<u16>, OscqError> {
+        let responder = match responder {
+            Responder::Silent(error) => {
+                let grace = self.backoff.policy.startup_grace;
+                if self.silent_since.get_or_insert_with(Instant::now).elapsed() < grace {
+                    Responder::Silent(error)
+                } else {
+                    Responder::Foreign(format!("nothing within {}s: {}", grace.as_secs_f32(), error))
+                }
+            }
+            responder => {
+                self.silent_since = None;
+                responder
+            }
+        };
+        plan_helper_launch(config, responder)
+    }
+
+    // Decide what to do about the helper's answer to `/port_udp`.
+    // `exited` is true if the helper is a process we started that has exited.
+    pub(crate) fn step(&mut self, polled: Result<u16, OscqError>, exited: bool) -> StartupStep {
+        match polled {
+            Ok(0) => {
+                log_info!("UDP port is 0, sending start command...");
+                StartupStep::Start
+            }
+            Ok(port_value) if self.udp_port.is_some_and(|bound| bound != port_value) => {
+                // The service runs on a port of its own choosing, e.g. an adopted helper that was started before us
+                let bound = self.udp_port.unwrap_or_default();
+                if self.moved_to_udp_port {
+                    return StartupStep::Fail(OscqError::UdpPortIgnored { bound, reported: port_value });
+                }
+                log_info!("UDP port is {}, restarting the service on UDP port {}...", port_value, bound);
+                self.moved_to_udp_port = true;
+                StartupStep::Start
+            }
+            Ok(port_value) => {
+                log_info!("UDP port: {}", port_value);
+                StartupStep::Ready(port_value)
+            }
+            Err(e @ OscqError::InvalidPort { .. }) => {
+                // Something answered, but it isn't a port number. Most likely another application took the port, so
+                // look again: this either fails with `PortOccupiedByForeignService` or falls back to a free port.
+                log_warn!("{}", e);
+                self.relaunch(e)
+            }
+            Err(e) if exited || self.spawned_at.elapsed() >= self.backoff.policy.startup_grace => {
+                log_warn!("Failed to retrieve UDP port, restarting giggletech process...");
+                self.relaunch(e)
+            }
+            Err(e) => {
+                // Still within the startup grace period; give the listener time to come up
+                log_info!("OSCQ Server is still starting up, waiting...");
+                self.last_error = Some(e);
+                StartupStep::Wait
+            }
+        }
+    }
+
+    // Record how the step went (`error` is what carrying it out failed with, if anything) and return how long to wait
+    // before polling again, or the error to give up with once the policy is exhausted
+    pub(crate) fn next_delay(&mut self, error: Option<OscqError>) -> Result<Duration, OscqError> {
+        let last_error = error.or_else(|| self.last_error.take());
+        self.backoff.next_delay(last_error).inspect_err(|e| log_error!("Giving up on the OSCQ Server: {}", e))
+    }
+
+    fn relaunch(&mut self, error: OscqError) -> StartupStep {
+        self.spawned_at = Instant::now();
+        self.last_error = Some(error);
+        StartupStep::Relaunch
+    }
+}
+
+// Delays between the attempts of one initialization loop
+#[cfg(any(feature = "blocking", feature = "async"))]
+struct Backoff<'a> {
+    policy: &'a RetryPolicy,
+    started: Instant,
+    attempts: u32,
+    delay: Duration,
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl<'a> Backoff<'a> {
+    fn new(policy: &'a RetryPolicy) -> Self {
+        Backoff {
+            policy,
+            started: Instant::now(),
+            attempts: 0,
+            delay: policy.initial_backoff,
+        }
+    }
+
+    // Record a failed attempt and return how long to sleep before the next one,
+    // or the error to give up with once the policy is exhausted
+    fn next_delay(&mut self, last_error: Option<OscqError>) -> Result<Duration, OscqError> {
+        self.attempts += 1;
+        let last_error = last_error.map(Box::new);
+
+        if let Some(max_attempts) = self.policy.max_attempts {
+            if self.attempts >= max_attempts {
+                return Err(OscqError::RetriesExhausted { attempts: self.attempts, last_error });
+            }
+        }
+
+        let mut delay = jittered(self.delay, self.policy.jitter);
+        if let Some(deadline) = self.policy.deadline {
+            let remaining = deadline.saturating_sub(self.started.elapsed());
+            if remaining.is_zero() {
+                return Err(OscqError::Timeout { after: deadline, last_error });
+            }
+            delay = delay.min(remaining);
+        }
+
+        self.delay = scaled(self.delay, self.policy.multiplier.max(1.0), self.policy.max_backoff);
+        Ok(delay)
+    }
+}
+
+// Spread `delay` randomly by +/- `jitter` so several clients don't poll in lockstep
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn jittered(delay: Duration, jitter: f64) -> Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    scaled(delay, 1.0 + jitter * (2.0 * fastrand::f64() - 1.0), Duration::MAX)
+}
+
+// `delay * factor`, but no more than `max`. `Duration::mul_f64` panics when the product doesn't fit a Duration (a
+// multiplier of 1e300 does that on the second attempt), so this works in f64 seconds and clamps before converting.
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn scaled(delay: Duration, factor: f64, max: Duration) -> Duration {
+    let seconds = delay.as_secs_f64() * factor;
+    if seconds.is_nan() {
+        return max;
+    }
+    Duration::try_from_secs_f64(seconds.min(max.as_secs_f64()).max(0.0)).map_or(max, |scaled| scaled.min(max))
+}
+
+#[cfg(all(test, any(feature = "blocking", feature = "async")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_multipliers_stop_at_max_backoff() {
+        let policy = RetryPolicy {
+            multiplier: 1e300,
+            jitter: 0.0,
+            deadline: None,
+            ..RetryPolicy::default()
+        };
+        let mut backoff = Backoff::new(&policy);
+        assert_eq!(backoff.next_delay(None).unwrap(), policy.initial_backoff);
+        for _ in 0..3 {
+            assert_eq!(backoff.next_delay(None).unwrap(), policy.max_backoff);
+        }
+
+        let policy = RetryPolicy { max_backoff: Duration::MAX, ..policy };
+        let mut backoff = Backoff::new(&policy);
+        for _ in 0..3 {
+            backoff.next_delay(None).unwrap();
+        }
+        assert_eq!(backoff.next_delay(None).unwrap(), Duration::MAX);
+    }
+
+    #[test]
+    fn startup_steps_follow_the_helper_answers() {
+        let policy = RetryPolicy { startup_grace: Duration::from_secs(60), ..RetryPolicy::default() };
+        let invalid = || OscqError::InvalidPort { url: String::new(), body: "<html>".to_string() };
+
+        let mut startup = Startup::new(&policy, None);
+        assert!(matches!(startup.step(Ok(0), false), StartupStep::Start));
+        assert!(matches!(startup.step(Err(invalid()), false), StartupStep::Relaunch));
+        assert!(matches!(startup.step(Err(OscqError::Cancelled), false), StartupStep::Wait));
+        assert!(matches!(startup.step(Err(OscqError::Cancelled), true), StartupStep::Relaunch));
+        assert!(matches!(startup.step(Ok(9000), false), StartupStep::Ready(9000)));
+
+        // A helper advertising another port than the one we bound is asked to move once
+        let mut startup = Startup::new(&policy, Some(9001));
+        assert!(matches!(startup.step(Ok(9000), false), StartupStep::Start));
+        assert!(matches!(
+            startup.step(Ok(9000), false),
+            StartupStep::Fail(OscqError::UdpPortIgnored { bound: 9001, reported: 9000 })
+        ));
+        assert!(matches!(startup.step(Ok(9001), false), StartupStep::Ready(9001)));
+    }
+}
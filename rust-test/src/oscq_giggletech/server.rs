@@ -0,0 +1,547 @@
+/*
+    Native OSCQuery HTTP server.
+
+    Serves the same thing the helper's `OSCQueryService` does, from this process:
+    - `GET /?HOST_INFO`  -> NAME, OSC_IP, OSC_PORT, OSC_TRANSPORT and the supported EXTENSIONS
+    - `GET /`            -> the whole address-space tree as JSON
+    - `GET /some/path`   -> the node at that path (404 if there is none); the path may be percent-encoded
+    - `GET /some/path?VALUE` (or TYPE, ACCESS, RANGE, DESCRIPTION, ...) -> `{"VALUE": ...}`, or 204 if it isn't set
+
+    The tree is an `OscQueryNode` (see `node.rs`). It starts out as the helper's single `/avatar` endpoint, and can be
+    replaced or edited with `set_tree()`, `insert()` and `remove()` while the server runs.
+
+    `OscQueryServer::start()` listens on a free TCP port on localhost from a background thread, and hands each connection
+    to a fixed pool of `WORKERS` threads. A client gets `READ_TIMEOUT` in all to send its request, however it spaces it
+    out, and connections that arrive while `MAX_QUEUED_CONNECTIONS` are already waiting for a worker are closed
+    unanswered. The OSC port and service name it reports can be changed while it runs. Dropping the handle stops the
+    server.
+
+    With the `native-server` cargo feature, `initialize_and_get_udp_port()` and friends run this server in-process instead
+    of launching `giggletech_oscq.exe`, and advertise it over mDNS with an `Advertiser` (see `mdns.rs`) so VRChat finds it.
+    Both are kept running for the rest of the program, like the helper would be, and calling those functions again
+    reuses them rather than starting another server.
+*/
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use serde_json::{json, Value};
+
+use super::logging::{log_info, log_warn};
+use super::node::ATTRIBUTES;
+use super::{Access, OscQueryNode, OscqError};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use super::{Advertiser, Config};
+
+// How long a client may take to send its request, in all
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Most header lines read from one request before giving up on it, and the longest line read
+const MAX_HEADER_LINES: usize = 100;
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+// Threads answering requests, and how many accepted connections may wait for one of them
+const WORKERS: usize = 4;
+const MAX_QUEUED_CONNECTIONS: usize = 32;
+
+// The server started by `serve_in_process` and its advertisement, kept alive for the rest of the program
+#[cfg(any(feature = "blocking", feature = "async"))]
+static IN_PROCESS: Mutex<Option<InProcess>> = Mutex::new(None);
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+struct InProcess {
+    server: OscQueryServer,
+    advertiser: Option<Advertiser>,
+}
+
+pub struct OscQueryServer {
+    state: Arc<RwLock<State>>,
+    http_port: u16,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// What the server reports, shared with the connection threads
+struct State {
+    name: String,
+    osc_ip: Ipv4Addr,
+    osc_port: u16,
+    tree: OscQueryNode,
+}
+
+impl OscQueryServer {
+    // Serve on a free port on localhost, advertising OSC on UDP `osc_port` under `name`
+    pub fn start(name: &str, osc_port: u16) -> Result<OscQueryServer, OscqError> {
+        OscQueryServer::start_on(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), name, osc_port)
+    }
+
+    // Serve on `address` (port 0 picks a free one)
+    pub fn start_on(address: SocketAddr, name: &str, osc_port: u16) -> Result<OscQueryServer, OscqError> {
+        let listener = TcpListener::bind(address).map_err(|source| OscqError::ServerBind { address, source })?;
+        let http_port = listener.local_addr().map_err(|source| OscqError::ServerBind { address, source })?.port();
+
+        let state = Arc::new(RwLock::new(State {
+            name: name.to_string(),
+            osc_ip: Ipv4Addr::LOCALHOST,
+            osc_port,
+            tree: default_tree(),
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // The workers stop once the accept loop, holding the other end of the queue, is gone
+        let (queue, connections) = bounded(MAX_QUEUED_CONNECTIONS);
+        for _ in 0..WORKERS {
+            let state = Arc::clone(&state);
+            let connections = connections.clone();
+            thread::Builder::new()
+                .name("oscq-server-worker".into())
+                .spawn(move || connections.iter().for_each(|stream| handle_connection(stream, &state)))
+                .map_err(|source| OscqError::ThreadSpawn { name: "OSCQuery server worker", source })?;
+        }
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("oscq-server".into())
+                .spawn(move || accept_connections(listener, &queue, &stop))
+                .map_err(|source| OscqError::ThreadSpawn { name: "OSCQuery server", source })?
+        };
+
+        log_info!("OSCQuery server listening on http://localhost:{}/ (OSC on UDP {})", http_port, osc_port);
+        Ok(OscQueryServer { state, http_port, stop, thread: Some(thread) })
+    }
+
+    // TCP port the OSCQuery HTTP server listens on
+    pub fn http_port(&self) -> u16 {
+        self.http_port
+    }
+
+    pub fn name(&self) -> String {
+        self.state.read().unwrap().name.clone()
+    }
+
+    pub fn set_name(&self, name: &str) {
+        self.state.write().unwrap().name = name.to_string();
+    }
+
+    pub fn osc_port(&self) -> u16 {
+        self.state.read().unwrap().osc_port
+    }
+
+    pub fn set_osc_port(&self, osc_port: u16) {
+        self.state.write().unwrap().osc_port = osc_port;
+    }
+
+    // The HOST_INFO document, as served at `/?HOST_INFO`
+    pub fn host_info(&self) -> Value {
+        self.state.read().unwrap().host_info()
+    }
+
+    // A copy of the address space currently served
+    pub fn tree(&self) -> OscQueryNode {
+        self.state.read().unwrap().tree.clone()
+    }
+
+    // Serve `tree` instead, from the next request on
+    pub fn set_tree(&self, tree: OscQueryNode) {
+        let mut root = OscQueryNode::root();
+        root.insert("/", tree).expect("\"/\" is a valid address");
+        self.state.write().unwrap().tree = root;
+    }
+
+    // Add (or update) the node at `path`, see `OscQueryNode::insert()`
+    pub fn insert(&self, path: &str, node: OscQueryNode) -> Result<(), OscqError> {
+        self.state.write().unwrap().tree.insert(path, node)
+    }
+
+    // Stop serving the node at `path`, see `OscQueryNode::remove()`
+    pub fn remove(&self, path: &str) -> Option<OscQueryNode> {
+        self.state.write().unwrap().tree.remove(path)
+    }
+}
+
+impl Drop for OscQueryServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop up so it sees the flag
+        let _ = TcpStream::connect((Ipv4Addr::LOCALHOST, self.http_port));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl State {
+    fn host_info(&self) -> Value {
+        json!({
+            "NAME": self.name,
+            "OSC_IP": self.osc_ip.to_string(),
+            "OSC_PORT": self.osc_port,
+            "OSC_TRANSPORT": "UDP",
+            "EXTENSIONS": {
+                "ACCESS": true,
+                "CLIPMODE": false,
+                "DESCRIPTION": true,
+                "RANGE": true,
+                "TYPE": true,
+                "VALUE": true,
+            },
+        })
+    }
+}
+
+// The address space the helper advertises: a single write-only string endpoint at /avatar
+fn default_tree() -> OscQueryNode {
+    let mut tree = OscQueryNode::root();
+    let avatar = OscQueryNode::endpoint("s", Access::Write).with_description("This is my avatar endpoint");
+    tree.insert("/avatar", avatar).expect("/avatar is a valid address");
+    tree
+}
+
+// Queue every connection for the workers, closing the ones there is no room for
+fn accept_connections(listener: TcpListener, queue: &Sender<TcpStream>, stop: &AtomicBool) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(stream) = stream else {
+            continue;
+        };
+        match queue.try_send(stream) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => log_warn!("Too many OSCQuery requests at once, dropping one"),
+            Err(TrySendError::Disconnected(_)) => break,
+        }
+    }
+}
+
+// Reads from a connection until `deadline`, however the client spaces out what it sends
+struct UntilDeadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for UntilDeadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+// Answer one HTTP request and close the connection
+fn handle_connection(stream: TcpStream, state: &RwLock<State>) {
+    let _ = stream.set_write_timeout(Some(READ_TIMEOUT));
+    let mut reader = BufReader::new(UntilDeadline { stream: &stream, deadline: Instant::now() + READ_TIMEOUT });
+    let mut read_line = |line: &mut String| (&mut reader).take(MAX_LINE_LENGTH).read_line(line);
+
+    let mut request_line = String::new();
+    if read_line(&mut request_line).is_err() {
+        return;
+    }
+    // The headers don't matter to us, but have to be read before answering
+    for _ in 0..MAX_HEADER_LINES {
+        let mut header = String::new();
+        match read_line(&mut header) {
+            Ok(0) | Err(_) => break,
+            Ok(_) if header.trim().is_empty() => break,
+            Ok(_) => {}
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => respond(target, &state.read().unwrap()),
+        (Some(_), Some(_)) => (405, json!({ "error": "only GET is supported" })),
+        _ => (400, json!({ "error": "malformed request" })),
+    };
+    let _ = write_response(&stream, status, &body);
+}
+
+// Status and JSON body for a GET of `target` (the body is ignored for 204)
+fn respond(target: &str, state: &State) -> (u16, Value) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if query == "HOST_INFO" {
+        return (200, state.host_info());
+    }
+    if !query.is_empty() && !ATTRIBUTES.contains(&query) {
+        return (400, json!({ "error": format!("unsupported query {}", query) }));
+    }
+    let Some(path) = percent_decode(path) else {
+        return (400, json!({ "error": format!("invalid percent-encoding in {}", path) }));
+    };
+    // Trailing slashes are tolerated, as the helper does
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    };
+    let Some(node) = state.tree.get(path) else {
+        return (404, json!({ "error": format!("no node at {}", path) }));
+    };
+    if query.is_empty() {
+        return (200, node.to_json());
+    }
+    match node.attribute(query) {
+        Some(value) => (200, json!({ query: value })),
+        None => (204, Value::Null),
+    }
+}
+
+// Undo the percent-encoding of a request path ("Gr%C3%B6%C3%9Fe" -> "Größe"); None if it isn't valid
+fn percent_decode(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            decoded.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn write_response(mut stream: &TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Error",
+    };
+    let body = if status == 204 { String::new() } else { body.to_string() };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// Serve OSCQuery for `config` from this process, for the rest of the program, advertising OSC on `udp_port`, and
+// return that port. Without `udp_port` the server keeps the port it already advertises, or the first time a socket is
+// bound on a free port (as the helper does), which is returned too: it holds the port until the caller lets go of it.
+// Later calls reuse the server started by the first one, with the new name and port.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(super) fn serve_in_process(config: &Config, udp_port: Option<u16>) -> Result<(u16, Option<UdpSocket>), OscqError> {
+    let mut in_process = IN_PROCESS.lock().unwrap();
+    let (udp_port, socket) = match (udp_port, in_process.as_ref()) {
+        (Some(udp_port), _) => (udp_port, None),
+        (None, Some(running)) => (running.server.osc_port(), None),
+        (None, None) => {
+            let (socket, udp_port) = bind_osc_socket()?;
+            (udp_port, Some(socket))
+        }
+    };
+
+    match in_process.as_mut() {
+        Some(running) if running.server.name() != config.service_name => {
+            running.server.set_name(&config.service_name);
+            running.server.set_osc_port(udp_port);
+            // Say goodbye under the old name before advertising the new one
+            running.advertiser.take();
+            running.advertiser = advertise(config, running.server.http_port(), udp_port);
+        }
+        Some(running) => {
+            running.server.set_osc_port(udp_port);
+            if let Some(advertiser) = &running.advertiser {
+                advertiser.set_ports(running.server.http_port(), udp_port);
+            }
+        }
+        None => {
+            let server = OscQueryServer::start(&config.service_name, udp_port)?;
+            let advertiser = advertise(config, server.http_port(), udp_port);
+            *in_process = Some(InProcess { server, advertiser });
+        }
+    }
+    log_info!("UDP port: {}", udp_port);
+    Ok((udp_port, socket))
+}
+
+// Without mDNS VRChat can't find the server, but it still works for anyone given the port
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn advertise(config: &Config, http_port: u16, udp_port: u16) -> Option<Advertiser> {
+    Advertiser::start(&config.service_name, http_port, udp_port)
+        .map_err(|e| log_warn!("Not advertising the OSCQuery server: {}", e))
+        .ok()
+}
+
+// Bind a UDP socket on a port nobody is using
+#[cfg(any(feature = "blocking", feature = "async"))]
+fn bind_osc_socket() -> Result<(UdpSocket, u16), OscqError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|source| OscqError::UdpBind { source })?;
+    let udp_port = socket.local_addr().map_err(|source| OscqError::UdpBind { source })?.port();
+    Ok((socket, udp_port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // Plain HTTP GET against the server on localhost; returns the status code and the parsed body
+    fn get(server: &OscQueryServer, target: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, server.http_port())).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let body = if body.is_empty() { Value::Null } else { serde_json::from_str(body).unwrap() };
+        (status, body)
+    }
+
+    #[test]
+    fn serves_host_info() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+        let (status, info) = get(&server, "/?HOST_INFO");
+
+        assert_eq!(status, 200);
+        assert_eq!(info["NAME"], "Giggletech");
+        assert_eq!(info["OSC_PORT"], 9001);
+        assert_eq!(info["OSC_TRANSPORT"], "UDP");
+        assert_eq!(info["EXTENSIONS"]["VALUE"], true);
+    }
+
+    // The only test serving in process, since there is one such server per process
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    #[test]
+    fn serves_in_process_from_one_server() {
+        let mut config = Config::default();
+        config.service_name = "In Process Test".to_string();
+        let (udp_port, socket) = serve_in_process(&config, None).unwrap();
+        assert_eq!(socket.unwrap().local_addr().unwrap().port(), udp_port);
+        let http_port = IN_PROCESS.lock().unwrap().as_ref().unwrap().server.http_port();
+
+        let (again, socket) = serve_in_process(&config, None).unwrap();
+        assert_eq!(again, udp_port);
+        assert!(socket.is_none());
+
+        config.service_name = "In Process Test 2".to_string();
+        assert_eq!(serve_in_process(&config, Some(9999)).unwrap().0, 9999);
+        let in_process = IN_PROCESS.lock().unwrap();
+        let server = &in_process.as_ref().unwrap().server;
+        assert_eq!((server.http_port(), server.osc_port()), (http_port, 9999));
+        assert_eq!(server.name(), "In Process Test 2");
+    }
+
+    #[test]
+    fn host_info_follows_changes() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+        server.set_osc_port(9002);
+        server.set_name("Living Room");
+        let (_, info) = get(&server, "/?HOST_INFO");
+
+        assert_eq!(info["OSC_PORT"], 9002);
+        assert_eq!(info["NAME"], "Living Room");
+    }
+
+    #[test]
+    fn serves_the_tree_and_its_nodes() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+
+        let (status, root) = get(&server, "/");
+        assert_eq!(status, 200);
+        assert_eq!(root["FULL_PATH"], "/");
+        assert_eq!(root["CONTENTS"]["avatar"]["TYPE"], "s");
+
+        let (status, avatar) = get(&server, "/avatar");
+        assert_eq!(status, 200);
+        assert_eq!(avatar["FULL_PATH"], "/avatar");
+        assert_eq!(avatar["ACCESS"], 2);
+    }
+
+    #[test]
+    fn serves_single_attributes() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+
+        assert_eq!(get(&server, "/avatar?TYPE"), (200, json!({ "TYPE": "s" })));
+        assert_eq!(get(&server, "/avatar?ACCESS"), (200, json!({ "ACCESS": 2 })));
+        assert_eq!(get(&server, "/avatar?VALUE").0, 204);
+        assert_eq!(get(&server, "/avatar?BOGUS").0, 400);
+    }
+
+    #[test]
+    fn serves_changes_to_the_tree() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+        server.remove("/avatar");
+        server
+            .insert("/avatar/parameters/Giggletech_Intensity", OscQueryNode::endpoint("f", Access::Write))
+            .unwrap();
+
+        let (status, root) = get(&server, "/");
+        assert_eq!(status, 200);
+        assert_eq!(root["CONTENTS"]["avatar"]["CONTENTS"]["parameters"]["CONTENTS"]["Giggletech_Intensity"]["TYPE"], "f");
+        assert!(root["CONTENTS"]["avatar"].get("TYPE").is_none());
+        assert_eq!(get(&server, "/avatar/parameters/Giggletech_Intensity?FULL_PATH").1["FULL_PATH"], "/avatar/parameters/Giggletech_Intensity");
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+
+        assert_eq!(get(&server, "/nothing/here").0, 404);
+    }
+
+    #[test]
+    fn decodes_percent_encoded_paths() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+        server.insert("/avatar/parameters/Größe", OscQueryNode::endpoint("f", Access::Read)).unwrap();
+
+        let (status, node) = get(&server, "/avatar/parameters/Gr%C3%B6%C3%9Fe");
+        assert_eq!(status, 200);
+        assert_eq!(node["FULL_PATH"], "/avatar/parameters/Größe");
+        assert_eq!(get(&server, "/avatar/parameters/Gr%C3%B6%C3%9Fe?TYPE").1, json!({ "TYPE": "f" }));
+        assert_eq!(get(&server, "/%61vatar").0, 200);
+        assert_eq!(get(&server, "/avatar%2").0, 400);
+        assert_eq!(get(&server, "/avatar%FF").0, 400);
+    }
+
+    #[test]
+    fn gives_up_on_a_request_that_takes_too_long() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+        // Keep every worker busy with a request that never ends, sent a line at a time
+        let started = Instant::now();
+        for _ in 0..WORKERS {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, server.http_port())).unwrap();
+            thread::spawn(move || {
+                let _ = write!(stream, "GET / HTTP/1.1\r\n");
+                while write!(stream, "X-Slow: yes\r\n").is_ok() && started.elapsed() < READ_TIMEOUT * 3 {
+                    thread::sleep(Duration::from_millis(500));
+                }
+            });
+        }
+        thread::sleep(Duration::from_millis(200));
+
+        // Once they have given up on those, the workers serve others again
+        assert_eq!(get(&server, "/avatar").0, 200);
+        assert!(started.elapsed() < READ_TIMEOUT * 2, "took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn stops_when_dropped() {
+        let server = OscQueryServer::start("Giggletech", 9001).unwrap();
+        let port = server.http_port();
+        drop(server);
+
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+/*
+    Avatar parameter subscriptions.
+
+    VRChat only sends the addresses an OSCQuery service advertises, so advertising the helper's catch-all `/avatar` gets
+    every parameter of the avatar sent to us. A `Subscription` holds address patterns such as
+    "/avatar/parameters/Giggletech_*" and "/avatar/change" instead, and works out which endpoints they stand for:
+    - VRChat's own OSCQuery service (`_oscjson._tcp`, named `VRChat-Client-XXXXXX`) is found with a `Browser` (see
+      `discovery.rs`), and its `/avatar` node lists the current avatar's parameters with their types.
+    - `expand()` fetches that list and picks the endpoints matching the patterns out of it (see `OscQueryNode::select()`),
+      so a new avatar gives a new set of endpoints. An answer that can't be read keeps the last avatar's endpoints.
+    - Until VRChat has been found the subscription stands for no endpoints at all. Once it has, VRChat dropping out of
+      the browser (it quit, or its records lapsed) changes nothing: the last endpoints stay published until it is back.
+
+    Asking VRChat can take up to `DEFAULT_READ_TIMEOUT`, so the supervisor doesn't expand the subscription itself: an
+    `Expander` does it every `poll_interval` on a thread of its own, and the watchdog only picks up the latest result. It
+    publishes that on the helper with `HelperClient::set_endpoints()`, see `Supervisor::subscribe()`.
+*/
+
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use reqwest::blocking::Client;
+use serde_json::Value;
+
+use super::logging::{log_debug, log_warn};
+use super::{
+    Browser, OscQueryNode, OscqError, ServiceKind, ServiceProfile, DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT,
+    VRCHAT_CLIENT_PREFIX,
+};
+
+pub(super) struct Subscription {
+    patterns: Vec<String>,
+    interface: Ipv4Addr,
+    // Started on the first `expand()`, and again after that if it failed
+    browser: Option<Browser>,
+    client: Client,
+    // Whether VRChat has been found at least once
+    found_vrchat: bool,
+    // The parameters of the avatar VRChat last told us about
+    avatar: Option<OscQueryNode>,
+}
+
+impl Subscription {
+    pub(super) fn new(patterns: Vec<String>) -> Result<Subscription, OscqError> {
+        Subscription::on(Ipv4Addr::UNSPECIFIED, patterns)
+    }
+
+    // Look for VRChat on the interface with address `interface`
+    pub(super) fn on(interface: Ipv4Addr, patterns: Vec<String>) -> Result<Subscription, OscqError> {
+        let client = Client::builder()
+            .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
+            .timeout(DEFAULT_READ_TIMEOUT)
+            .build()
+            .map_err(|source| OscqError::Http { url: "/avatar".to_string(), source })?;
+        Ok(Subscription { patterns, interface, browser: None, client, found_vrchat: false, avatar: None })
+    }
+
+    pub(super) fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub(super) fn set_patterns(&mut self, patterns: Vec<String>) {
+        self.patterns = patterns;
+    }
+
+    // The endpoints matching the patterns among the parameters of the last avatar VRChat told us about, to publish on a
+    // new helper while VRChat can't be asked
+    pub(super) fn last(&self) -> Option<OscQueryNode> {
+        let mut tree = self.avatar.as_ref()?.select(&self.patterns);
+        // The helper only needs the types; with VRChat's current values in it the tree would change all the time
+        clear_values(&mut tree);
+        Some(tree)
+    }
+
+    // The endpoints of the current avatar matching the patterns; an empty tree while VRChat has never been found, and
+    // None if it isn't seen or can't be asked right now, so whatever was published before can stay
+    pub(super) fn expand(&mut self) -> Option<OscQueryNode> {
+        if self.browser.is_none() {
+            self.browser = Browser::start_on(self.interface)
+                .map_err(|e| log_warn!("Can't look for VRChat to subscribe to its avatar parameters: {}", e))
+                .ok();
+        }
+        let vrchat: Vec<ServiceProfile> = self.browser.as_ref()?.profiles().into_iter().filter(is_vrchat).collect();
+        if vrchat.is_empty() {
+            return (!self.found_vrchat).then(OscQueryNode::root);
+        }
+        self.found_vrchat = true;
+
+        // A client that quit without a goodbye stays listed until its records expire, so try each one
+        let mut last_error = None;
+        for profile in &vrchat {
+            match self.avatar(profile) {
+                Ok(avatar) => {
+                    self.avatar = Some(avatar);
+                    return self.last();
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if let Some(e) = last_error {
+            log_debug!("Couldn't read the avatar parameters from VRChat: {}", e);
+        }
+        None
+    }
+
+    // VRChat's `/avatar` node, listing the parameters of the current avatar
+    fn avatar(&self, vrchat: &ServiceProfile) -> Result<OscQueryNode, OscqError> {
+        let url = format!("http://{}:{}/avatar", vrchat.host, vrchat.port);
+        let json: Value = self
+            .client
+            .get(&url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|source| OscqError::Http { url: url.clone(), source })?;
+        OscQueryNode::from_json(&json).ok_or(OscqError::InvalidNode { url })
+    }
+}
+
+// Expands a `Subscription` over and over on a thread of its own. Dropping it stops the thread once the expansion under
+// way (if any) is done.
+pub(super) struct Expander {
+    expansion: Arc<Mutex<Expansion>>,
+    // Wakes the thread up for an expansion right away; the thread stops when it is dropped
+    wake_tx: Sender<()>,
+}
+
+// What the handle and the thread share
+struct Expansion {
+    patterns: Vec<String>,
+    interval: Duration,
+    // The endpoints last found for `patterns`, None until there are any
+    tree: Option<OscQueryNode>,
+}
+
+impl Expander {
+    // Expand `patterns` now and then every `interval`
+    pub(super) fn start(patterns: Vec<String>, interval: Duration) -> Result<Expander, OscqError> {
+        let subscription = Subscription::new(patterns.clone())?;
+        let expansion = Arc::new(Mutex::new(Expansion { patterns, interval, tree: None }));
+        let (wake_tx, wake_rx) = mpsc::channel();
+        let shared = Arc::clone(&expansion);
+        thread::Builder::new()
+            .name("oscq-subscription".into())
+            .spawn(move || expand(subscription, &shared, &wake_rx))
+            .map_err(|source| OscqError::ThreadSpawn { name: "subscription", source })?;
+        Ok(Expander { expansion, wake_tx })
+    }
+
+    // Expand other patterns from now on, starting right away
+    pub(super) fn set_patterns(&self, patterns: Vec<String>) {
+        let mut expansion = self.expansion.lock().unwrap();
+        if expansion.patterns != patterns {
+            expansion.patterns = patterns;
+            expansion.tree = None;
+            let _ = self.wake_tx.send(());
+        }
+    }
+
+    pub(super) fn set_interval(&self, interval: Duration) {
+        self.expansion.lock().unwrap().interval = interval;
+    }
+
+    // The endpoints the current patterns stand for, as last found; None until they have been expanded
+    pub(super) fn latest(&self) -> Option<OscQueryNode> {
+        self.expansion.lock().unwrap().tree.clone()
+    }
+}
+
+// The thread of an `Expander`: without an answer from VRChat, the last endpoints found are kept
+fn expand(mut subscription: Subscription, expansion: &Mutex<Expansion>, wake_rx: &Receiver<()>) {
+    loop {
+        let patterns = expansion.lock().unwrap().patterns.clone();
+        if subscription.patterns() != patterns.as_slice() {
+            subscription.set_patterns(patterns.clone());
+        }
+        let tree = subscription.expand().or_else(|| subscription.last());
+
+        let interval = {
+            let mut expansion = expansion.lock().unwrap();
+            // Patterns changed while VRChat was being asked are expanded on the next round
+            if tree.is_some() && expansion.patterns == patterns {
+                expansion.tree = tree;
+            }
+            expansion.interval
+        };
+        if let Err(RecvTimeoutError::Disconnected) = wake_rx.recv_timeout(interval) {
+            return;
+        }
+    }
+}
+
+fn clear_values(tree: &mut OscQueryNode) {
+    let paths: Vec<String> = tree.endpoints().iter().map(|node| node.full_path().to_string()).collect();
+    for path in paths {
+        if let Some(node) = tree.get_mut(&path) {
+            node.value.clear();
+        }
+    }
+}
+
+fn is_vrchat(profile: &ServiceProfile) -> bool {
+    profile.kind == ServiceKind::OscQuery && profile.name.starts_with(VRCHAT_CLIENT_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::oscq_giggletech::{Access, Advertiser, OscQueryServer};
+
+    // Expand `subscription` until it gives a tree matching `done`
+    fn expand_until(subscription: &mut Subscription, done: impl Fn(&OscQueryNode) -> bool) -> Option<OscQueryNode> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            match subscription.expand() {
+                Some(tree) if done(&tree) => return Some(tree),
+                _ => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn expands_patterns_against_the_avatar_vrchat_publishes() {
+        // A stand-in for VRChat, publishing the parameters of an avatar
+        let vrchat = OscQueryServer::start("VRChat-Client-5UB5CR", 9001).unwrap();
+        vrchat.insert("/avatar/change", OscQueryNode::endpoint("s", Access::Read)).unwrap();
+        for (name, osc_type) in [("Giggletech_Intensity", "f"), ("Giggletech_Enabled", "T"), ("VelocityX", "f")] {
+            let endpoint = OscQueryNode::endpoint(osc_type, Access::ReadWrite).with_value(vec![0.into()]);
+            vrchat.insert(&format!("/avatar/parameters/{}", name), endpoint).unwrap();
+        }
+        let advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "VRChat-Client-5UB5CR", vrchat.http_port(), 9001).unwrap();
+
+        let patterns = vec!["/avatar/parameters/Giggletech_*".to_string(), "/avatar/change".to_string()];
+        let mut subscription = Subscription::on(Ipv4Addr::LOCALHOST, patterns).unwrap();
+        let tree = expand_until(&mut subscription, |tree| !tree.endpoints().is_empty()).unwrap();
+        let paths: Vec<&str> = tree.endpoints().iter().map(|node| node.full_path()).collect();
+        assert_eq!(
+            paths,
+            ["/avatar/change", "/avatar/parameters/Giggletech_Enabled", "/avatar/parameters/Giggletech_Intensity"]
+        );
+        assert!(tree.get("/avatar/parameters/Giggletech_Intensity").unwrap().value.is_empty());
+
+        // The next avatar has other parameters
+        vrchat.remove("/avatar/parameters/Giggletech_Enabled");
+        let tree = expand_until(&mut subscription, |tree| tree.endpoints().len() == 2).unwrap();
+        assert!(tree.get("/avatar/parameters/Giggletech_Enabled").is_none());
+
+        // Once VRChat is gone the published endpoints are kept, rather than replaced by none
+        drop(advertiser);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while subscription.browser.as_ref().unwrap().profiles().iter().any(is_vrchat) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(subscription.expand().is_none());
+        assert_eq!(subscription.last().map(|tree| tree.endpoints().len()), Some(2));
+    }
+
+    #[test]
+    fn keeps_the_last_avatar_when_vrchat_answers_something_else() {
+        // A stand-in for VRChat that answers every request with JSON that isn't an OSCQuery node
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let vrchat = ServiceProfile {
+            name: "VRChat-Client-0DD0DD".to_string(),
+            host: Ipv4Addr::LOCALHOST,
+            port: listener.local_addr().unwrap().port(),
+            kind: ServiceKind::OscQuery,
+        };
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n[]");
+            }
+        });
+
+        let _advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, &vrchat.name, vrchat.port, 9001).unwrap();
+        assert!(matches!(Subscription::new(Vec::new()).unwrap().avatar(&vrchat), Err(OscqError::InvalidNode { .. })));
+
+        // The avatar VRChat told us about before
+        let mut avatar = OscQueryNode::root();
+        avatar.insert("/avatar/parameters/Giggletech_Intensity", OscQueryNode::endpoint("f", Access::ReadWrite)).unwrap();
+        let patterns = vec!["/avatar/parameters/*".to_string()];
+        let mut subscription = Subscription::on(Ipv4Addr::LOCALHOST, patterns).unwrap();
+        subscription.avatar = Some(avatar);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !subscription.found_vrchat && Instant::now() < deadline {
+            subscription.expand();
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(subscription.found_vrchat);
+        assert!(subscription.expand().is_none());
+        assert_eq!(subscription.last().map(|tree| tree.endpoints().len()), Some(1));
+    }
+
+    #[test]
+    fn expander_follows_new_patterns_without_waiting_for_its_interval() {
+        let vrchat = OscQueryServer::start("VRChat-Client-E8A9D3", 9001).unwrap();
+        for name in ["Giggletech_Intensity", "VelocityX"] {
+            vrchat.insert(&format!("/avatar/parameters/{}", name), OscQueryNode::endpoint("f", Access::ReadWrite)).unwrap();
+        }
+        let _advertiser = Advertiser::start_on(Ipv4Addr::LOCALHOST, "VRChat-Client-E8A9D3", vrchat.http_port(), 9001).unwrap();
+        let paths = |expander: &Expander| -> Vec<String> {
+            let tree = expander.latest().unwrap_or_default();
+            tree.endpoints().iter().map(|node| node.full_path().to_string()).collect()
+        };
+        let wait_for = |expander: &Expander, expected: &[&str]| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while paths(expander) != expected && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(20));
+            }
+            assert_eq!(paths(expander), expected);
+        };
+
+        let patterns = vec!["/avatar/parameters/Giggletech_*".to_string()];
+        let expander = Expander::start(patterns, Duration::from_millis(50)).unwrap();
+        wait_for(&expander, &["/avatar/parameters/Giggletech_Intensity"]);
+
+        // Left alone, the expander would only look again an hour from now
+        expander.set_interval(Duration::from_secs(3600));
+        thread::sleep(Duration::from_millis(200));
+        expander.set_patterns(vec!["/avatar/parameters/Velocity*".to_string()]);
+        wait_for(&expander, &["/avatar/parameters/VelocityX"]);
+    }
+}
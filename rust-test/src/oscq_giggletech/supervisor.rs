@@ -0,0 +1,849 @@
+/*
+    Long-running watchdog for the Giggletech OSCQuery server.
+
+    `initialize_and_get_udp_port()` stops caring about `giggletech_oscq.exe` as soon as it has a port. `Supervisor` keeps
+    watching it from a background thread for as long as the handle is alive:
+    - Every `poll_interval` the process is checked with `try_wait`, and `/port_udp` and `/port_tcp` are polled.
+    - If the process has exited, or hasn't answered for `hang_timeout`, it is killed and brought up again using the
+      `RetryPolicy`, which also re-issues `/start`.
+    - If the process answers with port 0 (the OSCQuery service isn't running), `/start` is sent again.
+
+    The latest ports are available through `ports()`. Because the helper picks new random ports on every `/start`, a restart
+    usually moves them; whenever the polled ports differ from the last known ones a `SupervisorEvent::PortsChanged` is sent
+    to every receiver returned by `events()`, so e.g. an OSC receiver can rebind straight away.
+
+    The config file the supervisor was started from is watched too: when its modification time changes it is read again
+    (with the same environment and command line overrides on top) and compared with the running config. A new `httpPort`
+    or launch setting restarts the helper, a new `serviceName` re-issues `/start` under that name, and timeouts, retry
+    settings and the log level are simply updated in place. Every changed setting is logged and sent to the receivers as a
+    `SupervisorEvent::ConfigChanged`. A file that no longer parses is reported and ignored until it is fixed. A config that
+    was loaded without a file, because none was found, takes on the first one that turns up on the search path.
+
+    With `advertise` the service is advertised over mDNS from Rust with an `Advertiser` (see `mdns.rs`), and the helpers we
+    start are told to leave that out. The advertiser claims a name nobody else on the network is using, so if another host
+    already advertises "Giggletech" ours becomes "Giggletech (2)"; the helper is then restarted under that name too, so its
+    HOST_INFO matches. `info()` reports the name actually in use. A helper that is adopted rather than started is left to
+    advertise itself, as it was started by someone else and probably does.
+
+    `subscribe()` narrows down what VRChat sends us. Instead of the helper's catch-all `/avatar`, the helper publishes only
+    the endpoints matching a set of address patterns ("/avatar/parameters/Giggletech_*", "/avatar/change"), expanded
+    against the parameters of the avatar VRChat currently has loaded (see `subscription.rs`). The expansion is redone every
+    `poll_interval` on a thread of its own, so waiting for VRChat never delays the health checks; a new avatar or a new
+    subscription updates the published tree within about two polls, and the tree is published again on every helper we
+    restart. `unsubscribe()` goes back to `/avatar`.
+
+    With `bind_udp` the supervisor binds the OSC `UdpSocket` itself before starting the helper, and tells every helper it
+    starts (or adopts) to advertise that socket's port. The caller gets the bound socket from `udp_socket()`, so there is
+    no window in which another process can take the port, and the UDP port no longer moves when the helper restarts.
+
+    If a helper is already running on the configured port when the supervisor starts, it is adopted instead of starting a
+    second one (which would only fail to bind the port). An adopted helper can't be watched with `try_wait`, so it is only
+    supervised over HTTP. When it stops answering it is looked for again rather than restarted: it is someone else's and
+    may only be busy, so it is only sent `/stop` to make way for a new helper if `stop_adopted_helper` is set.
+
+    Dropping the handle (or calling `shutdown()`) stops the watchdog thread and then the helper: it is sent `/stop`, given
+    `shutdown_grace` to exit on its own, and only killed if it is still alive after that. An adopted helper is left running
+    unless `stop_adopted_helper` is set. Signals are the host application's business, so by default none are handled, and
+    a host has to opt in to `handle_signals` for the helper to be stopped on Ctrl-C. With it, Ctrl-C, SIGINT and SIGTERM
+    run the same shutdown for every live supervisor and then exit the process (with status 130), so the helper doesn't
+    outlive us and keep httpPort; that suits a program with no Ctrl-C handling of its own, like the `rust-test` binary.
+*/
+
+use std::fs;
+use std::path::Path;
+use std::net::UdpSocket;
+use std::process::Child;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Once, Weak};
+use crossbeam_channel::{unbounded, Receiver as EventReceiver, Sender as EventSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::logging::{self, log_error, log_info, log_warn};
+use super::config::SettingChange;
+use super::node::check_pattern;
+use super::subscription::Expander;
+use super::{
+    bind_udp_socket, bring_up, locate_config, Advertiser, Config, HelperClient, HelperProcess, OscQueryNode, OscqError,
+    OscqInfo, RetryPolicy,
+};
+
+// Ports the OSCQuery service is currently using
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    pub udp: u16,
+    pub tcp: u16,
+}
+
+// Things the watchdog reports to `Supervisor::events()` receivers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SupervisorEvent {
+    // The helper now reports different ports than before, usually after a restart
+    PortsChanged { old: Ports, new: Ports },
+    // A setting in the config file was changed and has been applied; values are shown as written in the file
+    ConfigChanged { key: &'static str, old: String, new: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct SupervisorOptions {
+    // Used to bring the process up, both initially and after every restart
+    pub retry: RetryPolicy,
+    // How often the running process is checked
+    pub poll_interval: Duration,
+    // How long the process may go without answering before it is considered hung and restarted
+    pub hang_timeout: Duration,
+    // Whether a helper that was already running when we started is shut down (with `/stop`) when the supervisor stops
+    pub stop_adopted_helper: bool,
+    // How long the helper gets to exit after `/stop` before it is killed
+    pub shutdown_grace: Duration,
+    // Install a Ctrl-C / SIGINT / SIGTERM handler that shuts the helper down and exits the process. Off by default, so
+    // without it a host has to stop the supervisor on those itself.
+    pub handle_signals: bool,
+    // Bind the OSC UDP socket ourselves and have the helper advertise its port (see `udp_socket()`)
+    pub bind_udp: bool,
+    // Advertise the service over mDNS from Rust, under a name nobody else is using, instead of leaving it to the helper
+    pub advertise: bool,
+}
+
+impl Default for SupervisorOptions {
+    fn default() -> Self {
+        SupervisorOptions::from(&Config::default())
+    }
+}
+
+// Options with the timeouts and retry policy set up in `config`
+impl From<&Config> for SupervisorOptions {
+    fn from(config: &Config) -> Self {
+        SupervisorOptions {
+            retry: config.retry_policy(),
+            poll_interval: config.poll_interval,
+            hang_timeout: config.hang_timeout,
+            stop_adopted_helper: false,
+            shutdown_grace: config.shutdown_grace,
+            handle_signals: false,
+            bind_udp: false,
+            advertise: false,
+        }
+    }
+}
+
+pub struct Supervisor {
+    shared: Arc<Shared>,
+    control: Arc<Control>,
+    // OSC socket bound before the helper was started, with `bind_udp`
+    socket: Option<UdpSocket>,
+}
+
+impl Supervisor {
+    // Bring the server up with the options from the config and keep supervising it
+    pub fn start() -> Result<Supervisor, OscqError> {
+        let config = Config::load()?;
+        let options = SupervisorOptions::from(&config);
+        Supervisor::start_with_config(config, options)
+    }
+
+    // Bring the server up and keep supervising it.
+    // Returns once the first UDP port is known, or with the error that prevented it.
+    pub fn start_with(options: SupervisorOptions) -> Result<Supervisor, OscqError> {
+        Supervisor::start_with_config(Config::load()?, options)
+    }
+
+    // Same as `start_with`, for an already loaded (and possibly overridden) config
+    pub fn start_with_config(config: Config, options: SupervisorOptions) -> Result<Supervisor, OscqError> {
+        logging::set_level(config.log_level);
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        let socket = if options.bind_udp { Some(bind_udp_socket()?) } else { None };
+        let udp_port = socket.as_ref().map(|(_, port)| *port);
+
+        let mdns = !options.advertise;
+        let (process, client, udp) = bring_up(&config, &options.retry, udp_port, mdns, &mut |delay| wait(&stop_rx, delay))?;
+        let tcp = client.tcp_port().unwrap_or(0);
+        let ports = Ports { udp, tcp };
+        let shared = Arc::new(Shared {
+            ports: Mutex::new(Some(ports)),
+            subscribers: Mutex::new(Vec::new()),
+            http_port: Mutex::new(Some(process.http_port())),
+            service_name: Mutex::new(config.service_name.clone()),
+            patterns: Mutex::new(None),
+            subscribed: Mutex::new(Vec::new()),
+        });
+
+        let handle_signals = options.handle_signals;
+        let mut watchdog = Watchdog {
+            config_modified: config.path().and_then(modified_time),
+            service_name: config.service_name.clone(),
+            config,
+            options,
+            process: Some(process),
+            client,
+            udp_port,
+            last_ports: ports,
+            advertiser: None,
+            expander: None,
+            published: None,
+            shared: Arc::clone(&shared),
+            stop_rx,
+        };
+        // Claim the mDNS name before returning, so `info()` has the right one from the start
+        watchdog.update_advertiser();
+        watchdog.sync_service_name();
+        // Without its thread the watchdog is dropped right here, which stops the helper we just brought up
+        let thread = thread::Builder::new()
+            .name("oscq-supervisor".into())
+            .spawn(move || watchdog.run())
+            .map_err(|source| OscqError::ThreadSpawn { name: "supervisor", source })?;
+
+        let control = Arc::new(Control {
+            stop_tx,
+            thread: Mutex::new(Some(thread)),
+        });
+        if handle_signals {
+            shutdown_on_signal(&control);
+        }
+
+        Ok(Supervisor { shared, control, socket: socket.map(|(socket, _)| socket) })
+    }
+
+    // Current ports, or None while the server is being restarted
+    pub fn ports(&self) -> Option<Ports> {
+        *self.shared.ports.lock().unwrap()
+    }
+
+    pub fn udp_port(&self) -> Option<u16> {
+        self.ports().map(|ports| ports.udp)
+    }
+
+    pub fn tcp_port(&self) -> Option<u16> {
+        self.ports().map(|ports| ports.tcp)
+    }
+
+    // The service as it is currently offered: its ports, the helper's HTTP port and the name it is advertised under,
+    // which differs from `serviceName` if that was taken on the network. None while the server is being restarted.
+    pub fn info(&self) -> Option<OscqInfo> {
+        let ports = self.ports()?;
+        Some(OscqInfo {
+            tcp_port: Some(ports.tcp),
+            udp_port: Some(ports.udp),
+            http_port: *self.shared.http_port.lock().unwrap(),
+            service_name: Some(self.shared.service_name.lock().unwrap().clone()),
+        })
+    }
+
+    // With `bind_udp`: a handle to the already bound OSC socket, together with its port.
+    // The port stays the same across helper restarts, so unlike `ports()` it never needs rebinding.
+    pub fn udp_socket(&self) -> Option<(UdpSocket, u16)> {
+        let socket = self.socket.as_ref()?.try_clone().ok()?;
+        let port = socket.local_addr().ok()?.port();
+        Some((socket, port))
+    }
+
+    // Have the helper publish only the endpoints matching `patterns`, e.g. "/avatar/parameters/Giggletech_*" and
+    // "/avatar/change", out of the current avatar's parameters, so VRChat sends nothing else. Replaces any earlier
+    // subscription; the published tree follows within `poll_interval`.
+    pub fn subscribe<I, S>(&self, patterns: I) -> Result<(), OscqError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let patterns: Vec<String> = patterns.into_iter().map(Into::into).collect();
+        for pattern in &patterns {
+            check_pattern(pattern)?;
+        }
+        *self.shared.patterns.lock().unwrap() = Some(patterns);
+        Ok(())
+    }
+
+    // Go back to publishing the catch-all `/avatar` endpoint
+    pub fn unsubscribe(&self) {
+        *self.shared.patterns.lock().unwrap() = None;
+    }
+
+    // Addresses of the endpoints currently published for the subscription, empty without one
+    pub fn subscribed(&self) -> Vec<String> {
+        self.shared.subscribed.lock().unwrap().clone()
+    }
+
+    // New receiver for supervisor events; every receiver gets every event sent after it was created
+    pub fn events(&self) -> EventReceiver<SupervisorEvent> {
+        let (tx, rx) = unbounded();
+        self.shared.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    // Stop supervising and shut the helper down, waiting until that is done. Dropping the handle does the same.
+    pub fn shutdown(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.control.stop();
+    }
+}
+
+// Stops the watchdog thread; shared between the handle and the signal handler
+struct Control {
+    stop_tx: Sender<()>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Control {
+    // Tell the watchdog to shut the helper down, and wait until it has
+    fn stop(&self) {
+        let _ = self.stop_tx.send(());
+        let thread = self.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Supervisors to stop when a termination signal arrives
+static SIGNAL_TARGETS: Mutex<Vec<Weak<Control>>> = Mutex::new(Vec::new());
+static SIGNAL_HANDLER: Once = Once::new();
+
+// Make Ctrl-C / SIGINT / SIGTERM stop `control` before the process exits.
+// The handler is installed once per process and serves every supervisor registered here.
+fn shutdown_on_signal(control: &Arc<Control>) {
+    {
+        let mut targets = SIGNAL_TARGETS.lock().unwrap();
+        targets.retain(|target| target.strong_count() > 0);
+        targets.push(Arc::downgrade(control));
+    }
+
+    SIGNAL_HANDLER.call_once(|| {
+        let installed = ctrlc::set_handler(|| {
+            log_info!("Termination requested, shutting down the OSCQ Server...");
+            let targets = std::mem::take(&mut *SIGNAL_TARGETS.lock().unwrap());
+            for control in targets.iter().filter_map(Weak::upgrade) {
+                control.stop();
+            }
+            std::process::exit(130);
+        });
+        if let Err(e) = installed {
+            log_warn!("Could not install a termination handler, the OSCQ Server won't be stopped on Ctrl-C: {}", e);
+        }
+    });
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Sleep for `delay`; returns false if the supervisor was stopped in the meantime
+fn wait(stop_rx: &Receiver<()>, delay: Duration) -> bool {
+    matches!(stop_rx.recv_timeout(delay), Err(RecvTimeoutError::Timeout))
+}
+
+// State shared between the handle and the background thread
+struct Shared {
+    ports: Mutex<Option<Ports>>,
+    subscribers: Mutex<Vec<EventSender<SupervisorEvent>>>,
+    // Port of the current helper's HTTP listener
+    http_port: Mutex<Option<u16>>,
+    // Name the service is advertised under
+    service_name: Mutex<String>,
+    // Patterns passed to `subscribe()`, None without a subscription
+    patterns: Mutex<Option<Vec<String>>>,
+    // Addresses published for them
+    subscribed: Mutex<Vec<String>>,
+}
+
+impl Shared {
+    // Send `event` to every live receiver, forgetting the ones that have been dropped
+    fn publish(&self, event: SupervisorEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+// State owned by the background thread
+struct Watchdog {
+    config: Config,
+    // Modification time of the config file when it was last read
+    config_modified: Option<SystemTime>,
+    options: SupervisorOptions,
+    process: Option<HelperProcess<Child>>,
+    // Client for the current helper, or for the configured port while there is none
+    client: HelperClient,
+    // Port of the socket bound with `bind_udp`, which every helper is told to advertise
+    udp_port: Option<u16>,
+    // Last ports the helper reported, kept while restarting so changes can be detected
+    last_ports: Ports,
+    // Service name the helper is using
+    service_name: String,
+    // With `advertise`, advertises the helper's service while we have one we started
+    advertiser: Option<Advertiser>,
+    // With a subscription, finds the endpoints to publish
+    expander: Option<Expander>,
+    // Tree last published on the current helper, None if it has the default `/avatar`
+    published: Option<OscQueryNode>,
+    shared: Arc<Shared>,
+    stop_rx: Receiver<()>,
+}
+
+impl Watchdog {
+    fn run(mut self) {
+        let mut unresponsive_since: Option<Instant> = None;
+
+        while wait(&self.stop_rx, self.options.poll_interval) {
+            if !self.check_config() {
+                break;
+            }
+            self.sync_service_name();
+
+            if self.has_exited() {
+                unresponsive_since = None;
+                if !self.restart() {
+                    break;
+                }
+                continue;
+            }
+
+            match self.client.udp_port() {
+                Ok(0) => {
+                    // The process is up but its OSCQuery service isn't; start it again
+                    log_info!("UDP port is 0, sending start command...");
+                    self.set_ports(None);
+                    if let Err(e) = self.client.start_with(None, self.udp_port) {
+                        log_warn!("Failed to start server: {}", e);
+                    }
+                    unresponsive_since = None;
+                }
+                Ok(udp) => {
+                    let tcp = self.client.tcp_port().unwrap_or(0);
+                    self.set_ports(Some(Ports { udp, tcp }));
+                    unresponsive_since = None;
+                    self.update_subscription();
+                }
+                Err(e) => {
+                    let since = *unresponsive_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= self.options.hang_timeout {
+                        log_warn!("OSCQ Server stopped responding ({}), restarting giggletech process...", e);
+                        unresponsive_since = None;
+                        if !self.restart() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        // Dropping the watchdog shuts the helper down
+    }
+
+    // Reload the config file if it was modified since we last read it, and apply whatever changed.
+    // Returns false if the supervisor was stopped while doing so.
+    fn check_config(&mut self) -> bool {
+        let path = match self.config.path() {
+            Some(path) => path.to_path_buf(),
+            // Loaded without a file: watch for one to turn up on the search path
+            None if self.config.searched() => match locate_config(None) {
+                Ok(Some(location)) => location.path,
+                _ => return true,
+            },
+            None => return true,
+        };
+        let modified = modified_time(&path);
+        if modified == self.config_modified {
+            return true;
+        }
+        self.config_modified = modified;
+
+        let config = match self.config.reload() {
+            Ok(config) => config,
+            Err(e) => {
+                log_warn!("Ignoring the changes to {}: {}", path.display(), e);
+                return true;
+            }
+        };
+
+        let changes = self.config.changes(&config);
+        let mut relaunch = false;
+        let mut rename = false;
+        for SettingChange { key, old, new } in changes {
+            log_info!("Config changed: {} {} -> {}", key, old, new);
+            match key {
+                // The helper only reads these when it is launched
+                "httpPort" | "httpPortFallback" | "launchMode" | "helperPath" | "helperArgs" => relaunch = true,
+                "serviceName" => rename = true,
+                "pollInterval" => {
+                    self.options.poll_interval = config.poll_interval;
+                    if let Some(expander) = &self.expander {
+                        expander.set_interval(config.poll_interval);
+                    }
+                }
+                "hangTimeout" => self.options.hang_timeout = config.hang_timeout,
+                "shutdownGrace" => self.options.shutdown_grace = config.shutdown_grace,
+                "startupTimeout" => self.options.retry.deadline = config.startup_timeout,
+                "startupGrace" => self.options.retry.startup_grace = config.startup_grace,
+                "initialBackoff" => self.options.retry.initial_backoff = config.initial_backoff,
+                "maxBackoff" => self.options.retry.max_backoff = config.max_backoff,
+                "backoffMultiplier" => self.options.retry.multiplier = config.backoff_multiplier,
+                "backoffJitter" => self.options.retry.jitter = config.backoff_jitter,
+                "maxAttempts" => self.options.retry.max_attempts = config.max_attempts,
+                "logLevel" => logging::set_level(config.log_level),
+                _ => {}
+            }
+            self.shared.publish(SupervisorEvent::ConfigChanged { key, old, new });
+        }
+        self.config = config;
+
+        if relaunch {
+            log_info!("Restarting the OSCQ Server with the new configuration...");
+            self.shutdown_helper();
+            return self.restart();
+        }
+        if rename && self.advertiser.is_some() {
+            // Withdraw the old name before claiming the new one
+            self.advertiser = None;
+            self.update_advertiser();
+        }
+        self.sync_service_name();
+        true
+    }
+
+    // Keep the helper's service name the same as the advertised one, which is `serviceName` unless the advertiser had to
+    // pick another after a name conflict
+    fn sync_service_name(&mut self) {
+        let name = match &self.advertiser {
+            Some(advertiser) => advertiser.name(),
+            None => self.config.service_name.clone(),
+        };
+        if name == self.service_name {
+            return;
+        }
+
+        // The helper restarts the service under the new name, which also gives it new ports
+        log_info!("Restarting the OSCQuery service as {:?}...", name);
+        self.set_ports(None);
+        if let Err(e) = self.client.start_with(Some(&name), self.udp_port) {
+            log_warn!("Failed to start server: {}", e);
+        }
+        *self.shared.service_name.lock().unwrap() = name.clone();
+        self.service_name = name;
+    }
+
+    // With `advertise`, advertise the service while the helper is one we started.
+    // An adopted helper was started by someone else, and probably advertises itself.
+    fn update_advertiser(&mut self) {
+        let spawned = matches!(self.process, Some(HelperProcess::Spawned { .. }));
+        if !self.options.advertise || spawned == self.advertiser.is_some() {
+            return;
+        }
+        self.advertiser = None;
+        if spawned {
+            let Ports { tcp, udp } = self.last_ports;
+            self.advertiser = Advertiser::start(&self.config.service_name, tcp, udp)
+                .map_err(|e| log_error!("Failed to advertise the OSCQuery service over mDNS: {}", e))
+                .ok();
+        }
+    }
+
+    // Publish the endpoints the subscription was last expanded to, if they differ from what the helper has
+    fn update_subscription(&mut self) {
+        let patterns = self.shared.patterns.lock().unwrap().clone();
+        let Some(patterns) = patterns else {
+            if self.expander.take().is_some() || self.published.is_some() {
+                log_info!("Unsubscribed, publishing /avatar again");
+                match self.client.reset_endpoints() {
+                    Ok(()) => self.published = None,
+                    Err(e) => log_warn!("Failed to reset the published endpoints: {}", e),
+                }
+                self.shared.subscribed.lock().unwrap().clear();
+            }
+            return;
+        };
+
+        let expander = match &self.expander {
+            Some(expander) => {
+                expander.set_patterns(patterns);
+                expander
+            }
+            None => match Expander::start(patterns, self.options.poll_interval) {
+                Ok(expander) => self.expander.insert(expander),
+                Err(e) => {
+                    // Try again on the next poll
+                    log_warn!("Failed to start expanding the subscription: {}", e);
+                    return;
+                }
+            },
+        };
+        let Some(tree) = expander.latest() else {
+            return;
+        };
+        if self.published.as_ref() == Some(&tree) {
+            return;
+        }
+
+        let paths: Vec<String> = tree.endpoints().iter().map(|node| node.full_path().to_string()).collect();
+        log_info!("Publishing {} endpoint(s): {}", paths.len(), paths.join(", "));
+        match self.client.set_endpoints(&tree) {
+            Ok(()) => {
+                self.published = Some(tree);
+                *self.shared.subscribed.lock().unwrap() = paths;
+            }
+            Err(e) => log_warn!("Failed to publish the subscribed endpoints: {}", e),
+        }
+    }
+
+    // Stop the helper on the way out, leaving an adopted one alone unless asked to stop it too
+    fn shutdown_helper(&mut self) {
+        match self.process.take() {
+            Some(HelperProcess::Adopted { .. }) if !self.options.stop_adopted_helper => {}
+            Some(mut process) => process.shutdown(&self.client, self.options.shutdown_grace),
+            None => {}
+        }
+        self.set_ports(None);
+    }
+
+    // True if there is no process, or the process we started has exited since the last check
+    fn has_exited(&mut self) -> bool {
+        match &mut self.process {
+            None => true,
+            Some(HelperProcess::Spawned { process, .. }) => match process.try_wait() {
+                Ok(Some(status)) => {
+                    log_warn!("giggletech process exited ({}), restarting...", status);
+                    true
+                }
+                _ => false,
+            },
+            Some(HelperProcess::Adopted { .. }) => false,
+        }
+    }
+
+    // Kill the current process (if any) and bring up a new one.
+    // Returns false if the supervisor was stopped while doing so.
+    fn restart(&mut self) -> bool {
+        self.set_ports(None);
+        match self.process.take() {
+            // We can't kill a helper we didn't start; ask it to exit so a new one can take the port
+            Some(HelperProcess::Adopted { .. }) if self.options.stop_adopted_helper => {
+                let _ = self.client.stop();
+            }
+            // Or leave it running, and adopt it again once it answers
+            Some(HelperProcess::Adopted { http_port }) => {
+                log_info!("Waiting for the adopted OSCQ Server on port {} to answer again...", http_port);
+            }
+            Some(mut process) => process.kill(),
+            None => {}
+        }
+
+        let stop_rx = &self.stop_rx;
+        let mdns = !self.options.advertise;
+        match bring_up(&self.config, &self.options.retry, self.udp_port, mdns, &mut |delay| wait(stop_rx, delay)) {
+            Ok((process, client, udp)) => {
+                let tcp = client.tcp_port().unwrap_or(0);
+                *self.shared.http_port.lock().unwrap() = Some(process.http_port());
+                self.process = Some(process);
+                self.client = client;
+                // A new helper starts out with the name from the config, and publishing /avatar
+                self.service_name = self.config.service_name.clone();
+                self.published = None;
+                self.set_ports(Some(Ports { udp, tcp }));
+                self.update_advertiser();
+                true
+            }
+            Err(OscqError::Cancelled) => false,
+            Err(e) => {
+                // Try again on the next poll
+                log_error!("Failed to restart the OSCQuery server: {}", e);
+                true
+            }
+        }
+    }
+
+    fn set_ports(&mut self, ports: Option<Ports>) {
+        *self.shared.ports.lock().unwrap() = ports;
+
+        if let Some(new) = ports {
+            let old = self.last_ports;
+            if new != old {
+                log_info!(
+                    "OSC ports changed: UDP {} -> {}, TCP {} -> {}",
+                    old.udp, new.udp, old.tcp, new.tcp
+                );
+                self.last_ports = new;
+                if let Some(advertiser) = &self.advertiser {
+                    advertiser.set_ports(new.tcp, new.udp);
+                }
+                self.shared.publish(SupervisorEvent::PortsChanged { old, new });
+            }
+        }
+    }
+}
+
+// However the watchdog goes away, the helper goes with it
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shutdown_helper();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::oscq_giggletech::testing::{config_file, helper_config, remove_config_dir, FakeHelper, ENV_LOCK};
+
+    // How long a test waits for the watchdog to react
+    const TIMEOUT: Duration = Duration::from_secs(15);
+
+    // Options that notice a helper going away within a fraction of a second
+    fn quick_options(config: &Config) -> SupervisorOptions {
+        let mut options = SupervisorOptions::from(config);
+        options.poll_interval = Duration::from_millis(50);
+        options.hang_timeout = Duration::from_millis(300);
+        options.retry.initial_backoff = Duration::from_millis(50);
+        options.retry.max_backoff = Duration::from_millis(200);
+        options
+    }
+
+    // Poll `condition` until it holds, for up to `timeout`
+    fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !condition() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        true
+    }
+
+    // Send one of the fake helper's own commands; it may not live to answer
+    fn send(supervisor: &Supervisor, command: &str) {
+        let http_port = supervisor.info().unwrap().http_port.unwrap();
+        let _ = reqwest::blocking::get(HelperClient::new(http_port).unwrap().url(command));
+    }
+
+    // Wait for the next PortsChanged, skipping any other events
+    fn next_ports_change(events: &EventReceiver<SupervisorEvent>) -> (Ports, Ports) {
+        loop {
+            match events.recv_timeout(TIMEOUT) {
+                Ok(SupervisorEvent::PortsChanged { old, new }) => return (old, new),
+                Ok(_) => {}
+                Err(e) => panic!("no PortsChanged: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn restarts_a_helper_that_exits() {
+        let config = helper_config("supervisor-exit", "");
+        let supervisor = Supervisor::start_with_config(config.clone(), quick_options(&config)).unwrap();
+        let events = supervisor.events();
+        let before = supervisor.ports().unwrap();
+
+        send(&supervisor, "crash");
+        let (old, new) = next_ports_change(&events);
+        assert_eq!(old, before);
+        assert_ne!(new, before);
+        assert_eq!(supervisor.ports(), Some(new));
+
+        drop(supervisor);
+        remove_config_dir(&config);
+    }
+
+    #[test]
+    fn restarts_a_helper_that_stops_answering() {
+        let config = helper_config("supervisor-hang", "");
+        let supervisor = Supervisor::start_with_config(config.clone(), quick_options(&config)).unwrap();
+        let events = supervisor.events();
+        let before = supervisor.ports().unwrap();
+
+        send(&supervisor, "hang");
+        let (old, new) = next_ports_change(&events);
+        assert_eq!(old, before);
+        assert_ne!(new, before);
+        assert_eq!(supervisor.ports(), Some(new));
+        assert_eq!(supervisor.info().unwrap().http_port, Some(config.http_port));
+
+        drop(supervisor);
+        remove_config_dir(&config);
+    }
+
+    #[test]
+    fn reports_every_move_of_the_ports() {
+        let helper = FakeHelper::started("Giggletech");
+        let mut config = Config::default();
+        config.http_port = helper.http_port();
+        let supervisor = Supervisor::start_with_config(config.clone(), quick_options(&config)).unwrap();
+        let events = supervisor.events();
+        let first = supervisor.ports().unwrap();
+
+        // Moved by someone else
+        let moved = Ports { udp: first.udp + 1, tcp: first.tcp + 1 };
+        helper.state().set_ports(moved.udp, moved.tcp);
+        assert_eq!(next_ports_change(&events), (first, moved));
+
+        // Stopped: the watchdog starts it again, on new ports
+        helper.state().set_ports(0, 0);
+        let (old, new) = next_ports_change(&events);
+        assert_eq!(old, moved);
+        let state = helper.state();
+        assert_eq!(new, Ports { udp: state.udp_port, tcp: state.tcp_port });
+        drop(state);
+
+        // An adopted helper is left running
+        drop(supervisor);
+        assert_eq!(helper.state().udp_port, new.udp);
+    }
+
+    #[test]
+    fn waits_for_an_adopted_helper_that_stops_answering() {
+        let helper = FakeHelper::started("Giggletech");
+        let mut config = Config::default();
+        config.http_port = helper.http_port();
+        let supervisor = Supervisor::start_with_config(config.clone(), quick_options(&config)).unwrap();
+        let before = supervisor.ports().unwrap();
+
+        // Busy for longer than the hang timeout
+        helper.state().silent = true;
+        assert!(wait_until(TIMEOUT, || supervisor.ports().is_none()));
+        helper.state().silent = false;
+
+        // Adopted again, still running on its old ports: it wasn't asked to stop
+        assert!(wait_until(TIMEOUT, || supervisor.ports().is_some()));
+        assert_eq!(supervisor.ports(), Some(before));
+        let state = helper.state();
+        assert_eq!(Ports { udp: state.udp_port, tcp: state.tcp_port }, before);
+    }
+
+    #[test]
+    fn applies_changes_to_the_config_file() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let helper = FakeHelper::started("Giggletech");
+        let settings = format!("version: 2\nhttpPort: {}\npollInterval: 0.05\n", helper.http_port());
+        let path = config_file("supervisor-reload", &settings);
+        let config = Config::from_file(&path).unwrap();
+        let supervisor = Supervisor::start_with_config(config.clone(), SupervisorOptions::from(&config)).unwrap();
+        let events = supervisor.events();
+        // The next ConfigChanged, skipping the PortsChanged of a rename
+        let next_change = || loop {
+            match events.recv_timeout(TIMEOUT) {
+                Ok(SupervisorEvent::ConfigChanged { key, new, .. }) => return (key, new),
+                Ok(_) => {}
+                Err(e) => panic!("no ConfigChanged: {}", e),
+            }
+        };
+
+        fs::write(&path, format!("{}hangTimeout: 7\nserviceName: Living Room\n", settings)).unwrap();
+        assert_eq!(next_change(), ("serviceName", "Living Room".to_string()));
+        assert_eq!(next_change(), ("hangTimeout", "7.0".to_string()));
+        assert!(wait_until(TIMEOUT, || helper.state().service_name == "Living Room"));
+        assert_eq!(supervisor.info().unwrap().service_name.as_deref(), Some("Living Room"));
+
+        // A file that doesn't parse is ignored until it is fixed
+        fs::write(&path, format!("{}hangTimeout: soon\n", settings)).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        fs::write(&path, format!("{}hangTimeout: 8\nserviceName: Living Room\n", settings)).unwrap();
+        assert_eq!(next_change(), ("hangTimeout", "8.0".to_string()));
+
+        drop(supervisor);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
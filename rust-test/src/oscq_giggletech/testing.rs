@@ -0,0 +1,75 @@
+/*
+    Fixtures shared by the tests of several modules.
+
+    - `ENV_LOCK` keeps the tests that set `GIGGLETECH_*` variables apart from those that read them. `TestEnv` holds it
+      while a test changes the environment, and puts the variables back afterwards.
+    - `config_file` writes a config file into a directory of its own.
+    - `FakeHelper` (see `testing/fake_helper.rs`) stands in for `giggletech_oscq.exe`, in the test process or as a
+      process of its own.
+*/
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+use super::CONFIG_FILE_NAME;
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+mod fake_helper;
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(super) use fake_helper::{helper_config, remove_config_dir, FakeHelper};
+
+// Held by the tests that set `GIGGLETECH_*` variables, and by those reading them through `Config::read` or `reload`
+pub(super) static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+// The environment of one test: holds `ENV_LOCK`, and when dropped restores every variable changed through it, even if
+// the test failed half-way
+pub(super) struct TestEnv {
+    // Variables changed so far, with the values they had before
+    saved: Vec<(String, Option<OsString>)>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TestEnv {
+    pub(super) fn lock() -> TestEnv {
+        TestEnv { saved: Vec::new(), _lock: ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner()) }
+    }
+
+    pub(super) fn set(&mut self, name: &str, value: impl AsRef<OsStr>) {
+        self.save(name);
+        std::env::set_var(name, value);
+    }
+
+    pub(super) fn remove(&mut self, name: &str) {
+        self.save(name);
+        std::env::remove_var(name);
+    }
+
+    fn save(&mut self, name: &str) {
+        if !self.saved.iter().any(|(saved, _)| saved == name) {
+            self.saved.push((name.to_string(), std::env::var_os(name)));
+        }
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        for (name, value) in self.saved.drain(..) {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+    }
+}
+
+// A config file holding `text`, in a directory of its own
+pub(super) fn config_file(name: &str, text: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("giggletech-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(CONFIG_FILE_NAME);
+    fs::write(&path, text).unwrap();
+    path
+}
@@ -0,0 +1,327 @@
+/*
+    A stand-in for `giggletech_oscq.exe`.
+
+    `FakeHelper` is a small HTTP server on localhost that answers the helper's commands the way the C# helper does.
+    `/start` picks new random ports (or advertises `udpPort`) and takes on `serviceName`, `/stop` sets both ports back to
+    0, `/info` answers with the banner and "Key: Value" lines, and `POST /endpoints` keeps the tree it was sent.
+    Every request is recorded, and a test can change what the helper reports (its ports, or whether it answers at all)
+    through `state()`.
+
+    It runs in one of two ways:
+    - In the test process, with `FakeHelper::start`, for tests that adopt a running helper.
+    - As a process of its own, started like the real helper with a config from `helper_config`: this test binary is run
+      with `fake_helper_process` as its only test, which serves on the `--http-port` it was given and exits on `/stop`.
+      `/crash` makes it exit right away and `/hang` makes it stop answering, and its `/info` also reports the working
+      directory and `--config` it was started with.
+*/
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde_yaml::{Mapping, Value};
+
+use super::config_file;
+use crate::oscq_giggletech::{free_local_port, Config, HELPER_BANNER};
+
+// Full name of the test that runs the fake helper as a process of its own
+const PROCESS_TEST: &str = "oscq_giggletech::testing::fake_helper::fake_helper_process";
+
+pub(crate) struct FakeHelper {
+    state: Arc<Mutex<State>>,
+    http_port: u16,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// What the fake helper reports, and what it has been asked
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    pub service_name: String,
+    // 0 while the service isn't started, like the real helper
+    pub udp_port: u16,
+    pub tcp_port: u16,
+    // Ignore `/start`, like a helper whose service fails to start
+    pub broken: bool,
+    // Close every connection without answering, after recording the request
+    pub silent: bool,
+    // Accept connections but never answer them
+    pub hung: bool,
+    // Body of the last `POST /endpoints`, None for the default `/avatar`
+    pub endpoints: Option<String>,
+    // Every request served, e.g. "GET /start?serviceName=Home"
+    pub requests: Vec<String>,
+    // Running as a process of its own: exit on `/stop`, and report how it was launched
+    process: Option<Launch>,
+}
+
+#[derive(Debug)]
+struct Launch {
+    working_dir: PathBuf,
+    config: Option<String>,
+}
+
+impl FakeHelper {
+    // Serve on a free port, with the service not started yet
+    pub fn start(service_name: &str) -> FakeHelper {
+        FakeHelper::start_on(0, service_name)
+    }
+
+    pub fn start_on(http_port: u16, service_name: &str) -> FakeHelper {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, http_port)).unwrap();
+        let http_port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(State { service_name: service_name.to_string(), ..State::default() }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let state = Arc::clone(&state);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || serve(listener, &state, &stop))
+        };
+        FakeHelper { state, http_port, stop, thread: Some(thread) }
+    }
+
+    // Serve with the service already started, on new random ports
+    pub fn started(service_name: &str) -> FakeHelper {
+        let helper = FakeHelper::start(service_name);
+        helper.state().start(None, None);
+        helper
+    }
+
+    pub fn http_port(&self) -> u16 {
+        self.http_port
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn requests(&self) -> Vec<String> {
+        self.state().requests.clone()
+    }
+}
+
+impl Drop for FakeHelper {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the accept loop so it sees the flag
+        let _ = TcpStream::connect((Ipv4Addr::LOCALHOST, self.http_port));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl State {
+    // What `/start` does: take on the name and UDP port if given, and move to new random ports otherwise
+    fn start(&mut self, service_name: Option<String>, udp_port: Option<u16>) {
+        if let Some(service_name) = service_name {
+            self.service_name = service_name;
+        }
+        let old = (self.udp_port, self.tcp_port);
+        while (self.udp_port, self.tcp_port) == old {
+            self.udp_port = udp_port.unwrap_or_else(|| fastrand::u16(20000..60000));
+            self.tcp_port = fastrand::u16(20000..60000);
+        }
+    }
+
+    // Move the service to other ports, as a restart by someone else would
+    pub fn set_ports(&mut self, udp_port: u16, tcp_port: u16) {
+        (self.udp_port, self.tcp_port) = (udp_port, tcp_port);
+    }
+
+    fn info(&self, http_port: u16) -> String {
+        let mut info = format!(
+            "{}\nTCP Port: {}\nUDP Port: {}\nHTTP Listener Port: {}\nService Name: {}",
+            HELPER_BANNER, self.tcp_port, self.udp_port, http_port, self.service_name
+        );
+        if let Some(launch) = &self.process {
+            info += &format!("\nWorking Directory: {}", launch.working_dir.display());
+            info += &format!("\nConfig: {}", launch.config.as_deref().unwrap_or_default());
+        }
+        info
+    }
+}
+
+fn serve(listener: TcpListener, state: &Mutex<State>, stop: &AtomicBool) {
+    // Connections of a hung helper, kept open so the client waits for an answer that never comes
+    let mut held = Vec::new();
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let http_port = listener.local_addr().map(|address| address.port()).unwrap_or_default();
+        let (silent, hung) = {
+            let state = state.lock().unwrap();
+            (state.silent, state.hung)
+        };
+        if hung {
+            held.push(stream);
+        } else if silent {
+            ignore(stream, state);
+        } else {
+            handle(stream, state, http_port);
+        }
+    }
+}
+
+// Record the request, and hang up without answering it
+fn ignore(stream: TcpStream, state: &Mutex<State>) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let mut request_line = String::new();
+    if BufReader::new(&stream).read_line(&mut request_line).is_ok() {
+        let request: Vec<&str> = request_line.split_whitespace().take(2).collect();
+        state.lock().unwrap().requests.push(request.join(" "));
+    }
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>, http_port: u16) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).unwrap_or(0) == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    if reader.read_exact(&mut body).is_err() {
+        return;
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| decode(value))
+    };
+
+    let mut state = state.lock().unwrap();
+    state.requests.push(format!("{} {}", method, target));
+    let mut exit = None;
+    let reply = match path.trim_matches('/').to_ascii_lowercase().as_str() {
+        "start" if state.broken => "Service started...".to_string(),
+        "start" => {
+            let udp_port = param("udpPort").and_then(|port| port.parse().ok());
+            state.start(param("serviceName"), udp_port);
+            "Service started...".to_string()
+        }
+        "stop" => {
+            state.set_ports(0, 0);
+            exit = state.process.as_ref().map(|_| 0);
+            "Service stopped. Application shutting down...".to_string()
+        }
+        "info" => state.info(http_port),
+        "port_udp" => state.udp_port.to_string(),
+        "port_tcp" => state.tcp_port.to_string(),
+        "endpoints" => {
+            let body = String::from_utf8_lossy(&body).into_owned();
+            state.endpoints = Some(body).filter(|body| !body.trim().is_empty());
+            "Publishing".to_string()
+        }
+        "crash" if state.process.is_some() => std::process::exit(1),
+        "hang" => {
+            state.silent = true;
+            String::new()
+        }
+        _ => "Unknown command".to_string(),
+    };
+    drop(state);
+
+    let _ = write!(
+        &stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        reply.len(),
+        reply
+    );
+    if let Some(code) = exit {
+        let _ = (&stream).flush();
+        std::process::exit(code);
+    }
+}
+
+// Undo the URL encoding of a query value
+fn decode(value: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = input.by_ref().take(2).collect();
+                let hex = std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                bytes.push(hex.unwrap_or(b'?'));
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Settings that launch the fake helper as a process of its own on `http_port`, as lines of a config file
+fn helper_settings(http_port: u16) -> String {
+    let exe = std::env::current_exe().unwrap();
+    let args = [PROCESS_TEST, "--exact", "--ignored", "--quiet", "--test-threads=1", "--"];
+    let settings = Mapping::from_iter([
+        (Value::from("httpPort"), Value::from(http_port)),
+        (Value::from("launchMode"), Value::from("command")),
+        (Value::from("helperPath"), Value::from(exe.to_string_lossy().into_owned())),
+        (Value::from("helperArgs"), Value::Sequence(args.into_iter().map(Value::from).collect())),
+    ]);
+    serde_yaml::to_string(&settings).unwrap()
+}
+
+// Config file launching the fake helper on a free port, with `extra` settings after it
+pub(crate) fn helper_config(name: &str, extra: &str) -> Config {
+    let text = helper_settings(free_local_port().unwrap()) + extra;
+    Config::from_file(&config_file(name, &text)).unwrap()
+}
+
+pub(crate) fn remove_config_dir(config: &Config) {
+    if let Some(dir) = config.path().and_then(Path::parent) {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+// The fake helper as a process of its own. Only does anything when launched like the helper, with `--http-port`.
+#[test]
+#[ignore = "run as the helper by the tests that launch one"]
+fn fake_helper_process() {
+    let args: Vec<String> = std::env::args().collect();
+    let value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned();
+    let Some(http_port) = value("--http-port").and_then(|port| port.parse().ok()) else {
+        return;
+    };
+
+    let helper = FakeHelper::start_on(http_port, &value("--service-name").unwrap_or_default());
+    helper.state().process = Some(Launch {
+        working_dir: std::env::current_dir().unwrap_or_default(),
+        config: value("--config"),
+    });
+    // Serves until `/stop` or `/crash` exits the process
+    loop {
+        thread::park();
+    }
+}
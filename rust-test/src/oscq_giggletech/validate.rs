@@ -0,0 +1,323 @@
+/*
+    Validation of `config_oscq.yml`.
+
+    Instead of stopping at the first thing serde doesn't like, the config is checked in passes and every problem found is
+    returned together, each with the key and (where it can be worked out) the line and column it is on:
+    1. YAML syntax: reported with the position the parser gave up at. Nothing else can be checked after this.
+    2. Unknown keys: anything that isn't one of the settings in `config.rs`. The helper's YAML library uses an
+       underscored naming convention, which makes `http_port` an easy mistake; such near-misses get a suggestion.
+    3. Types: each known key on its own, so one bad value doesn't hide another.
+    4. Values: HTTP ports outside 1024 - 65535 (the ones below are privileged and the helper can't bind them without
+       admin rights), empty service names or ones too long to advertise over mDNS, and retry settings that make no
+       sense.
+
+    The value checks also run on a config assembled from the environment and command line (`Config::validate`).
+*/
+
+use std::fmt;
+use std::path::PathBuf;
+use serde_yaml::{Mapping, Value};
+
+use super::config::SETTINGS;
+use super::dns::MAX_LABEL;
+use super::{Config, OscqError, CONFIG_VERSION};
+
+// Lowest port the helper may listen on without admin rights
+const MIN_HTTP_PORT: i64 = 1024;
+
+// Largest growth of the poll delay; anything more reaches maxBackoff after one attempt anyway
+const MAX_BACKOFF_MULTIPLIER: f64 = 10.0;
+
+// One thing wrong with a config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    // Setting the problem is about, if any
+    pub key: Option<String>,
+    // 1-based position in the file, when known
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "line {}, column {}: ", line, column)?,
+            (Some(line), None) => write!(f, "line {}: ", line)?,
+            _ => {}
+        }
+        match &self.key {
+            Some(key) => write!(f, "{}: {}", key, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl ConfigProblem {
+    fn new(key: Option<&str>, message: impl Into<String>) -> Self {
+        ConfigProblem { key: key.map(str::to_string), line: None, column: None, message: message.into() }
+    }
+}
+
+// Why a config was rejected: every problem found, and the YAML parser's error for the first setting it couldn't read
+#[derive(Debug, Default)]
+pub(super) struct Rejection {
+    pub(super) problems: Vec<ConfigProblem>,
+    pub(super) source: Option<serde_yaml::Error>,
+}
+
+impl Rejection {
+    pub(super) fn into_error(self, path: Option<PathBuf>) -> OscqError {
+        OscqError::ConfigInvalid { path, problems: self.problems, source: self.source }
+    }
+}
+
+// Parse and check the text of a config file, returning every problem found
+pub(super) fn parse_config(text: &str) -> Result<Config, Rejection> {
+    // Pass 1: syntax
+    let fields = match serde_yaml::from_str::<Value>(text) {
+        Ok(Value::Mapping(fields)) => fields,
+        // An empty file is a valid config that sets nothing
+        Ok(Value::Null) => Mapping::new(),
+        Ok(_) => {
+            let problems = vec![ConfigProblem::new(None, "expected `key: value` lines")];
+            return Err(Rejection { problems, source: None });
+        }
+        Err(e) => {
+            let location = e.location();
+            let mut message = e.to_string();
+            // serde_yaml puts " at line X column Y" in the message, which we report separately
+            if let Some(location) = &location {
+                message = message.replacen(&format!(" at line {} column {}", location.line(), location.column()), "", 1);
+            }
+            let problem = ConfigProblem {
+                key: None,
+                line: location.as_ref().map(|location| location.line()),
+                column: location.as_ref().map(|location| location.column()),
+                message,
+            };
+            return Err(Rejection { problems: vec![problem], source: Some(e) });
+        }
+    };
+
+    let mut problems = Vec::new();
+    let mut source = None;
+    let mut known = Mapping::new();
+    for (key, value) in fields {
+        let Some(name) = key.as_str() else {
+            problems.push(ConfigProblem::new(None, format!("key {:?} is not text", key)));
+            continue;
+        };
+
+        // Pass 2: unknown keys
+        if !SETTINGS.contains(&name) && name != "version" {
+            let message = match suggestion(name) {
+                Some(setting) => format!("unknown key, did you mean `{}`?", setting),
+                None => "unknown key".to_string(),
+            };
+            problems.push(ConfigProblem::new(Some(name), message));
+            continue;
+        }
+
+        // Pass 3: the type of each value, on its own
+        let single = Mapping::from_iter([(key.clone(), value.clone())]);
+        match serde_yaml::from_value::<Config>(Value::Mapping(single)) {
+            Ok(_) => {
+                known.insert(key, value);
+            }
+            Err(e) => {
+                problems.push(ConfigProblem::new(Some(name), value_error(name, &value, &e)));
+                source.get_or_insert(e);
+            }
+        }
+    }
+
+    // Every remaining value has the right type, so this can't fail
+    let has_version = known.contains_key("version");
+    let mut config: Config = serde_yaml::from_value(Value::Mapping(known)).unwrap_or_default();
+    if !has_version {
+        config.version = 1;
+    }
+
+    // Pass 4: the values themselves
+    problems.extend(check(&config));
+
+    if problems.is_empty() {
+        Ok(config)
+    } else {
+        for problem in &mut problems {
+            if let Some((line, column)) = problem.key.as_deref().and_then(|key| find_key(text, key)) {
+                problem.line = Some(line);
+                problem.column = Some(column);
+            }
+        }
+        problems.sort_by_key(|problem| problem.line);
+        Err(Rejection { problems, source })
+    }
+}
+
+// Values that have the right type but can't be used
+pub(super) fn check(config: &Config) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+    let mut problem = |key, message: &str| problems.push(ConfigProblem::new(Some(key), message));
+
+    if config.version == 0 || config.version > CONFIG_VERSION {
+        problem(
+            "version",
+            &format!("{} is not a config version this program knows, the newest is {}", config.version, CONFIG_VERSION),
+        );
+    }
+
+    if i64::from(config.http_port) < MIN_HTTP_PORT {
+        problem("httpPort", &port_range_message(i64::from(config.http_port)));
+    }
+    if config.service_name.trim().is_empty() {
+        problem("serviceName", "must not be empty");
+    } else if config.service_name.len() > MAX_LABEL {
+        // It becomes a single DNS label in the mDNS records
+        problem(
+            "serviceName",
+            &format!("is {} bytes long, at most {} fit in an mDNS name", config.service_name.len(), MAX_LABEL),
+        );
+    }
+    if config.poll_interval.is_zero() {
+        problem("pollInterval", "must be more than 0");
+    }
+    if config.initial_backoff > config.max_backoff {
+        problem("initialBackoff", "must not be more than maxBackoff");
+    }
+    if !(1.0..=MAX_BACKOFF_MULTIPLIER).contains(&config.backoff_multiplier) {
+        problem("backoffMultiplier", &format!("must be between 1.0 and {:.1}", MAX_BACKOFF_MULTIPLIER));
+    }
+    if !(0.0..=1.0).contains(&config.backoff_jitter) {
+        problem("backoffJitter", "must be between 0.0 and 1.0");
+    }
+    if config.max_attempts == Some(0) {
+        problem("maxAttempts", "must be at least 1, or null for no limit");
+    }
+
+    problems
+}
+
+// Explain why `value` doesn't fit `key`
+fn value_error(key: &str, value: &Value, error: &serde_yaml::Error) -> String {
+    match (key, value.as_i64()) {
+        ("httpPort", Some(port)) => port_range_message(port),
+        _ => error.to_string(),
+    }
+}
+
+fn port_range_message(port: i64) -> String {
+    if (0..MIN_HTTP_PORT).contains(&port) {
+        format!("{} is a privileged port, use one between {} and 65535", port, MIN_HTTP_PORT)
+    } else {
+        format!("{} is not a port number between {} and 65535", port, MIN_HTTP_PORT)
+    }
+}
+
+// The setting `key` was probably meant to be: same letters, ignoring case, `_` and `-`
+pub(super) fn suggestion(key: &str) -> Option<&'static str> {
+    let normalize = |key: &str| key.replace(['_', '-'], "").to_ascii_lowercase();
+    let key = normalize(key);
+    SETTINGS.iter().copied().find(|setting| normalize(setting) == key)
+}
+
+// 1-based line and column of a top-level `key:` in the file
+pub(super) fn find_key(text: &str, key: &str) -> Option<(usize, usize)> {
+    text.lines().enumerate().find_map(|(index, line)| {
+        let trimmed = line.trim_start();
+        let rest = trimmed
+            .strip_prefix(key)
+            .or_else(|| trimmed.strip_prefix(&format!("\"{}\"", key)))
+            .or_else(|| trimmed.strip_prefix(&format!("'{}'", key)))?;
+        rest.trim_start()
+            .starts_with(':')
+            .then(|| (index + 1, line.len() - trimmed.len() + 1))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(text: &str) -> Vec<String> {
+        parse_config(text).unwrap_err().problems.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn accepts_partial_and_empty_configs() {
+        assert_eq!(parse_config("httpPort: 7000\n").unwrap().http_port, 7000);
+        assert_eq!(parse_config("version: 2").unwrap(), Config::default());
+        assert_eq!(parse_config("# nothing set\n").unwrap().version, 1);
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_position() {
+        let rejection = parse_config("httpPort: 6969\nserviceName: [unclosed\n").unwrap_err();
+        let problems = rejection.problems;
+
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].line.is_some());
+        assert!(problems[0].column.is_some());
+        assert!(!problems[0].message.contains(&format!("at line {}", problems[0].line.unwrap())));
+        // The parser's own error is kept as the source
+        let error = rejection.source.unwrap();
+        assert_eq!(error.location().map(|location| location.line()), problems[0].line);
+    }
+
+    #[test]
+    fn suggests_the_right_spelling_of_unknown_keys() {
+        assert_eq!(
+            problems("http_port: 6969\nfoo: 1\n"),
+            vec![
+                "line 1, column 1: http_port: unknown key, did you mean `httpPort`?",
+                "line 2, column 1: foo: unknown key",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let text = "httpPort: 80\nserviceName: \"  \"\npollInterval: soon\nbackoffJitter: 2\n";
+
+        assert_eq!(
+            problems(text),
+            vec![
+                "line 1, column 1: httpPort: 80 is a privileged port, use one between 1024 and 65535",
+                "line 2, column 1: serviceName: must not be empty",
+                "line 3, column 1: pollInterval: invalid type: string \"soon\", expected f64",
+                "line 4, column 1: backoffJitter: must be between 0.0 and 1.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unusable_backoff_multipliers() {
+        for multiplier in ["0.5", "1e300", ".inf", ".nan"] {
+            assert_eq!(
+                problems(&format!("backoffMultiplier: {}", multiplier)),
+                vec!["line 1, column 1: backoffMultiplier: must be between 1.0 and 10.0"]
+            );
+        }
+        assert_eq!(parse_config("backoffMultiplier: 10").unwrap().backoff_multiplier, 10.0);
+    }
+
+    #[test]
+    fn rejects_service_names_too_long_for_mdns() {
+        // Counted in bytes, as they go on the wire: 22 characters of 3 bytes each
+        assert_eq!(
+            problems(&format!("serviceName: {}", "ギ".repeat(22))),
+            vec!["line 1, column 1: serviceName: is 66 bytes long, at most 63 fit in an mDNS name"]
+        );
+        let longest = "x".repeat(MAX_LABEL);
+        assert_eq!(parse_config(&format!("serviceName: {}", longest)).unwrap().service_name, longest);
+    }
+
+    #[test]
+    fn rejects_out_of_range_ports() {
+        assert_eq!(
+            problems("httpPort: 70000"),
+            vec!["line 1, column 1: httpPort: 70000 is not a port number between 1024 and 65535"]
+        );
+    }
+}